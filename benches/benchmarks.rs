@@ -10,23 +10,31 @@
 
 #![feature(test)]
 
+extern crate bytes;
 extern crate futures;
 #[macro_use]
 extern crate redis_async;
 extern crate test;
 extern crate tokio;
+extern crate tokio_io;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 use test::Bencher;
 
+use bytes::BytesMut;
+
 use futures::Future;
 use futures::sync::oneshot;
 
 use tokio::runtime::Runtime;
 
+use tokio_io::codec::{Decoder, Encoder};
+
 use redis_async::client;
+use redis_async::mock::MockServer;
+use redis_async::resp;
 
 fn spawn_and_wait<R, E, F>(runtime: &mut Runtime, f: F) -> Result<R, E>
 where
@@ -45,7 +53,8 @@ fn open_paired_connection(runtime: &mut Runtime, addr: &SocketAddr) -> client::P
 
 #[bench]
 fn bench_simple_getsetdel(b: &mut Bencher) {
-    let addr = "127.0.0.1:6379".parse().unwrap();
+    let server = MockServer::start().expect("Cannot start server");
+    let addr = server.addr();
 
     let mut runtime = Runtime::new().expect("Runtime");
 
@@ -64,7 +73,8 @@ fn bench_simple_getsetdel(b: &mut Bencher) {
 
 #[bench]
 fn bench_big_pipeline(b: &mut Bencher) {
-    let addr = "127.0.0.1:6379".parse().unwrap();
+    let server = MockServer::start().expect("Cannot start server");
+    let addr = server.addr();
 
     let mut runtime = Runtime::new().expect("Runtime");
 
@@ -91,7 +101,8 @@ fn bench_big_pipeline(b: &mut Bencher) {
 
 #[bench]
 fn bench_complex_pipeline(b: &mut Bencher) {
-    let addr = "127.0.0.1:6379".parse().unwrap();
+    let server = MockServer::start().expect("Cannot start server");
+    let addr = server.addr();
 
     let mut runtime = Runtime::new().expect("Runtime");
 
@@ -120,3 +131,27 @@ fn bench_complex_pipeline(b: &mut Bencher) {
 
     runtime.shutdown_now().wait().unwrap();
 }
+
+/// Decodes the result of `SMEMBERS` on a set of 1,000 members (the same data as `complex_test`), as it would
+/// arrive from a socket in many small reads.  This doesn't require a Redis server.
+#[bench]
+fn bench_decode_smembers(b: &mut Bencher) {
+    let members = (0..1000)
+        .map(|i| format!("VALUE: {}", i).into())
+        .collect();
+    let mut encoded = BytesMut::new();
+    resp::RespCodec::default()
+        .encode(resp::RespValue::Array(members), &mut encoded)
+        .expect("Cannot encode");
+
+    b.iter(|| {
+        let mut codec = resp::RespCodec::default();
+        let mut buf = BytesMut::with_capacity(encoded.len());
+        let mut result = None;
+        for chunk in encoded.chunks(64) {
+            buf.extend_from_slice(chunk);
+            result = codec.decode(&mut buf).expect("Cannot decode");
+        }
+        assert!(result.is_some());
+    });
+}
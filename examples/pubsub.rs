@@ -18,7 +18,6 @@ use futures::{Future, Stream};
 use futures::future;
 
 use redis_async::client;
-use redis_async::resp::FromResp;
 
 fn main() {
     let topic = env::args().nth(1).unwrap_or("test-topic".to_string());
@@ -30,12 +29,12 @@ fn main() {
 
     let msgs =
         client::pubsub_connect(&addr).and_then(move |connection| connection.subscribe(topic));
-    let the_loop = msgs.map_err(|_| ()).and_then(|msgs| {
+    let the_loop = msgs.and_then(|msgs| {
         msgs.for_each(|message| {
-            println!("{}", String::from_resp(message).unwrap());
+            println!("{}", message.decode::<String>().unwrap());
             future::ok(())
         })
     });
 
-    tokio::run(the_loop);
+    tokio::run(the_loop.map_err(|e| println!("ERROR: {:?}", e)));
 }
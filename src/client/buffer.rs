@@ -0,0 +1,283 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Bounded buffers between a PUBSUB connection and each of its subscribers.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use futures::{
+    task::{self, Task},
+    Async, Poll, Stream,
+};
+
+/// What happens when a subscriber doesn't keep up, and its buffer is full, see
+/// `ConnectionBuilder::pubsub_buffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Discards the oldest buffered message to make room for the new one.
+    DropOldest,
+
+    /// Discards the new message.
+    DropNewest,
+
+    /// Ends the subscriber's stream with `error::Error::Lagged`, once the messages already buffered have been
+    /// received.  The new message is discarded.
+    Disconnect,
+
+    /// Stops reading from the connection until there's room, so Redis buffers the messages instead.  This
+    /// delays every subscription on the same connection, including confirmations of new subscriptions, so a
+    /// stream that isn't being read mustn't be kept while waiting to subscribe on the same connection.
+    Backpressure,
+}
+
+/// The size of each subscriber's buffer, and what to do when it's full.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BufferConfig {
+    pub(crate) capacity: usize,
+    pub(crate) policy: SlowConsumerPolicy,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            capacity: 1024,
+            policy: SlowConsumerPolicy::DropOldest,
+        }
+    }
+}
+
+/// The number of a connection's subscribers whose buffers are full, with `Backpressure`, so the connection can
+/// tell if it should read more without checking each buffer.
+#[derive(Default)]
+pub(crate) struct Pressure {
+    full: AtomicUsize,
+
+    /// Notified when no buffers are full, if the connection is waiting for that.
+    task: Mutex<Option<Task>>,
+}
+
+impl Pressure {
+    /// Returns `NotReady` if any buffer is full, the current task is notified once none are.
+    pub(crate) fn poll_ready(&self) -> Async<()> {
+        if self.full.load(Ordering::SeqCst) == 0 {
+            return Async::Ready(());
+        }
+        *self.task.lock().expect("Poisoned pressure") = Some(task::current());
+        // The last full buffer may have been emptied before the task was stored
+        if self.full.load(Ordering::SeqCst) == 0 {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    fn filled(&self) {
+        self.full.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn emptied(&self) {
+        if self.full.fetch_sub(1, Ordering::SeqCst) == 1 {
+            if let Some(task) = self.task.lock().expect("Poisoned pressure").take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+struct Shared<T> {
+    config: BufferConfig,
+    queue: VecDeque<T>,
+    dropped: usize,
+    sender_gone: bool,
+    receiver_gone: bool,
+
+    /// Whether the buffer is full, and counted as such by `pressure`.  Only with `Backpressure`.
+    full: bool,
+    pressure: Arc<Pressure>,
+
+    /// Notified when there's something to receive.
+    receiver_task: Option<Task>,
+}
+
+impl<T> Shared<T> {
+    /// Updates whether the buffer is full, after an item is added or removed.
+    fn update_full(&mut self) {
+        let full = !self.receiver_gone
+            && self.config.policy == SlowConsumerPolicy::Backpressure
+            && self.queue.len() >= self.config.capacity;
+        self.set_full(full);
+    }
+
+    fn set_full(&mut self, full: bool) {
+        if full != self.full {
+            self.full = full;
+            if full {
+                self.pressure.filled();
+            } else {
+                self.pressure.emptied();
+            }
+        }
+    }
+}
+
+/// The result of `Sender::send`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Sent {
+    /// Either buffered, or dropped according to the policy.
+    Ok,
+
+    /// The buffer is full and the policy is to disconnect.
+    Lagged,
+
+    /// The receiver has gone.
+    Closed,
+}
+
+/// A buffer for one subscriber, `pressure` is shared by every subscriber of the same connection.
+pub(crate) fn channel<T>(
+    config: BufferConfig,
+    pressure: Arc<Pressure>,
+) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        config: config,
+        queue: VecDeque::new(),
+        dropped: 0,
+        sender_gone: false,
+        receiver_gone: false,
+        full: false,
+        pressure: pressure,
+        receiver_task: None,
+    }));
+    let sender = Sender {
+        config: config,
+        shared: shared.clone(),
+    };
+    (sender, Receiver { shared: shared })
+}
+
+pub(crate) struct Sender<T> {
+    config: BufferConfig,
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Buffers an item, applying the policy if the buffer is full.  With `Backpressure` the caller should
+    /// check `Pressure::poll_ready` first, otherwise the item is buffered regardless.
+    pub(crate) fn send(&self, item: T) -> Sent {
+        let mut shared = self.shared.lock().expect("Poisoned buffer");
+        if shared.receiver_gone {
+            return Sent::Closed;
+        }
+        if shared.queue.len() >= self.config.capacity {
+            match self.config.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    shared.queue.pop_front();
+                    shared.dropped += 1;
+                }
+                SlowConsumerPolicy::DropNewest => {
+                    shared.dropped += 1;
+                    return Sent::Ok;
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    shared.dropped += 1;
+                    return Sent::Lagged;
+                }
+                SlowConsumerPolicy::Backpressure => (),
+            }
+        }
+        push(&mut shared, item);
+        Sent::Ok
+    }
+
+    /// Buffers an item regardless of the capacity, for notices and errors that mustn't be lost.
+    pub(crate) fn force(&self, item: T) -> Sent {
+        let mut shared = self.shared.lock().expect("Poisoned buffer");
+        if shared.receiver_gone {
+            return Sent::Closed;
+        }
+        push(&mut shared, item);
+        Sent::Ok
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+fn push<T>(shared: &mut Shared<T>, item: T) {
+    shared.queue.push_back(item);
+    shared.update_full();
+    if let Some(task) = shared.receiver_task.take() {
+        task.notify();
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().expect("Poisoned buffer");
+        shared.sender_gone = true;
+        // Nothing more will be sent, so this buffer shouldn't hold up the connection
+        shared.set_full(false);
+        if let Some(task) = shared.receiver_task.take() {
+            task.notify();
+        }
+    }
+}
+
+pub(crate) struct Receiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// The number of items discarded because the buffer was full.
+    pub(crate) fn dropped(&self) -> usize {
+        self.shared.lock().expect("Poisoned buffer").dropped
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut shared = self.shared.lock().expect("Poisoned buffer");
+        match shared.queue.pop_front() {
+            Some(item) => {
+                if !shared.sender_gone {
+                    shared.update_full();
+                }
+                Ok(Async::Ready(Some(item)))
+            }
+            None if shared.sender_gone => Ok(Async::Ready(None)),
+            None => {
+                shared.receiver_task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().expect("Poisoned buffer");
+        shared.receiver_gone = true;
+        shared.queue.clear();
+        shared.set_full(false);
+    }
+}
@@ -0,0 +1,277 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Options for connecting to Redis, applied to a connection before it is used.
+
+use std::fmt;
+use std::net::SocketAddr;
+
+use futures::{future, stream, Future, Sink, Stream};
+
+use error::{self, Error};
+use resp::{self, FromResp};
+use super::buffer::{BufferConfig, SlowConsumerPolicy};
+use super::cluster::{self, ClusterConnection};
+use super::connect::{connect, RespConnection};
+use super::multiplexed::{Distribution, MultiplexedClient};
+use super::paired::{self, PairedConnection};
+use super::pubsub::{self, PubsubConnection};
+use super::reconnect::{Backoff, Reconnect};
+use super::sentinel::{self, SentinelConnection};
+
+/// Connects to Redis, authenticating, selecting a database and naming the connection as required.
+///
+/// The same builder can be used for all three types of connection, and can be kept and reused to make
+/// further connections with the same settings:
+///
+/// ```rust,no_run
+/// # extern crate redis_async;
+/// # use redis_async::client::ConnectionBuilder;
+/// # fn main() {
+/// let addr = "127.0.0.1:6379".parse().unwrap();
+/// let builder = ConnectionBuilder::new(&addr)
+///     .password("secret")
+///     .db(2)
+///     .client_name("my-service");
+/// let connection_f = builder.paired_connect();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ConnectionBuilder {
+    addr: SocketAddr,
+    username: Option<String>,
+    password: Option<String>,
+    db: Option<u32>,
+    client_name: Option<String>,
+    reconnect: Option<Backoff>,
+    pubsub_buffer: BufferConfig,
+}
+
+/// The password is left out, so that logging a builder (or a `PoolBuilder`) doesn't reveal it.
+impl fmt::Debug for ConnectionBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectionBuilder")
+            .field("addr", &self.addr)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("db", &self.db)
+            .field("client_name", &self.client_name)
+            .field("reconnect", &self.reconnect)
+            .field("pubsub_buffer", &self.pubsub_buffer)
+            .finish()
+    }
+}
+
+impl ConnectionBuilder {
+    /// Connects to the server at `addr`, with no further setup.
+    pub fn new(addr: &SocketAddr) -> Self {
+        ConnectionBuilder {
+            addr: *addr,
+            username: None,
+            password: None,
+            db: None,
+            client_name: None,
+            reconnect: None,
+            pubsub_buffer: BufferConfig::default(),
+        }
+    }
+
+    /// The address connected to.
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// The same settings, connecting to another server, e.g. another node of a cluster.
+    pub(crate) fn with_addr(&self, addr: &SocketAddr) -> Self {
+        ConnectionBuilder {
+            addr: *addr,
+            ..self.clone()
+        }
+    }
+
+    /// Sends `AUTH` with this password.
+    pub fn password<T: Into<String>>(mut self, password: T) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// The user to authenticate as, using Redis 6's ACLs.  This is only used if a `password` is also set.
+    pub fn username<T: Into<String>>(mut self, username: T) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    /// Sends `SELECT` to use this database rather than the default.
+    ///
+    /// This doesn't apply to PUBSUB connections, as channels are shared by all databases.
+    pub fn db(mut self, db: u32) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Sends `CLIENT SETNAME`, to identify the connection in `CLIENT LIST`.
+    pub fn client_name<T: Into<String>>(mut self, client_name: T) -> Self {
+        self.client_name = Some(client_name.into());
+        self
+    }
+
+    /// Reconnects, sending the same setup commands again, if the connection is lost.  By default connections
+    /// aren't re-established.  PUBSUB connections also subscribe again to each topic.
+    ///
+    /// Commands sent while reconnecting fail straight away with `error::Error::Connection`, rather than
+    /// being queued until the connection is available.
+    pub fn reconnect(mut self, backoff: Backoff) -> Self {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    /// The number of messages buffered for each PUBSUB subscriber, and what happens when a subscriber doesn't
+    /// keep up and its buffer fills.  By default 1024 messages are buffered, with
+    /// `SlowConsumerPolicy::DropOldest`.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn pubsub_buffer(mut self, capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        assert!(capacity > 0, "PUBSUB buffers must have room for at least one message");
+        self.pubsub_buffer = BufferConfig {
+            capacity: capacity,
+            policy: policy,
+        };
+        self
+    }
+
+    /// The commands to send once connected, each paired with a name to identify it in errors.
+    fn setup_commands(&self, select: bool) -> Vec<(&'static str, resp::RespValue)> {
+        let mut commands = Vec::new();
+        if let Some(ref password) = self.password {
+            let command = match self.username {
+                Some(ref username) => resp_array!["AUTH", username.as_str(), password.as_str()],
+                None => resp_array!["AUTH", password.as_str()],
+            };
+            commands.push(("AUTH", command));
+        }
+        if let (Some(db), true) = (self.db, select) {
+            commands.push(("SELECT", resp_array!["SELECT", db.to_string()]));
+        }
+        if let Some(ref client_name) = self.client_name {
+            commands.push((
+                "CLIENT SETNAME",
+                resp_array!["CLIENT", "SETNAME", client_name.as_str()],
+            ));
+        }
+        commands
+    }
+
+    fn setup_connect(
+        &self,
+        select: bool,
+    ) -> Box<Future<Item = RespConnection, Error = error::Error> + Send> {
+        let commands = self.setup_commands(select);
+        let con_f = connect(&self.addr)
+            .map_err(|e| e.into())
+            .and_then(move |connection| {
+                stream::iter_ok(commands).fold(connection, |connection, (name, command)| {
+                    connection
+                        .send(command)
+                        .map_err(|e| e.into())
+                        .and_then(|connection| connection.into_future().map_err(|(e, _)| e))
+                        .map_err(move |e| Error::Setup(name.into(), Box::new(e)))
+                        .and_then(move |(reply, connection)| match reply {
+                            Some(reply) => match resp::RespValue::from_resp(reply) {
+                                Ok(_) => Ok(connection),
+                                Err(e) => Err(Error::Setup(name.into(), Box::new(e))),
+                            },
+                            None => Err(Error::Setup(name.into(), Box::new(Error::EndOfStream))),
+                        })
+                })
+            });
+        Box::new(con_f)
+    }
+
+    /// Returns a future that resolves to a low-level `RespConnection`, see `client::connect`, once each
+    /// configured setup command has succeeded.
+    ///
+    /// If any of them fail, e.g. the password is wrong, the future fails with `error::Error::Setup`.
+    pub fn connect(&self) -> Box<Future<Item = RespConnection, Error = error::Error> + Send> {
+        self.setup_connect(true)
+    }
+
+    /// As `connect`, but resolves to a `PairedConnection`, see `client::paired_connect`.
+    pub fn paired_connect(&self) -> Box<Future<Item = PairedConnection, Error = error::Error> + Send> {
+        let reconnect = self.reconnect.clone().map(|backoff| {
+            let builder = self.clone();
+            Reconnect::new(backoff, move || builder.connect())
+        });
+        Box::new(
+            self.connect()
+                .map(move |connection| paired::spawn(connection, reconnect)),
+        )
+    }
+
+    /// Makes `connections` paired connections, resolving to a `MultiplexedClient` that spreads commands across
+    /// them according to `distribution`.
+    ///
+    /// # Panics
+    ///
+    /// If `connections` is zero.
+    pub fn multiplexed_connect(
+        &self,
+        connections: usize,
+        distribution: Distribution,
+    ) -> Box<Future<Item = MultiplexedClient, Error = error::Error> + Send> {
+        assert!(connections > 0, "At least one connection is needed");
+        let connections_f = (0..connections)
+            .map(|_| self.paired_connect())
+            .collect::<Vec<_>>();
+        Box::new(
+            future::join_all(connections_f)
+                .map(move |connections| MultiplexedClient::new(connections, distribution)),
+        )
+    }
+
+    /// Connects to a Redis Cluster through the node at this address, resolving to a `ClusterConnection` once
+    /// it's found which node serves each hash slot.  Each node is connected to, as needed, with these settings.
+    /// A cluster only has database zero, so `db` shouldn't be set.
+    pub fn cluster_connect(&self) -> Box<Future<Item = ClusterConnection, Error = error::Error> + Send> {
+        cluster::connect(self.clone())
+    }
+
+    /// Connects to the master called `master_name`, asking each of `sentinels` in turn for its address,
+    /// resolving to a `SentinelConnection` that follows the master when it fails over.  The master is connected
+    /// to with these settings, but the address given to `new` isn't used.  The sentinels are connected to with
+    /// no setup.
+    ///
+    /// # Panics
+    ///
+    /// If there are no sentinels.
+    pub fn sentinel_connect<T: Into<String>>(
+        &self,
+        sentinels: Vec<SocketAddr>,
+        master_name: T,
+    ) -> Box<Future<Item = SentinelConnection, Error = error::Error> + Send> {
+        sentinel::connect(self.clone(), sentinels, master_name.into())
+    }
+
+    /// As `connect`, but resolves to a `PubsubConnection`, see `client::pubsub_connect`.
+    ///
+    /// If set to `reconnect`, every topic is subscribed to again once reconnected.
+    pub fn pubsub_connect(&self) -> Box<Future<Item = PubsubConnection, Error = error::Error> + Send> {
+        let reconnect = self.reconnect.clone().map(|backoff| {
+            let builder = self.clone();
+            Reconnect::new(backoff, move || builder.setup_connect(false))
+        });
+        let buffer = self.pubsub_buffer;
+        Box::new(
+            self.setup_connect(false)
+                .map(move |connection| pubsub::spawn(connection, reconnect, buffer)),
+        )
+    }
+}
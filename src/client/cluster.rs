@@ -0,0 +1,702 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Redis Cluster, sending each command to the node that serves the hash slot of its keys.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::mem;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::{future::{self, Loop}, Future};
+
+use error;
+use resp::{self, FromResp};
+use super::builder::ConnectionBuilder;
+use super::paired::{PairedConnection, SendBox};
+
+/// The number of hash slots that keys are divided between.
+pub const SLOTS: u16 = 16384;
+
+/// The most redirects followed for one command, the last is returned as an error if there are more.
+const MAX_REDIRECTS: usize = 5;
+
+/// A slot that no node serves.
+const NO_NODE: u16 = u16::MAX;
+
+/// The CRC16 (XMODEM) checksum that Redis Cluster hashes keys with.
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+            if crc & 0x8000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x1021
+            }
+        })
+    })
+}
+
+/// The hash slot of a key.
+///
+/// If the key contains a `{`, followed by a `}` with at least one character between them, only the characters
+/// between the first such pair are hashed.  So `{user1000}.following` and `{user1000}.followers` share a
+/// slot, and can be used together in a multi-key command.
+pub fn slot(key: &[u8]) -> u16 {
+    let hashed = match key.iter().position(|b| *b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|b| *b == b'}') {
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(hashed) % SLOTS
+}
+
+/// An argument of a command as bytes, if it can be a key.
+fn arg_bytes(arg: &resp::RespValue) -> Option<Cow<'_, [u8]>> {
+    match *arg {
+        resp::RespValue::BulkString(ref bytes) => Some(Cow::Borrowed(bytes)),
+        resp::RespValue::SimpleString(ref string) => Some(Cow::Borrowed(string.as_bytes())),
+        resp::RespValue::Integer(i) => Some(Cow::Owned(i.to_string().into_bytes())),
+        _ => None,
+    }
+}
+
+/// The keys of a command, `args` being its name and arguments.  Most commands have one key, their first
+/// argument, those known to have more or none are listed.
+fn command_keys(args: &[resp::RespValue]) -> Vec<&resp::RespValue> {
+    let name = match args.first().and_then(arg_bytes) {
+        Some(name) => name.to_ascii_uppercase(),
+        None => return Vec::new(),
+    };
+    let args = &args[1..];
+    match &name[..] {
+        b"ASKING" | b"AUTH" | b"CLIENT" | b"CLUSTER" | b"COMMAND" | b"CONFIG" | b"DBSIZE" | b"DISCARD"
+        | b"ECHO" | b"EXEC" | b"FLUSHALL" | b"FLUSHDB" | b"INFO" | b"KEYS" | b"MULTI" | b"PING"
+        | b"PUBLISH" | b"RANDOMKEY" | b"READONLY" | b"READWRITE" | b"SCAN" | b"SCRIPT" | b"SELECT"
+        | b"TIME" | b"UNWATCH" | b"WAIT" => Vec::new(),
+        b"DEL" | b"EXISTS" | b"MGET" | b"PFCOUNT" | b"PFMERGE" | b"SDIFF" | b"SDIFFSTORE" | b"SINTER"
+        | b"SINTERSTORE" | b"SUNION" | b"SUNIONSTORE" | b"TOUCH" | b"UNLINK" | b"WATCH" => {
+            args.iter().collect()
+        }
+        b"BLMOVE" | b"BRPOPLPUSH" | b"COPY" | b"LMOVE" | b"RENAME" | b"RENAMENX" | b"RPOPLPUSH" | b"SMOVE" => {
+            args.iter().take(2).collect()
+        }
+        // The last argument is the timeout
+        b"BLPOP" | b"BRPOP" | b"BZPOPMAX" | b"BZPOPMIN" => {
+            args[..args.len().saturating_sub(1)].iter().collect()
+        }
+        b"MSET" | b"MSETNX" => args.iter().step_by(2).collect(),
+        // The key follows a subcommand, e.g. `OBJECT ENCODING key`
+        b"MEMORY" | b"OBJECT" | b"XGROUP" | b"XINFO" => args.iter().skip(1).take(1).collect(),
+        // The number of keys comes before them
+        b"LMPOP" | b"SINTERCARD" | b"ZDIFF" | b"ZINTER" | b"ZINTERCARD" | b"ZMPOP" | b"ZUNION" => {
+            counted_keys(args, 0)
+        }
+        b"BLMPOP" | b"BZMPOP" | b"EVAL" | b"EVALSHA" | b"EVAL_RO" | b"EVALSHA_RO" | b"FCALL" | b"FCALL_RO" => {
+            counted_keys(args, 1)
+        }
+        b"ZDIFFSTORE" | b"ZINTERSTORE" | b"ZUNIONSTORE" => {
+            let mut keys = args.iter().take(1).collect::<Vec<_>>();
+            keys.extend(counted_keys(args, 1));
+            keys
+        }
+        b"XREAD" | b"XREADGROUP" => {
+            let streams = args.iter().position(|arg| match arg_bytes(arg) {
+                Some(arg) => arg.eq_ignore_ascii_case(b"STREAMS"),
+                None => false,
+            });
+            match streams {
+                // The keys are followed by as many IDs
+                Some(idx) => {
+                    let rest = &args[idx + 1..];
+                    rest[..rest.len() / 2].iter().collect()
+                }
+                None => Vec::new(),
+            }
+        }
+        _ => args.iter().take(1).collect(),
+    }
+}
+
+/// The keys following the number of keys, which is the argument at `idx`.
+fn counted_keys(args: &[resp::RespValue], idx: usize) -> Vec<&resp::RespValue> {
+    let numkeys = args.get(idx)
+        .and_then(arg_bytes)
+        .and_then(|numkeys| String::from_utf8_lossy(&numkeys).parse().ok())
+        .unwrap_or(0);
+    args.iter().skip(idx + 1).take(numkeys).collect()
+}
+
+/// The hash slot of a command's keys, `None` if it has none.  Fails with `error::Error::CrossSlot` if they
+/// are in different slots.
+fn command_slot(msg: &resp::RespValue) -> Result<Option<u16>, error::Error> {
+    let args = match *msg {
+        resp::RespValue::Array(ref args) => args,
+        _ => return Err(error::internal("Command must be a RespValue::Array")),
+    };
+    let mut slots = command_keys(args)
+        .into_iter()
+        .filter_map(arg_bytes)
+        .map(|key| slot(&key));
+    let first = match slots.next() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+    if slots.all(|slot| slot == first) {
+        Ok(Some(first))
+    } else {
+        let name = args.first()
+            .and_then(arg_bytes)
+            .map(|name| String::from_utf8_lossy(&name).into_owned())
+            .unwrap_or_default();
+        Err(error::Error::CrossSlot(format!(
+            "The keys of {} are in different hash slots, a {{hashtag}} can keep them in the same one",
+            name
+        )))
+    }
+}
+
+/// The address of a node, as given in `CLUSTER SLOTS`, `CLUSTER SHARDS` or a redirect.  An unknown host, empty
+/// or `?`, is the same as that of the node the address came from.
+///
+/// Only IP addresses are accepted, as resolving a hostname would block the thread running the connection, so
+/// nodes must not be set to announce hostnames with `cluster-preferred-endpoint-type`.
+fn node_addr(host: &str, port: u16, from: &SocketAddr) -> Result<SocketAddr, error::Error> {
+    let host = host.trim_matches(|c| c == '[' || c == ']');
+    if host.is_empty() || host == "?" {
+        return Ok(SocketAddr::new(from.ip(), port));
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, port)),
+        Err(_) => Err(error::Error::Connection(format!(
+            "Cluster node has a hostname, not an IP address: {}",
+            host
+        ))),
+    }
+}
+
+/// A port or hash slot from a reply, which must fit in a `u16`.
+fn to_u16(value: usize, name: &str) -> Result<u16, error::Error> {
+    u16::try_from(value).map_err(|_| {
+        error::resp(
+            format!("Invalid {} in cluster reply", name),
+            resp::RespValue::Integer(value as i64),
+        )
+    })
+}
+
+/// A redirect, replied instead of running a command by a node that doesn't serve its hash slot.
+#[derive(Debug, PartialEq)]
+enum Redirect {
+    /// `MOVED`, the slot is served by another node.
+    Moved(u16, SocketAddr),
+
+    /// `ASK`, the slot is being migrated to another node, which should be sent this command only, preceded by
+    /// `ASKING`.
+    Ask(SocketAddr),
+}
+
+impl Redirect {
+    /// Parses an error reply, e.g. `MOVED 3999 127.0.0.1:6381`, from the node at `from`.
+    fn parse(e: &str, from: &SocketAddr) -> Option<Redirect> {
+        let mut parts = e.split(' ');
+        let moved = match parts.next()? {
+            "MOVED" => true,
+            "ASK" => false,
+            _ => return None,
+        };
+        let slot = parts.next()?.parse().ok()?;
+        let endpoint = parts.next()?;
+        let separator = endpoint.rfind(':')?;
+        let port = endpoint[separator + 1..].parse().ok()?;
+        let addr = node_addr(&endpoint[..separator], port, from).ok()?;
+        if moved {
+            Some(Redirect::Moved(slot, addr))
+        } else {
+            Some(Redirect::Ask(addr))
+        }
+    }
+}
+
+/// Which node serves each hash slot.
+struct SlotMap {
+    nodes: Vec<SocketAddr>,
+
+    /// The index in `nodes` of the node that serves each slot, or `NO_NODE`.
+    slots: Vec<u16>,
+}
+
+impl SlotMap {
+    fn new() -> Self {
+        SlotMap {
+            nodes: Vec::new(),
+            slots: vec![NO_NODE; SLOTS as usize],
+        }
+    }
+
+    fn node(&self, slot: u16) -> Option<SocketAddr> {
+        match self.slots[slot as usize] {
+            NO_NODE => None,
+            idx => Some(self.nodes[idx as usize]),
+        }
+    }
+
+    /// Records that `addr` serves the slots from `start` to `end` inclusive.
+    fn assign(&mut self, start: u16, end: u16, addr: SocketAddr) -> Result<(), error::Error> {
+        if start > end || end >= SLOTS {
+            return Err(error::internal(format!("Invalid hash slots: {}-{}", start, end)));
+        }
+        let idx = match self.nodes.iter().position(|node| *node == addr) {
+            Some(idx) => idx,
+            None => {
+                self.nodes.push(addr);
+                self.nodes.len() - 1
+            }
+        };
+        for slot in &mut self.slots[start as usize..=end as usize] {
+            *slot = idx as u16;
+        }
+        Ok(())
+    }
+
+    /// Parses the reply to `CLUSTER SLOTS` sent to the node at `from`.  Each entry is the first and last slot
+    /// of a range, the master that serves it, then any replicas.
+    fn from_slots(reply: resp::RespValue, from: &SocketAddr) -> Result<SlotMap, error::Error> {
+        let mut map = SlotMap::new();
+        for range in Vec::<Vec<resp::RespValue>>::from_resp(reply)? {
+            let mut range = range.into_iter();
+            let (start, end, master) = match (range.next(), range.next(), range.next()) {
+                (Some(start), Some(end), Some(master)) => (start, end, master),
+                _ => return Err(error::internal("Too few elements in CLUSTER SLOTS range")),
+            };
+            let mut master = Vec::<resp::RespValue>::from_resp(master)?.into_iter();
+            let (host, port) = match (master.next(), master.next()) {
+                (Some(host), Some(port)) => (String::from_resp(host)?, usize::from_resp(port)?),
+                _ => return Err(error::internal("Too few elements in CLUSTER SLOTS node")),
+            };
+            let addr = node_addr(&host, to_u16(port, "port")?, from)?;
+            let (start, end) = (usize::from_resp(start)?, usize::from_resp(end)?);
+            map.assign(to_u16(start, "hash slot")?, to_u16(end, "hash slot")?, addr)?;
+        }
+        Ok(map)
+    }
+
+    /// Parses the reply to `CLUSTER SHARDS` sent to the node at `from`, for servers without `CLUSTER SLOTS`.
+    /// Each shard has its ranges of slots, as pairs of first and last slot, and its nodes, one being the
+    /// master.
+    fn from_shards(reply: resp::RespValue, from: &SocketAddr) -> Result<SlotMap, error::Error> {
+        type Fields = HashMap<String, resp::RespValue>;
+
+        fn field<T: FromResp>(fields: &mut Fields, name: &str) -> Result<T, error::Error> {
+            match fields.remove(name) {
+                Some(value) => T::from_resp(value),
+                None => Err(error::internal(format!("No {} in CLUSTER SHARDS reply", name))),
+            }
+        }
+
+        let mut map = SlotMap::new();
+        for mut shard in Vec::<Fields>::from_resp(reply)? {
+            let slots: Vec<usize> = field(&mut shard, "slots")?;
+            let nodes: Vec<Fields> = field(&mut shard, "nodes")?;
+            let mut master = None;
+            for mut node in nodes {
+                if field::<String>(&mut node, "role")? == "master" {
+                    master = Some(node);
+                    break;
+                }
+            }
+            let mut master = match master {
+                Some(master) => master,
+                None => continue,
+            };
+            let host: String = field(&mut master, "ip")?;
+            let port: usize = field(&mut master, "port").or_else(|_| field(&mut master, "tls-port"))?;
+            let addr = node_addr(&host, to_u16(port, "port")?, from)?;
+            for range in slots.chunks(2) {
+                if range.len() == 2 {
+                    map.assign(to_u16(range[0], "hash slot")?, to_u16(range[1], "hash slot")?, addr)?;
+                }
+            }
+        }
+        Ok(map)
+    }
+}
+
+struct State {
+    slots: SlotMap,
+
+    /// A connection to each node that's been sent a command.
+    connections: HashMap<SocketAddr, PairedConnection>,
+
+    /// Whether the slot map is being fetched because of a `MOVED` redirect, so others don't fetch it too.
+    refreshing: bool,
+}
+
+struct Shared {
+    builder: ConnectionBuilder,
+    state: Mutex<State>,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Poisoned cluster state")
+    }
+}
+
+/// Allows the slot map to be fetched again after a redirect, when dropped.
+struct Refreshing(Arc<Shared>);
+
+impl Drop for Refreshing {
+    fn drop(&mut self) {
+        self.0.state().refreshing = false;
+    }
+}
+
+/// The state of sending a command: the node a redirect said to send it to, and whether to send `ASKING` first,
+/// if there's been one; and the number of redirects so far.
+type SendLoop = (ClusterConnection, resp::RespValue, Option<(SocketAddr, bool)>, usize);
+
+/// A connection to a Redis Cluster, made with `ConnectionBuilder::cluster_connect` or `cluster_connect`.
+///
+/// Each command is sent to the master node that serves the hash slot of its keys, on a `PairedConnection`
+/// made the first time that node is needed.  Commands without keys, e.g. `PING`, are sent to any one node.
+/// Redirects are followed, so commands can still be sent while slots are migrated between nodes, or after a
+/// failover.  Clones share the same connections.
+///
+/// Only commands whose keys are known can be sent to the right node, see `send`.  Transactions, `WATCH` and
+/// PUBSUB need a connection to a single node, which can be made with `ConnectionBuilder::new` and the
+/// address of the node returned by `node`.
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate redis_async;
+/// # extern crate futures;
+/// # use futures::Future;
+/// # use redis_async::client;
+/// # fn main() {
+/// let addr = "127.0.0.1:7000".parse().unwrap();
+/// let value_f = client::cluster_connect(&addr).and_then(|cluster| {
+///     cluster
+///         .send::<()>(resp_array!["SET", "{user1000}.name", "Ben"])
+///         .and_then(move |()| cluster.send::<Vec<String>>(resp_array!["MGET", "{user1000}.name", "other"]))
+/// });
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ClusterConnection {
+    shared: Arc<Shared>,
+}
+
+pub(crate) fn connect(
+    builder: ConnectionBuilder,
+) -> Box<Future<Item = ClusterConnection, Error = error::Error> + Send> {
+    let seed = *builder.addr();
+    let cluster = ClusterConnection {
+        shared: Arc::new(Shared {
+            builder: builder,
+            state: Mutex::new(State {
+                slots: SlotMap::new(),
+                connections: HashMap::new(),
+                refreshing: false,
+            }),
+        }),
+    };
+    Box::new(cluster.refresh_from(seed).map(move |()| cluster))
+}
+
+/// Connects to a Redis Cluster, through the node at `addr`, with no further setup, see
+/// `ConnectionBuilder::cluster_connect`.
+pub fn cluster_connect(
+    addr: &SocketAddr,
+) -> Box<Future<Item = ClusterConnection, Error = error::Error> + Send> {
+    ConnectionBuilder::new(addr).cluster_connect()
+}
+
+impl ClusterConnection {
+    /// Sends a command to the node that serves the hash slot of its keys, following any `MOVED` or `ASK`
+    /// redirects.  Otherwise the same as `PairedConnection::send`.
+    ///
+    /// The keys of most commands are their first argument, and of those commands with more or no keys, the
+    /// common ones are known.  If a command's keys are in different slots the future fails with
+    /// `error::Error::CrossSlot`, without anything being sent.  If a slot isn't served by any node, or a
+    /// node can't be connected to, it fails with `error::Error::Connection`.
+    pub fn send<T: FromResp + Send + 'static>(&self, msg: resp::RespValue) -> SendBox<T> {
+        let slot = match command_slot(&msg) {
+            Ok(slot) => slot,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let send_f = future::loop_fn(
+            (self.clone(), msg, None, 0),
+            move |(cluster, msg, redirected, redirects): SendLoop| -> SendBox<Loop<resp::RespValue, SendLoop>> {
+                // A redirect is followed even if it's for another slot, in case the keys aren't those expected
+                let (addr, asking) = match redirected {
+                    Some(redirected) => redirected,
+                    None => match cluster.node_for(slot) {
+                        Ok(addr) => (addr, false),
+                        Err(e) => return Box::new(future::err(e)),
+                    },
+                };
+                let reply_f = cluster.send_to(addr, msg.clone(), asking);
+                Box::new(reply_f.then(move |result| -> SendBox<Loop<resp::RespValue, SendLoop>> {
+                    match result {
+                        Ok(reply) => Box::new(future::ok(Loop::Break(reply))),
+                        Err(error::Error::Remote(e)) => match Redirect::parse(&e, &addr) {
+                            Some(Redirect::Moved(slot, to)) if redirects < MAX_REDIRECTS => {
+                                let moved_f = cluster.moved(slot, to);
+                                Box::new(moved_f.map(move |()| {
+                                    Loop::Continue((cluster, msg, Some((to, false)), redirects + 1))
+                                }))
+                            }
+                            Some(Redirect::Ask(to)) if redirects < MAX_REDIRECTS => {
+                                let redirected = Some((to, true));
+                                Box::new(future::ok(Loop::Continue((cluster, msg, redirected, redirects + 1))))
+                            }
+                            _ => Box::new(future::err(error::Error::Remote(e))),
+                        },
+                        Err(e) => {
+                            if let error::Error::Connection(_) = e {
+                                // Connect again next time, the node may be back, or replaced after a failover
+                                cluster.shared.state().connections.remove(&addr);
+                            }
+                            Box::new(future::err(e))
+                        }
+                    }
+                }))
+            },
+        );
+        Box::new(send_f.and_then(T::from_resp))
+    }
+
+    /// The address of the node that serves a key's hash slot, if any.
+    pub fn node(&self, key: &[u8]) -> Option<SocketAddr> {
+        self.shared.state().slots.node(slot(key))
+    }
+
+    /// Fetches which node serves each hash slot again, from any node.  This is done automatically when a
+    /// `MOVED` redirect is received, but not when a node can't be reached, as a failover takes some time.
+    pub fn refresh(&self) -> SendBox<()> {
+        let addr = match self.node_for(None) {
+            Ok(addr) => addr,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        self.refresh_from(addr)
+    }
+
+    /// The node to send a command for `slot` to, any node if `None`.
+    fn node_for(&self, slot: Option<u16>) -> Result<SocketAddr, error::Error> {
+        let state = self.shared.state();
+        match slot {
+            Some(slot) => state
+                .slots
+                .node(slot)
+                .ok_or_else(|| error::Error::Connection(format!("No node serves hash slot {}", slot))),
+            None => Ok(state
+                .slots
+                .nodes
+                .first()
+                .cloned()
+                .unwrap_or_else(|| *self.shared.builder.addr())),
+        }
+    }
+
+    /// The connection to the node at `addr`, connecting if there isn't one.
+    fn connection(&self, addr: SocketAddr) -> SendBox<PairedConnection> {
+        if let Some(connection) = self.shared.state().connections.get(&addr) {
+            return Box::new(future::ok(connection.clone()));
+        }
+        let shared = self.shared.clone();
+        Box::new(
+            self.shared
+                .builder
+                .with_addr(&addr)
+                .paired_connect()
+                .map(move |connection| {
+                    // Another command may have connected to the same node in the meantime
+                    shared
+                        .state()
+                        .connections
+                        .entry(addr)
+                        .or_insert(connection)
+                        .clone()
+                }),
+        )
+    }
+
+    /// Sends a command to the node at `addr`, preceded by `ASKING` if `asking`, resolving to the reply.  Error
+    /// replies fail with `error::Error::Remote`.
+    fn send_to(&self, addr: SocketAddr, msg: resp::RespValue, asking: bool) -> SendBox<resp::RespValue> {
+        Box::new(self.connection(addr).and_then(move |connection| -> SendBox<resp::RespValue> {
+            if !asking {
+                return connection.send(msg);
+            }
+            Box::new(
+                connection
+                    .send_all(vec![resp_array!["ASKING"], msg])
+                    .and_then(|mut replies| match replies.pop() {
+                        Some(reply) => reply.and_then(resp::RespValue::from_resp),
+                        None => Err(error::internal("No reply after ASKING")),
+                    }),
+            )
+        }))
+    }
+
+    /// Fetches the slot map from the node at `addr`, with `CLUSTER SLOTS`, or `CLUSTER SHARDS` if that isn't
+    /// supported.  Connections to nodes that no longer serve any slots are closed.
+    fn refresh_from(&self, addr: SocketAddr) -> SendBox<()> {
+        let shared = self.shared.clone();
+        let slots_f = self.connection(addr).and_then(move |connection| {
+            connection
+                .send(resp_array!["CLUSTER", "SLOTS"])
+                .then(move |result| -> SendBox<SlotMap> {
+                    match result {
+                        Ok(reply) => Box::new(future::result(SlotMap::from_slots(reply, &addr))),
+                        Err(error::Error::Remote(_)) => Box::new(
+                            connection
+                                .send(resp_array!["CLUSTER", "SHARDS"])
+                                .and_then(move |reply| SlotMap::from_shards(reply, &addr)),
+                        ),
+                        Err(e) => Box::new(future::err(e)),
+                    }
+                })
+        });
+        Box::new(slots_f.map(move |slots| {
+            let mut state = shared.state();
+            state
+                .connections
+                .retain(|addr, _| slots.nodes.contains(addr));
+            state.slots = slots;
+        }))
+    }
+
+    /// Records that `slot` is served by the node at `addr`, as a `MOVED` redirect said, then fetches the whole
+    /// slot map from that node, as others have probably moved too.  If the slot map is already being fetched,
+    /// or can't be, the command is sent again without waiting.
+    fn moved(&self, slot: u16, addr: SocketAddr) -> SendBox<()> {
+        let refreshing = {
+            let mut state = self.shared.state();
+            if let Err(e) = state.slots.assign(slot, slot, addr) {
+                return Box::new(future::err(e));
+            }
+            mem::replace(&mut state.refreshing, true)
+        };
+        if refreshing {
+            return Box::new(future::ok(()));
+        }
+        let refreshing = Refreshing(self.shared.clone());
+        Box::new(self.refresh_from(addr).then(move |result| {
+            drop(refreshing);
+            if let Err(e) = result {
+                warn!("Cannot fetch cluster slots after a redirect: {}", e);
+            }
+            Ok(())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use error;
+    use resp;
+
+    use super::{command_slot, crc16, slot, Redirect, SlotMap};
+
+    #[test]
+    fn test_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(slot(b"foo"), 12182);
+        assert_eq!(slot(b"bar"), 5061);
+        assert_eq!(slot(b"{user1000}.following"), slot(b"{user1000}.followers"));
+        assert_eq!(slot(b"{user1000}.following"), slot(b"user1000"));
+        assert_eq!(slot(b"foo{bar}{zap}"), slot(b"bar"));
+        assert_eq!(slot(b"foo{{bar}}zap"), slot(b"{bar"));
+        assert_eq!(slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % super::SLOTS);
+    }
+
+    #[test]
+    fn test_command_slot() {
+        assert_eq!(command_slot(&resp_array!["PING"]).unwrap(), None);
+        assert_eq!(command_slot(&resp_array!["GET", "foo"]).unwrap(), Some(12182));
+        assert_eq!(
+            command_slot(&resp_array!["MSET", "{a}1", "x", "{a}2", "y"]).unwrap(),
+            Some(slot(b"a"))
+        );
+        assert_eq!(
+            command_slot(&resp_array!["EVAL", "return 1", "1", "foo", "bar"]).unwrap(),
+            Some(12182)
+        );
+        assert_eq!(
+            command_slot(&resp_array!["XREAD", "COUNT", "1", "STREAMS", "foo", "0-0"]).unwrap(),
+            Some(12182)
+        );
+        assert_eq!(
+            command_slot(&resp_array!["OBJECT", "ENCODING", "foo"]).unwrap(),
+            Some(12182)
+        );
+        assert_eq!(
+            command_slot(&resp_array!["ZUNION", "2", "{foo}1", "{foo}2", "WITHSCORES"]).unwrap(),
+            Some(12182)
+        );
+        assert_eq!(
+            command_slot(&resp_array!["ZUNIONSTORE", "{foo}", "2", "{foo}1", "{foo}2"]).unwrap(),
+            Some(12182)
+        );
+        assert_eq!(
+            command_slot(&resp_array!["BLMPOP", "0", "1", "foo", "LEFT"]).unwrap(),
+            Some(12182)
+        );
+        assert!(command_slot(&resp_array!["MGET", "foo", "bar"]).is_err());
+        assert!(command_slot(&resp_array!["SINTERCARD", "2", "foo", "bar"]).is_err());
+        assert!(command_slot(&resp_array!["RENAME", "foo", "bar"]).is_err());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let from: SocketAddr = "10.0.0.1:7000".parse().unwrap();
+        assert_eq!(
+            Redirect::parse("MOVED 3999 127.0.0.1:6381", &from),
+            Some(Redirect::Moved(3999, "127.0.0.1:6381".parse().unwrap()))
+        );
+        assert_eq!(
+            Redirect::parse("ASK 3999 :6381", &from),
+            Some(Redirect::Ask("10.0.0.1:6381".parse().unwrap()))
+        );
+        assert_eq!(Redirect::parse("ERR unknown command", &from), None);
+        assert_eq!(Redirect::parse("MOVED 3999 redis-2.example:6381", &from), None);
+    }
+
+    #[test]
+    fn test_slots_out_of_range() {
+        let from: SocketAddr = "10.0.0.1:7000".parse().unwrap();
+        let node = |port: i64| resp_array!["127.0.0.1", resp::RespValue::Integer(port)];
+        let slots = |start: i64, end: i64, port: i64| {
+            resp::RespValue::Array(vec![resp_array![
+                resp::RespValue::Integer(start),
+                resp::RespValue::Integer(end),
+                node(port)
+            ]])
+        };
+        assert!(SlotMap::from_slots(slots(0, 16383, 7001), &from).is_ok());
+        match SlotMap::from_slots(slots(0, 16383, 70001), &from) {
+            Err(error::Error::RESP(_, _)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("A port that doesn't fit in a u16 should be rejected"),
+        }
+        match SlotMap::from_slots(slots(0, 65536, 7001), &from) {
+            Err(error::Error::RESP(_, _)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("A slot that doesn't fit in a u16 should be rejected"),
+        }
+    }
+}
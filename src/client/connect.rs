@@ -37,5 +37,5 @@ pub type RespConnection = Framed<TcpStream, resp::RespCodec>;
 /// But since most Redis usages involve issue commands that result in one
 /// single result, this library also implements `paired_connect`.
 pub fn connect(addr: &SocketAddr) -> Box<Future<Item = RespConnection, Error = io::Error> + Send> {
-    Box::new(TcpStream::connect(addr).map(move |socket| socket.framed(resp::RespCodec)))
+    Box::new(TcpStream::connect(addr).map(move |socket| socket.framed(resp::RespCodec::default())))
 }
@@ -19,25 +19,46 @@
 //! * `paired_connect` is used for most of the standard Redis commands, where one request results
 //! in one response.
 //! * `pubsub_connect` is used for Redis's PUBSUB functionality.
+//!
+//! Each can also be made with a `ConnectionBuilder`, to authenticate, select a database or name the connection
+//! before it is used.
 
+pub mod buffer;
+pub mod builder;
+pub mod cluster;
 pub mod connect;
+pub mod notifications;
+pub mod multiplexed;
 #[macro_use]
 pub mod paired;
+pub mod pipeline;
+pub mod pool;
 pub mod pubsub;
+pub mod reconnect;
+pub mod sentinel;
+pub mod transaction;
 
-pub use self::{connect::connect, paired::{paired_connect, PairedConnection},
-               pubsub::{pubsub_connect, PubsubConnection}};
+pub use self::{buffer::SlowConsumerPolicy, builder::ConnectionBuilder,
+               cluster::{cluster_connect, ClusterConnection}, connect::connect, multiplexed::MultiplexedClient,
+               paired::{paired_connect, PairedConnection}, pipeline::Pipeline,
+               pubsub::{pubsub_connect, PubsubConnection}, reconnect::Backoff,
+               sentinel::{sentinel_connect, SentinelConnection}, transaction::Transaction};
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
     use std::io;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
 
     use futures::sync::oneshot;
-    use futures::{stream, Future, Sink, Stream};
+    use futures::{future, stream, Future, Sink, Stream};
 
     use tokio;
+    use tokio::timer::Delay;
 
     use error;
+    use mock::{Action, FakeServer, MockServer};
     use resp;
 
     fn run_and_wait<R, E, F>(f: F) -> Result<R, E>
@@ -51,9 +72,26 @@ mod test {
         rx.wait().expect("Cannot wait for a result")
     }
 
+    /// Sends each command, in order, to a `FakeServer` following `script`; and returns the results.
+    fn send_with_script(
+        script: Vec<Action>,
+        commands: Vec<resp::RespValue>,
+    ) -> Vec<Result<resp::RespValue, error::Error>> {
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+        let test_f = super::paired_connect(&server.addr()).and_then(|connection| {
+            let results = commands
+                .into_iter()
+                .map(|command| connection.send(command).then(Ok::<_, error::Error>))
+                .collect::<Vec<_>>();
+            future::join_all(results)
+        });
+        run_and_wait(test_f).unwrap()
+    }
+
     #[test]
     fn can_connect() {
-        let addr = "127.0.0.1:6379".parse().unwrap();
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
 
         let connection = super::connect(&addr)
             .map_err(|e| e.into())
@@ -72,7 +110,8 @@ mod test {
 
     #[test]
     fn complex_test() {
-        let addr = "127.0.0.1:6379".parse().unwrap();
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
         let connection = super::connect(&addr)
             .map_err(|e| e.into())
             .and_then(|connection| {
@@ -99,7 +138,8 @@ mod test {
 
     #[test]
     fn can_paired_connect() {
-        let addr = "127.0.0.1:6379".parse().unwrap();
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
 
         let connect_f = super::paired_connect(&addr).and_then(|connection| {
             let res_f = connection.send(resp_array!["PING", "TEST"]);
@@ -114,7 +154,8 @@ mod test {
 
     #[test]
     fn complex_paired_connect() {
-        let addr = "127.0.0.1:6379".parse().unwrap();
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
 
         let connect_f = super::paired_connect(&addr).and_then(|connection| {
             connection
@@ -129,7 +170,8 @@ mod test {
 
     #[test]
     fn sending_a_lot_of_data_test() {
-        let addr = "127.0.0.1:6379".parse().unwrap();
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
 
         let test_f = super::paired_connect(&addr);
         let send_data = test_f.and_then(|connection| {
@@ -147,7 +189,8 @@ mod test {
 
     #[test]
     fn pubsub_test() {
-        let addr = "127.0.0.1:6379".parse().unwrap();
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
         let paired_c = super::paired_connect(&addr);
         let pubsub_c = super::pubsub_connect(&addr);
         let msgs = paired_c.join(pubsub_c).and_then(|(paired, pubsub)| {
@@ -160,14 +203,1651 @@ mod test {
                     .map(|_: resp::RespValue| msgs)
             })
         });
-        let tst = msgs.and_then(|msgs| {
-            msgs.take(2)
-                .collect()
-                .map_err(|_| error::internal("unreachable"))
+        let tst = msgs.and_then(|msgs| msgs.take(2).collect());
+        let result = run_and_wait(tst).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].channel, "test-topic");
+        assert_eq!(result[0].pattern, None);
+        assert_eq!(result[0].decode::<String>().unwrap(), "test-message");
+        assert_eq!(result[1].payload, "test-message2".into());
+    }
+
+    #[test]
+    fn pubsub_unsubscribe() {
+        let server = MockServer::start().expect("Cannot start server");
+        let test_f = super::pubsub_connect(&server.addr()).and_then(|pubsub| {
+            pubsub
+                .subscribe("test-topic")
+                .and_then(move |msgs| pubsub.unsubscribe("test-topic").join(msgs.collect()))
+        });
+        let (_, result) = run_and_wait(test_f).unwrap();
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn pubsub_unsubscribe_in_flight() {
+        let script = vec![
+            Action::Read(1),
+            Action::Send(resp_array!["subscribe", "test-topic", resp::RespValue::Integer(1)]),
+            Action::Read(1),
+            // Published before Redis received the UNSUBSCRIBE
+            Action::Send(resp_array!["message", "test-topic", "in-flight"]),
+            Action::Delay(Duration::from_millis(100)),
+            Action::Send(resp_array!["unsubscribe", "test-topic", resp::RespValue::Integer(0)]),
+            Action::Read(1),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+        let test_f = super::pubsub_connect(&server.addr()).and_then(|pubsub| {
+            pubsub.subscribe("test-topic").and_then(move |msgs| {
+                let unsubscribed = pubsub.unsubscribe("test-topic");
+                let msgs = msgs.map(|message| message.decode::<String>().unwrap()).collect();
+                unsubscribed.join(msgs)
+            })
+        });
+        let (_, result) = run_and_wait(test_f).unwrap();
+        assert_eq!(result, vec!["in-flight".to_string()]);
+    }
+
+    #[test]
+    fn pubsub_subscribe_closed() {
+        let server = FakeServer::start(vec![vec![Action::Close]]).expect("Cannot start server");
+        let test_f = super::pubsub_connect(&server.addr()).and_then(|pubsub| {
+            delay(100).and_then(move |()| pubsub.subscribe("test-topic").then(|result| Ok(result.err())))
         });
+        match run_and_wait(test_f).unwrap() {
+            Some(error::Error::Connection(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn psubscribe_test() {
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
+        let paired_c = super::paired_connect(&addr);
+        let pubsub_c = super::pubsub_connect(&addr);
+        let msgs = paired_c.join(pubsub_c).and_then(|(paired, pubsub)| {
+            pubsub.psubscribe("test-[ab]*").and_then(move |msgs| {
+                faf!(paired.send(resp_array!["PUBLISH", "test-a1", "first"]));
+                faf!(paired.send(resp_array!["PUBLISH", "test-c", "not matched"]));
+                paired
+                    .send(resp_array!["PUBLISH", "test-b", "second"])
+                    .map(|_: resp::RespValue| msgs)
+            })
+        });
+        let tst = msgs.and_then(|msgs| msgs.take(2).collect());
         let result = run_and_wait(tst).unwrap();
+        let result = result
+            .into_iter()
+            .map(|msg| (msg.channel, msg.pattern, msg.payload))
+            .collect::<Vec<_>>();
+        let pattern = Some("test-[ab]*".to_string());
+        assert_eq!(
+            result,
+            vec![
+                ("test-a1".to_string(), pattern.clone(), "first".into()),
+                ("test-b".to_string(), pattern, "second".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ssubscribe_test() {
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
+        let paired_c = super::paired_connect(&addr);
+        let pubsub_c = super::pubsub_connect(&addr);
+        let msgs = paired_c.join(pubsub_c).and_then(|(paired, pubsub)| {
+            pubsub.ssubscribe("test-shard").and_then(move |msgs| {
+                faf!(paired.send(resp_array!["PUBLISH", "test-shard", "not sharded"]));
+                paired
+                    .spublish("test-shard", "sharded")
+                    .map(|receivers| (receivers, msgs))
+            })
+        });
+        let tst = msgs.and_then(|(receivers, msgs)| {
+            msgs.into_future()
+                .map(move |(msg, _)| (receivers, msg))
+                .map_err(|(e, _)| e)
+        });
+        let (receivers, msg) = run_and_wait(tst).unwrap();
+        assert_eq!(receivers, 1);
+        assert_eq!(msg.map(|msg| msg.payload), Some("sharded".into()));
+    }
+
+    #[test]
+    fn ssubscribe_ended_by_server() {
+        let script = vec![
+            Action::Read(1),
+            Action::Send(resp_array!["ssubscribe", "test-shard", resp::RespValue::Integer(1)]),
+            Action::Send(resp_array!["smessage", "test-shard", "moving"]),
+            Action::Send(resp_array!["sunsubscribe", "test-shard", resp::RespValue::Integer(0)]),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+
+        let test_f = super::pubsub_connect(&server.addr())
+            .and_then(|pubsub| pubsub.ssubscribe("test-shard"))
+            .and_then(|msgs| msgs.then(Ok::<_, error::Error>).collect());
+        let result = run_and_wait(test_f).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].as_ref().unwrap().payload, "moving".into());
+        match result[1] {
+            Err(error::Error::Remote(_)) => (),
+            ref x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn mock_server_commands() {
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
+
+        let test_f = super::paired_connect(&addr).and_then(|connection| {
+            faf!(connection.send(resp_array!["HSET", "H", "a", "1", "b", "2"]));
+            faf!(connection.send(resp_array!["RPUSH", "L", "x", "y", "z"]));
+            faf!(connection.send(resp_array!["MULTI"]));
+            faf!(connection.send(resp_array!["INCR", "CTR"]));
+            faf!(connection.send(resp_array!["LRANGE", "L", "1", "-1"]));
+            let exec_f = connection.send(resp_array!["EXEC"]);
+            let hash_f = connection.send(resp_array!["HGETALL", "H"]);
+            let wrong_f = connection
+                .send::<resp::RespValue>(resp_array!["LLEN", "H"])
+                .then(|result| Ok(result.is_err()));
+            exec_f.join3(hash_f, wrong_f)
+        });
+        let (exec, hash, wrong_type): ((i64, Vec<String>), HashMap<String, String>, bool) =
+            run_and_wait(test_f).unwrap();
+        assert_eq!(exec, (1, vec!["y".to_string(), "z".to_string()]));
+        assert_eq!(hash.len(), 2);
+        assert_eq!(hash["b"], "2");
+        assert!(wrong_type);
+    }
+
+    #[test]
+    fn mock_server_stuck_subscriber() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
+
+        // Subscribes, then never reads the messages published
+        let mut stuck = TcpStream::connect(addr).expect("Cannot connect");
+        stuck
+            .write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$5\r\ntopic\r\n")
+            .expect("Cannot subscribe");
+        let mut confirmation = [0; 64];
+        let _ = stuck.read(&mut confirmation).expect("Cannot read confirmation");
+
+        let message = vec![b'x'; 256 * 1024];
+        let test_f = super::paired_connect(&addr).and_then(move |connection| {
+            let publishes = (0..64)
+                .map(|_| connection.send::<i64>(resp_array!["PUBLISH", "topic", message.clone()]))
+                .collect::<Vec<_>>();
+            future::join_all(publishes).and_then(move |_| super::paired_connect(&addr)).and_then(|other| {
+                faf!(other.send(resp_array!["SET", "X", "other"]));
+                other.send::<String>(resp_array!["GET", "X"])
+            })
+        });
+        assert_eq!(run_and_wait(test_f).unwrap(), "other");
+        drop(stuck);
+    }
+
+    #[test]
+    fn transaction_test() {
+        let server = MockServer::start().expect("Cannot start server");
+        let test_f = super::paired_connect(&server.addr()).and_then(|connection| {
+            faf!(connection.send(resp_array!["SET", "S", "not a number"]));
+            let mut transaction = connection.transaction();
+            let incr_f = transaction.send::<i64>(resp_array!["INCR", "CTR"]);
+            let wrong_f = transaction
+                .send::<i64>(resp_array!["INCR", "S"])
+                .then(|result| Ok(result.is_err()));
+            let get_f = transaction.send::<String>(resp_array!["GET", "S"]);
+            transaction
+                .exec()
+                .and_then(|()| incr_f.join3(wrong_f, get_f))
+        });
+        let (incr, wrong, get) = run_and_wait(test_f).unwrap();
+        assert_eq!(incr, 1);
+        assert!(wrong);
+        assert_eq!(get, "not a number");
+    }
+
+    #[test]
+    fn transaction_exec_abort() {
+        let server = MockServer::start().expect("Cannot start server");
+        let test_f = super::paired_connect(&server.addr()).and_then(|connection| {
+            let mut transaction = connection.transaction();
+            let incr_f = transaction.send::<i64>(resp_array!["INCR", "CTR"]).then(Ok);
+            faf!(transaction.send(resp_array!["GET"]));
+            let exec_f = transaction.exec().then(Ok);
+            let ctr_f = connection.send::<Option<i64>>(resp_array!["GET", "CTR"]);
+            exec_f.join3(incr_f, ctr_f)
+        });
+        let (exec, incr, ctr): (Result<(), error::Error>, Result<i64, error::Error>, Option<i64>) =
+            run_and_wait(test_f).unwrap();
+        match exec {
+            Err(error::Error::ExecAbort(ref reason)) => assert!(reason.contains("wrong number of arguments")),
+            ref x => panic!("Unexpected result: {:?}", x),
+        }
+        match incr {
+            Err(error::Error::ExecAbort(_)) => (),
+            ref x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(ctr, None);
+    }
+
+    #[test]
+    fn transaction_aborted() {
+        let script = vec![
+            Action::Read(3),
+            Action::Send(resp::RespValue::SimpleString("OK".into())),
+            Action::Send(resp::RespValue::SimpleString("QUEUED".into())),
+            Action::Send(resp::RespValue::Nil),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+        let test_f = super::paired_connect(&server.addr()).and_then(|connection| {
+            let mut transaction = connection.transaction();
+            let incr_f = transaction.send::<i64>(resp_array!["INCR", "CTR"]).then(Ok);
+            transaction.exec().then(Ok).join(incr_f)
+        });
+        let (exec, incr): (Result<(), error::Error>, Result<i64, error::Error>) = run_and_wait(test_f).unwrap();
+        match (exec, incr) {
+            (Err(error::Error::TransactionAborted), Err(error::Error::TransactionAborted)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn pipeline_test() {
+        let server = MockServer::start().expect("Cannot start server");
+        let test_f = super::paired_connect(&server.addr()).and_then(|connection| {
+            faf!(connection.send(resp_array!["SET", "S", "not a number"]));
+            let mut pipeline = connection.pipeline();
+            pipeline
+                .add(resp_array!["INCR", "CTR"])
+                .add(resp_array!["INCR", "S"])
+                .add(resp_array!["GET", "S"]);
+            let mut failing = connection.pipeline();
+            failing.add(resp_array!["GET", "S"]).add(resp_array!["INCR", "S"]);
+            let empty = connection.pipeline();
+            pipeline
+                .send::<(Result<i64, error::Error>, Result<i64, error::Error>, Result<String, error::Error>)>()
+                .join3(
+                    failing.send::<(String, i64)>().then(Ok),
+                    empty.send::<Vec<resp::RespValue>>(),
+                )
+        });
+        let ((incr, wrong, get), failed, empty) = run_and_wait(test_f).unwrap();
+        assert_eq!(incr.unwrap(), 1);
+        match wrong {
+            Err(error::Error::Remote(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(get.unwrap(), "not a number");
+        match failed {
+            Err(error::Error::Remote(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn lease_test() {
+        let server = MockServer::start().expect("Cannot start server");
+        let test_f = super::paired_connect(&server.addr()).and_then(|connection| {
+            let leased = connection.lease();
+            let set_f = connection.send::<()>(resp_array!["SET", "K", "1"]);
+            leased
+                .send::<Option<String>>(resp_array!["GET", "K"])
+                .and_then(move |before| {
+                    drop(leased);
+                    set_f.map(move |()| before)
+                })
+                .and_then(move |before| {
+                    connection
+                        .send::<Option<String>>(resp_array!["GET", "K"])
+                        .map(move |after| (before, after))
+                })
+        });
+        let (before, after) = run_and_wait(test_f).unwrap();
+        assert_eq!(before, None);
+        assert_eq!(after, Some("1".to_string()));
+    }
+
+    /// Doubles `CTR` with `watch`, changing it from another connection during the first `interfere` attempts.
+    fn watch_double(attempts: usize, interfere: usize) -> (Result<i64, error::Error>, usize, i64) {
+        use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_f = calls.clone();
+        let test_f = super::paired_connect(&addr)
+            .join(super::paired_connect(&addr))
+            .and_then(move |(connection, other)| {
+                faf!(connection.send(resp_array!["SET", "CTR", "1"]));
+                let doubled_f = connection.watch(vec!["CTR"], attempts, move |leased| {
+                    let call = calls_f.fetch_add(1, Ordering::SeqCst);
+                    let other = other.clone();
+                    leased
+                        .send::<String>(resp_array!["GET", "CTR"])
+                        .and_then(move |value| {
+                            let value: i64 = value.parse().expect("Not a number");
+                            let changed: super::paired::SendBox<()> = if call < interfere {
+                                Box::new(other.send::<i64>(resp_array!["INCR", "CTR"]).map(|_| ()))
+                            } else {
+                                Box::new(future::ok(()))
+                            };
+                            changed.map(move |()| value)
+                        })
+                        .map(move |value| {
+                            let mut transaction = leased.transaction();
+                            let doubled = (value * 2).to_string();
+                            let doubled_f = transaction.send::<()>(resp_array!["SET", "CTR", doubled]);
+                            (transaction, doubled_f.map(move |()| value * 2))
+                        })
+                });
+                doubled_f
+                    .and_then(|doubled_f| doubled_f)
+                    .then(Ok)
+                    .and_then(move |doubled| {
+                        connection
+                            .send::<String>(resp_array!["GET", "CTR"])
+                            .map(move |value| (doubled, value.parse().expect("Not a number")))
+                    })
+            });
+        let (doubled, value) = run_and_wait(test_f).unwrap();
+        (doubled, calls.load(Ordering::SeqCst), value)
+    }
+
+    #[test]
+    fn watch_retry() {
+        let (doubled, calls, value) = watch_double(3, 1);
+        assert_eq!(doubled.unwrap(), 4);
+        assert_eq!(calls, 2);
+        assert_eq!(value, 4);
+    }
+
+    #[test]
+    fn watch_attempts_exhausted() {
+        let (doubled, calls, value) = watch_double(2, 2);
+        match doubled {
+            Err(error::Error::TransactionAborted) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(calls, 2);
+        assert_eq!(value, 3);
+    }
+
+    /// A connection to database zero and a connection to database one, to show which is used for a command.
+    fn two_databases(
+        addr: &SocketAddr,
+    ) -> Box<Future<Item = Vec<super::PairedConnection>, Error = error::Error> + Send> {
+        let addr = *addr;
+        Box::new(super::paired_connect(&addr).and_then(move |first| {
+            super::ConnectionBuilder::new(&addr)
+                .db(1)
+                .paired_connect()
+                .map(move |second| vec![first, second])
+        }))
+    }
+
+    #[test]
+    fn multiplexed_round_robin() {
+        use super::multiplexed::Distribution;
+
+        let server = MockServer::start().expect("Cannot start server");
+        let test_f = two_databases(&server.addr()).and_then(|connections| {
+            let client = super::MultiplexedClient::new(connections, Distribution::RoundRobin);
+            faf!(client.send(resp_array!["SET", "K", "v"]));
+            let other_f = client.send::<Option<String>>(resp_array!["GET", "K"]);
+            let same_f = client.send::<Option<String>>(resp_array!["GET", "K"]);
+            other_f.join(same_f)
+        });
+        let (other, same) = run_and_wait(test_f).unwrap();
+        assert_eq!(other, None);
+        assert_eq!(same, Some("v".to_string()));
+    }
+
+    #[test]
+    fn multiplexed_by_key() {
+        use super::multiplexed::Distribution;
+
+        let server = MockServer::start().expect("Cannot start server");
+        let test_f = two_databases(&server.addr()).and_then(|connections| {
+            let client = super::MultiplexedClient::new(connections.clone(), Distribution::ByKey);
+            let keys = (0..10).map(|idx| format!("K{}", idx)).collect::<Vec<_>>();
+            for key in &keys {
+                faf!(client.send(resp_array!["SET", key.as_str(), key.as_str()]));
+            }
+            let values_f = future::join_all(
+                keys.iter()
+                    .map(|key| client.send::<Option<String>>(resp_array!["GET", key.as_str()]))
+                    .collect::<Vec<_>>(),
+            );
+            let sizes_f = future::join_all(
+                connections
+                    .iter()
+                    .map(|connection| connection.send::<i64>(resp_array!["DBSIZE"]))
+                    .collect::<Vec<_>>(),
+            );
+            values_f.join(sizes_f).map(move |(values, sizes)| (keys, values, sizes))
+        });
+        let (keys, values, sizes) = run_and_wait(test_f).unwrap();
+        assert_eq!(values, keys.into_iter().map(Some).collect::<Vec<_>>());
+        assert_eq!(sizes.iter().sum::<i64>(), 10);
+        assert!(sizes.iter().all(|size| *size > 0));
+    }
+
+    #[test]
+    fn multiplexed_least_in_flight() {
+        use super::multiplexed::Distribution;
+
+        let scripts = vec![
+            vec![
+                Action::Read(1),
+                Action::Send("a".into()),
+                Action::Read(1),
+                Action::Send("b".into()),
+                Action::Read(1),
+                Action::Send("c".into()),
+            ],
+            vec![Action::Read(1), Action::Send("d".into())],
+        ];
+        let server = FakeServer::start(scripts).expect("Cannot start server");
+        let addr = server.addr();
+        let test_f = super::paired_connect(&addr)
+            .and_then(move |first| super::paired_connect(&addr).map(move |second| vec![first, second]))
+            .and_then(|connections| {
+                let client = super::MultiplexedClient::new(connections, Distribution::LeastInFlight);
+                let echo = |client: &super::MultiplexedClient| {
+                    client.send::<String>(resp_array!["ECHO", "x"])
+                };
+                // The first connection is free each time, until two commands are sent at once
+                echo(&client)
+                    .and_then(move |a| echo(&client).map(move |b| (a, b, client)))
+                    .and_then(move |(a, b, client)| {
+                        echo(&client)
+                            .join(echo(&client))
+                            .map(move |(c, d)| vec![a, b, c, d])
+                    })
+            });
+        let result = run_and_wait(test_f).unwrap();
+        assert_eq!(result, vec!["a", "b", "c", "d"]);
+        let received = server.received();
+        assert_eq!(received[0].len(), 3);
+        assert_eq!(received[1].len(), 1);
+    }
+
+    /// A reply to `CLUSTER SLOTS`, with each range of slots served by a node with no replicas.
+    fn cluster_slots(ranges: Vec<(i64, i64, SocketAddr)>) -> resp::RespValue {
+        resp::RespValue::Array(
+            ranges
+                .into_iter()
+                .map(|(start, end, addr)| {
+                    resp_array![
+                        resp::RespValue::Integer(start),
+                        resp::RespValue::Integer(end),
+                        resp_array![
+                            addr.ip().to_string(),
+                            resp::RespValue::Integer(i64::from(addr.port())),
+                            "node-id"
+                        ]
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn cluster_moved() {
+        // "foo" is in slot 12182, "bar" in 5061
+        let other = FakeServer::start_with(|addr| {
+            vec![vec![
+                Action::Read(1),
+                Action::Send(cluster_slots(vec![(0, 16383, addr)])),
+                Action::Read(1),
+                Action::Send("moved".into()),
+                Action::Read(1),
+                Action::Send("also moved".into()),
+            ]]
+        }).expect("Cannot start server");
+        let other_addr = other.addr();
+        let seed = FakeServer::start_with(|addr| {
+            vec![vec![
+                Action::Read(1),
+                Action::Send(cluster_slots(vec![(0, 16383, addr)])),
+                Action::Read(1),
+                Action::Send(resp::RespValue::Error(format!("MOVED 12182 {}", other_addr))),
+            ]]
+        }).expect("Cannot start server");
+        let test_f = super::cluster_connect(&seed.addr()).and_then(|cluster| {
+            cluster
+                .send::<String>(resp_array!["GET", "foo"])
+                .and_then(move |foo| cluster.send::<String>(resp_array!["GET", "bar"]).map(|bar| (foo, bar)))
+        });
+        let (foo, bar) = run_and_wait(test_f).unwrap();
+        assert_eq!(foo, "moved");
+        assert_eq!(bar, "also moved");
+        let received = other.received();
+        assert_eq!(received[0][0], resp::Command::new("CLUSTER", vec!["SLOTS".into()]));
+        assert_eq!(received[0][1], resp::Command::new("GET", vec!["foo".into()]));
+        assert_eq!(received[0][2], resp::Command::new("GET", vec!["bar".into()]));
+    }
+
+    #[test]
+    fn cluster_moved_other_slot() {
+        // The key of an unknown command is taken to be its first argument, in slot 5061, but the node says it's
+        // in 12182.  The slot map can't be fetched from the other node, so only the redirect says where to go.
+        let other = FakeServer::start(vec![vec![
+            Action::Read(1),
+            Action::Send(resp::RespValue::Error("ERR unknown subcommand".into())),
+            Action::Read(1),
+            Action::Send(resp::RespValue::Error("ERR unknown subcommand".into())),
+            Action::Read(1),
+            Action::Send("moved".into()),
+        ]]).expect("Cannot start server");
+        let other_addr = other.addr();
+        let seed = FakeServer::start_with(|addr| {
+            vec![vec![
+                Action::Read(1),
+                Action::Send(cluster_slots(vec![(0, 16383, addr)])),
+                Action::Read(1),
+                Action::Send(resp::RespValue::Error(format!("MOVED 12182 {}", other_addr))),
+            ]]
+        }).expect("Cannot start server");
+        let test_f = super::cluster_connect(&seed.addr())
+            .and_then(|cluster| cluster.send::<String>(resp_array!["CUSTOM.GET", "bar", "foo"]));
+        assert_eq!(run_and_wait(test_f).unwrap(), "moved");
+        assert_eq!(
+            other.received()[0][2],
+            resp::Command::new("CUSTOM.GET", vec!["bar".into(), "foo".into()])
+        );
+    }
+
+    #[test]
+    fn cluster_ask() {
+        let other = FakeServer::start(vec![vec![
+            Action::Read(2),
+            Action::Send(resp::RespValue::SimpleString("OK".into())),
+            Action::Send("migrating".into()),
+        ]]).expect("Cannot start server");
+        let other_addr = other.addr();
+        let seed = FakeServer::start_with(|addr| {
+            vec![vec![
+                Action::Read(1),
+                Action::Send(cluster_slots(vec![(0, 8191, addr), (8192, 16383, addr)])),
+                Action::Read(1),
+                Action::Send(resp::RespValue::Error(format!("ASK 12182 {}", other_addr))),
+                Action::Read(1),
+                Action::Send("not migrated".into()),
+            ]]
+        }).expect("Cannot start server");
+        let test_f = super::cluster_connect(&seed.addr()).and_then(|cluster| {
+            cluster
+                .send::<String>(resp_array!["GET", "foo"])
+                .and_then(move |first| {
+                    cluster
+                        .send::<String>(resp_array!["GET", "foo"])
+                        .map(|second| (first, second))
+                })
+        });
+        let (first, second) = run_and_wait(test_f).unwrap();
+        assert_eq!(first, "migrating");
+        assert_eq!(second, "not migrated");
+        let received = other.received();
+        assert_eq!(received[0][0], resp::Command::new("ASKING", vec![]));
+        assert_eq!(received[0][1], resp::Command::new("GET", vec!["foo".into()]));
+    }
+
+    #[test]
+    fn cluster_cross_slot() {
+        let seed = FakeServer::start_with(|addr| {
+            vec![vec![
+                Action::Read(1),
+                Action::Send(cluster_slots(vec![(0, 16383, addr)])),
+                Action::Read(1),
+                Action::Send(resp_array!["1", "2"]),
+            ]]
+        }).expect("Cannot start server");
+        let test_f = super::cluster_connect(&seed.addr()).and_then(|cluster| {
+            let cross_f = cluster
+                .send::<Vec<String>>(resp_array!["MGET", "foo", "bar"])
+                .then(Ok::<_, error::Error>);
+            let same_f = cluster.send::<Vec<String>>(resp_array!["MGET", "{foo}1", "{foo}2"]);
+            cross_f.join(same_f)
+        });
+        let (cross, same) = run_and_wait(test_f).unwrap();
+        match cross {
+            Err(error::Error::CrossSlot(_)) => (),
+            result => panic!("Expected a cross-slot error, got: {:?}", result),
+        }
+        assert_eq!(same, vec!["1", "2"]);
+        assert_eq!(seed.received()[0].len(), 2);
+    }
+
+    /// A reply to `ROLE` from a master with no replicas.
+    fn master_role() -> resp::RespValue {
+        resp_array!["master", resp::RespValue::Integer(0), resp::RespValue::Array(vec![])]
+    }
+
+    /// A reply to `SENTINEL get-master-addr-by-name`.
+    fn sentinel_master(addr: &SocketAddr) -> resp::RespValue {
+        resp_array![addr.ip().to_string(), addr.port().to_string()]
+    }
+
+    #[test]
+    fn sentinel_failover() {
+        let first = FakeServer::start(vec![vec![
+            Action::Read(1),
+            Action::Send(master_role()),
+            Action::Read(1),
+            Action::Send("first".into()),
+        ]]).expect("Cannot start server");
+        let second = FakeServer::start(vec![vec![
+            Action::Read(1),
+            Action::Send(master_role()),
+            Action::Read(1),
+            Action::Send("second".into()),
+        ]]).expect("Cannot start server");
+        let (first_addr, second_addr) = (first.addr(), second.addr());
+        let switch = format!(
+            "mymaster {} {} {} {}",
+            first_addr.ip(),
+            first_addr.port(),
+            second_addr.ip(),
+            second_addr.port()
+        );
+        let sentinel = FakeServer::start(vec![
+            vec![Action::Read(1), Action::Send(sentinel_master(&first_addr))],
+            vec![
+                Action::Read(1),
+                Action::Send(resp_array!["subscribe", "+switch-master", resp::RespValue::Integer(1)]),
+                Action::Delay(Duration::from_millis(200)),
+                Action::Send(resp_array!["message", "+switch-master", switch]),
+            ],
+        ]).expect("Cannot start server");
+        let test_f = super::sentinel_connect(vec![sentinel.addr()], "mymaster").and_then(|connection| {
+            connection
+                .send::<String>(resp_array!["GET", "key"])
+                .and_then(|before| delay(500).map(move |()| before))
+                .and_then(move |before| {
+                    connection
+                        .send::<String>(resp_array!["GET", "key"])
+                        .map(move |after| (before, after, connection.master_addr()))
+                })
+        });
+        let (before, after, master_addr) = run_and_wait(test_f).unwrap();
+        assert_eq!(before, "first");
+        assert_eq!(after, "second");
+        assert_eq!(master_addr, second_addr);
+        let received = sentinel.received();
+        assert_eq!(
+            received[0][0],
+            resp::Command::new("SENTINEL", vec!["get-master-addr-by-name".into(), "mymaster".into()])
+        );
+        assert_eq!(received[1][0], resp::Command::new("SUBSCRIBE", vec!["+switch-master".into()]));
+    }
+
+    #[test]
+    fn sentinel_failover_retry() {
+        let first = FakeServer::start(vec![vec![
+            Action::Read(1),
+            Action::Send(master_role()),
+        ]]).expect("Cannot start server");
+        // Not yet promoted when first connected to after the failover
+        let second = FakeServer::start(vec![
+            vec![
+                Action::Read(1),
+                Action::Send(resp_array!["slave", "127.0.0.1", resp::RespValue::Integer(6379)]),
+            ],
+            vec![
+                Action::Read(1),
+                Action::Send(master_role()),
+                Action::Read(1),
+                Action::Send("second".into()),
+            ],
+        ]).expect("Cannot start server");
+        let (first_addr, second_addr) = (first.addr(), second.addr());
+        let switch = format!(
+            "mymaster {} {} {} {}",
+            first_addr.ip(),
+            first_addr.port(),
+            second_addr.ip(),
+            second_addr.port()
+        );
+        let sentinel = FakeServer::start(vec![
+            vec![Action::Read(1), Action::Send(sentinel_master(&first_addr))],
+            vec![
+                Action::Read(1),
+                Action::Send(resp_array!["subscribe", "+switch-master", resp::RespValue::Integer(1)]),
+                Action::Delay(Duration::from_millis(200)),
+                Action::Send(resp_array!["message", "+switch-master", switch]),
+            ],
+            vec![Action::Read(1), Action::Send(sentinel_master(&second_addr))],
+        ]).expect("Cannot start server");
+        let test_f = super::sentinel_connect(vec![sentinel.addr()], "mymaster").and_then(|connection| {
+            delay(800).and_then(move |()| {
+                connection
+                    .send::<String>(resp_array!["GET", "key"])
+                    .map(move |after| (after, connection.master_addr()))
+            })
+        });
+        let (after, master_addr) = run_and_wait(test_f).unwrap();
+        assert_eq!(after, "second");
+        assert_eq!(master_addr, second_addr);
+        assert_eq!(sentinel.received().len(), 3);
+        assert_eq!(second.received().len(), 2);
+    }
+
+    #[test]
+    fn sentinel_stale_master() {
+        let replica = FakeServer::start(vec![vec![
+            Action::Read(1),
+            Action::Send(resp_array![
+                "slave",
+                "127.0.0.1",
+                resp::RespValue::Integer(6379),
+                "connected",
+                resp::RespValue::Integer(0)
+            ]),
+        ]]).expect("Cannot start server");
+        let master = FakeServer::start(vec![vec![
+            Action::Read(1),
+            Action::Send(master_role()),
+            Action::Read(1),
+            Action::Send("value".into()),
+        ]]).expect("Cannot start server");
+        let stale = FakeServer::start(vec![vec![
+            Action::Read(1),
+            Action::Send(sentinel_master(&replica.addr())),
+        ]]).expect("Cannot start server");
+        let current = FakeServer::start(vec![
+            vec![Action::Read(1), Action::Send(sentinel_master(&master.addr()))],
+            vec![
+                Action::Read(1),
+                Action::Send(resp_array!["subscribe", "+switch-master", resp::RespValue::Integer(1)]),
+            ],
+        ]).expect("Cannot start server");
+        let sentinels = vec![stale.addr(), current.addr()];
+        let test_f = super::sentinel_connect(sentinels, "mymaster").and_then(|connection| {
+            connection
+                .send::<String>(resp_array!["GET", "key"])
+                .map(move |value| (value, connection.master_addr()))
+        });
+        let (value, master_addr) = run_and_wait(test_f).unwrap();
+        assert_eq!(value, "value");
+        assert_eq!(master_addr, master.addr());
+        assert_eq!(replica.received()[0], vec![resp::Command::new("ROLE", vec![])]);
+    }
+
+    fn pool_builder(addr: &SocketAddr) -> super::pool::PoolBuilder {
+        super::pool::PoolBuilder::new(super::ConnectionBuilder::new(addr))
+    }
+
+    #[test]
+    fn pool_reuse() {
+        let server = MockServer::start().expect("Cannot start server");
+        let pool = pool_builder(&server.addr()).build();
+        let test_f = pool.get()
+            .and_then(|pooled| {
+                pooled
+                    .send::<()>(resp_array!["CLIENT", "SETNAME", "first"])
+                    .map(move |()| drop(pooled))
+            })
+            .and_then(move |()| {
+                pool.get()
+                    .and_then(|pooled| pooled.send::<Option<String>>(resp_array!["CLIENT", "GETNAME"]))
+                    .map(move |name| (name, pool.size(), pool.idle()))
+            });
+        let (name, size, idle) = run_and_wait(test_f).unwrap();
+        assert_eq!(name, Some("first".to_string()));
+        assert_eq!(size, 1);
+        assert_eq!(idle, 1);
+    }
+
+    #[test]
+    fn pool_checkout_timeout() {
+        let server = MockServer::start().expect("Cannot start server");
+        let pool = pool_builder(&server.addr())
+            .max_size(1)
+            .checkout_timeout(Duration::from_millis(100))
+            .build();
+        let test_f = pool.get().and_then(move |held| {
+            pool.get()
+                .then(|result| Ok(result.err()))
+                .and_then(move |timed_out| {
+                    // Waits for the connection that is held
+                    let returned_f = delay(50).map(move |()| drop(held));
+                    returned_f.join(pool.get()).map(move |((), pooled)| {
+                        drop(pooled);
+                        (timed_out, pool.size())
+                    })
+                })
+        });
+        let (timed_out, size) = run_and_wait(test_f).unwrap();
+        match timed_out {
+            Some(error::Error::Connection(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(size, 1);
+    }
+
+    #[test]
+    fn pool_waiting_order() {
+        use futures::{future::Either, Async};
+
+        let server = MockServer::start().expect("Cannot start server");
+        let pool = pool_builder(&server.addr())
+            .max_size(1)
+            .health_check(false)
+            .build();
+        let test_f = pool.get().and_then(move |held| {
+            future::lazy(move || {
+                let mut second = pool.get();
+                let mut third = pool.get();
+                assert!(second.poll()?.is_not_ready());
+                assert!(third.poll()?.is_not_ready());
+                // The second is woken, but the connection is taken before it tries again
+                drop(held);
+                let taken = match pool.get().poll()? {
+                    Async::Ready(pooled) => pooled,
+                    Async::NotReady => panic!("The returned connection should be idle"),
+                };
+                assert!(second.poll()?.is_not_ready());
+                drop(taken);
+                Ok::<_, error::Error>(second.select2(third).then(|result| match result {
+                    Ok(Either::A(_)) => Ok("second"),
+                    Ok(Either::B(_)) => Ok("third"),
+                    Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+                }))
+            }).flatten()
+        });
+        assert_eq!(run_and_wait(test_f).unwrap(), "second");
+    }
+
+    #[test]
+    fn pool_waiter_dropped_after_notified() {
+        use futures::Async;
+
+        let server = MockServer::start().expect("Cannot start server");
+        let pool = pool_builder(&server.addr())
+            .max_size(1)
+            .health_check(false)
+            .build();
+        let test_f = pool.get().and_then(move |held| {
+            future::lazy(move || {
+                let mut second = pool.get();
+                let mut third = pool.get();
+                assert!(second.poll()?.is_not_ready());
+                assert!(third.poll()?.is_not_ready());
+                // The second is woken, but given up on before it tries again
+                drop(held);
+                drop(second);
+                let pooled = match third.poll()? {
+                    Async::Ready(pooled) => pooled,
+                    Async::NotReady => panic!("The third should have been woken instead"),
+                };
+                Ok::<_, error::Error>(pooled.send::<String>(resp_array!["PING"]))
+            }).flatten()
+        });
+        assert_eq!(run_and_wait(test_f).unwrap(), "PONG");
+    }
+
+    #[test]
+    fn pool_idle_timeout() {
+        let server = MockServer::start().expect("Cannot start server");
+        let pool = pool_builder(&server.addr())
+            .idle_timeout(Duration::from_millis(50))
+            .build();
+        let test_f = pool.get()
+            .and_then(|pooled| {
+                pooled
+                    .send::<()>(resp_array!["CLIENT", "SETNAME", "first"])
+                    .map(move |()| drop(pooled))
+            })
+            .and_then(|()| delay(100))
+            .and_then(move |()| {
+                pool.get()
+                    .and_then(|pooled| pooled.send::<Option<String>>(resp_array!["CLIENT", "GETNAME"]))
+                    .map(move |name| (name, pool.size()))
+            });
+        let (name, size) = run_and_wait(test_f).unwrap();
+        assert_eq!(name, None);
+        assert_eq!(size, 1);
+    }
+
+    #[test]
+    fn pool_health_check() {
+        let scripts = vec![
+            vec![Action::Close],
+            vec![Action::Read(1), Action::Send(resp::RespValue::SimpleString("PONG".into()))],
+        ];
+        let server = FakeServer::start(scripts).expect("Cannot start server");
+        let pool = pool_builder(&server.addr()).build();
+        let test_f = pool.get()
+            .map(drop)
+            .and_then(|()| delay(100))
+            .and_then(move |()| pool.get().map(move |pooled| (pooled, pool)))
+            .and_then(|(pooled, pool)| {
+                pooled
+                    .send::<String>(resp_array!["PING"])
+                    .map(move |pong| (pong, pool.size()))
+            });
+        let (pong, size) = run_and_wait(test_f).unwrap();
+        assert_eq!(pong, "PONG");
+        assert_eq!(size, 1);
+        assert_eq!(server.received()[1], vec![resp::Command::new("PING", vec![])]);
+    }
+
+    #[test]
+    fn builder_setup() {
+        let ok = resp::RespValue::SimpleString("OK".into());
+        let script = vec![
+            Action::Read(1),
+            Action::Send(ok.clone()),
+            Action::Read(1),
+            Action::Send(ok.clone()),
+            Action::Read(1),
+            Action::Send(ok),
+            Action::Read(1),
+            Action::Send("PONG".into()),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+
+        let test_f = super::ConnectionBuilder::new(&server.addr())
+            .username("user")
+            .password("secret")
+            .db(3)
+            .client_name("test-client")
+            .paired_connect()
+            .and_then(|connection| connection.send::<String>(resp_array!["PING"]));
+        assert_eq!(run_and_wait(test_f).unwrap(), "PONG");
+
+        let received = server.received();
+        let expected = vec![
+            resp::Command::new("AUTH", vec!["user".into(), "secret".into()]),
+            resp::Command::new("SELECT", vec!["3".into()]),
+            resp::Command::new("CLIENT", vec!["SETNAME".into(), "test-client".into()]),
+            resp::Command::new("PING", vec![]),
+        ];
+        assert_eq!(received[0], expected);
+    }
+
+    #[test]
+    fn builder_debug_hides_password() {
+        let addr = "127.0.0.1:6379".parse().unwrap();
+        let builder = super::ConnectionBuilder::new(&addr).password("secret");
+        let debug = format!("{:?}", super::pool::PoolBuilder::new(builder));
+        assert!(!debug.contains("secret"));
+        assert!(debug.contains(r#"password: Some("***")"#));
+    }
+
+    #[test]
+    fn builder_setup_failure() {
+        let script = vec![
+            Action::Read(1),
+            Action::Send(resp::RespValue::Error("WRONGPASS invalid password".into())),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+
+        let test_f = super::ConnectionBuilder::new(&server.addr())
+            .password("wrong")
+            .paired_connect();
+        let err = match run_and_wait(test_f) {
+            Err(err) => err,
+            Ok(_) => panic!("Connection should have failed"),
+        };
+        assert_eq!(err.to_string(), "AUTH failed: WRONGPASS invalid password");
+        match err {
+            error::Error::Setup(ref command, ref e) => {
+                assert_eq!(command, "AUTH");
+                match **e {
+                    error::Error::Remote(ref msg) => assert_eq!(msg, "WRONGPASS invalid password"),
+                    ref e => panic!("Unexpected error: {:?}", e),
+                }
+            }
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn builder_db_and_client_name() {
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
+
+        let test_f = super::ConnectionBuilder::new(&addr)
+            .db(1)
+            .client_name("test-client")
+            .paired_connect()
+            .join(super::paired_connect(&addr))
+            .and_then(|(db1, db0)| {
+                faf!(db1.send(resp_array!["SET", "X", "in db 1"]));
+                let name_f = db1.send(resp_array!["CLIENT", "GETNAME"]);
+                let db1_f = db1.send(resp_array!["GET", "X"]);
+                let db0_f = db0.send(resp_array!["GET", "X"]);
+                name_f.join3(db1_f, db0_f)
+            });
+        let (name, db1, db0): (String, Option<String>, Option<String>) =
+            run_and_wait(test_f).unwrap();
+        assert_eq!(name, "test-client");
+        assert_eq!(db1, Some("in db 1".to_string()));
+        assert_eq!(db0, None);
+    }
+
+    /// Resolves after `millis` milliseconds.
+    fn delay(millis: u64) -> Box<Future<Item = (), Error = error::Error> + Send> {
+        Box::new(
+            Delay::new(Instant::now() + Duration::from_millis(millis))
+                .map_err(|e| error::internal(e.to_string())),
+        )
+    }
+
+    #[test]
+    fn reconnect_after_disconnect() {
+        let server = MockServer::start().expect("Cannot start server");
+        let addr = server.addr();
+
+        let test_f = super::ConnectionBuilder::new(&addr)
+            .db(1)
+            .reconnect(super::Backoff::new(
+                Duration::from_millis(10),
+                Duration::from_millis(100),
+            ))
+            .paired_connect()
+            .and_then(|connection| {
+                faf!(connection.send(resp_array!["SET", "X", "before"]));
+                // The mock server closes the connection after replying to QUIT
+                connection
+                    .send::<String>(resp_array!["QUIT"])
+                    .and_then(|_| delay(200))
+                    .and_then(move |_| connection.send::<String>(resp_array!["GET", "X"]))
+            });
+        assert_eq!(run_and_wait(test_f).unwrap(), "before");
+    }
+
+    #[test]
+    fn reconnect_fails_in_flight_requests() {
+        let scripts = vec![
+            vec![
+                Action::Read(2),
+                Action::Send(resp::RespValue::SimpleString("OK".into())),
+                Action::Close,
+            ],
+            vec![Action::Read(1), Action::Send("PONG".into())],
+        ];
+        let server = FakeServer::start(scripts).expect("Cannot start server");
+
+        let test_f = super::ConnectionBuilder::new(&server.addr())
+            .reconnect(super::Backoff::default())
+            .paired_connect()
+            .and_then(|connection| {
+                let set_f = connection.send::<String>(resp_array!["SET", "X", "Y"]);
+                let get_f = connection
+                    .send::<String>(resp_array!["GET", "X"])
+                    .then(Ok);
+                set_f
+                    .join(get_f)
+                    .and_then(|results| delay(200).map(|_| results))
+                    .and_then(move |(set, get)| {
+                        connection
+                            .send::<String>(resp_array!["PING"])
+                            .map(|ping| (set, get, ping))
+                    })
+            });
+        let (set, get, ping) = run_and_wait(test_f).unwrap();
+        assert_eq!(set, "OK");
+        match get {
+            Err(error::Error::Connection(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(ping, "PONG");
+    }
+
+    #[test]
+    fn reconnect_waits_after_quick_loss() {
+        let scripts = vec![
+            vec![Action::Read(1), Action::Send("PONG".into()), Action::Close],
+            vec![Action::Close],
+            vec![Action::Read(1), Action::Send("PONG".into())],
+        ];
+        let server = FakeServer::start(scripts).expect("Cannot start server");
+
+        // The second connection is closed as soon as it's made, so the third waits for the initial delay
+        let test_f = super::ConnectionBuilder::new(&server.addr())
+            .reconnect(super::Backoff::new(
+                Duration::from_millis(300),
+                Duration::from_secs(1),
+            ))
+            .paired_connect()
+            .and_then(|connection| {
+                connection
+                    .send::<String>(resp_array!["PING"])
+                    .and_then(|_| delay(150))
+                    .and_then(move |_| {
+                        connection
+                            .send::<String>(resp_array!["PING"])
+                            .then(Ok)
+                            .and_then(|early| delay(400).map(move |_| early))
+                            .and_then(move |early| {
+                                connection
+                                    .send::<String>(resp_array!["PING"])
+                                    .map(move |late| (early, late))
+                            })
+                    })
+            });
+        let (early, late) = run_and_wait(test_f).unwrap();
+        match early {
+            Err(error::Error::Connection(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(late, "PONG");
+        assert_eq!(server.received().len(), 3);
+    }
+
+    #[test]
+    fn reconnect_gives_up() {
+        // The `SELECT` sent while reconnecting will fail, as further connections are closed straight away
+        let script = vec![
+            Action::Read(1),
+            Action::Send(resp::RespValue::SimpleString("OK".into())),
+            Action::Close,
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+
+        let test_f = super::ConnectionBuilder::new(&server.addr())
+            .db(1)
+            .reconnect(
+                super::Backoff::new(Duration::from_millis(10), Duration::from_millis(10))
+                    .max_attempts(2),
+            )
+            .paired_connect()
+            .and_then(|connection| {
+                delay(200).and_then(move |_| {
+                    connection
+                        .send::<String>(resp_array!["PING"])
+                        .then(Ok)
+                })
+            });
+        match run_and_wait(test_f).unwrap() {
+            Err(error::Error::Connection(ref msg)) => assert_eq!(msg, "Connection is closed"),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn no_reconnect_by_default() {
+        let script = vec![Action::Read(1), Action::Close];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+
+        let test_f = super::paired_connect(&server.addr()).and_then(|connection| {
+            let get_f = connection
+                .send::<String>(resp_array!["GET", "X"])
+                .then(Ok);
+            get_f.join(delay(100)).and_then(move |(get, _)| {
+                connection
+                    .send::<String>(resp_array!["PING"])
+                    .then(move |ping| Ok((get, ping)))
+            })
+        });
+        let (get, ping) = run_and_wait(test_f).unwrap();
+        match (get, ping) {
+            (Err(error::Error::Connection(_)), Err(error::Error::Connection(_))) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn pubsub_resubscribe() {
+        use super::pubsub::PubsubItem;
+
+        let subscribed = resp_array!["subscribe", "test-topic", resp::RespValue::Integer(1)];
+        let scripts = vec![
+            vec![
+                Action::Read(1),
+                Action::Send(subscribed.clone()),
+                Action::Send(resp_array!["message", "test-topic", "before"]),
+                Action::Close,
+            ],
+            vec![
+                Action::Read(1),
+                Action::Send(subscribed),
+                Action::Send(resp_array!["message", "test-topic", "after"]),
+                Action::Read(1),
+                Action::Send(resp_array!["unsubscribe", "test-topic", resp::RespValue::Integer(0)]),
+            ],
+        ];
+        let server = FakeServer::start(scripts).expect("Cannot start server");
+
+        let test_f = super::ConnectionBuilder::new(&server.addr())
+            .reconnect(super::Backoff::default())
+            .pubsub_connect()
+            .and_then(|pubsub| pubsub.subscribe("test-topic"))
+            .and_then(|msgs| {
+                msgs.with_reconnect_notices().take(3).collect()
+            });
+        let result = run_and_wait(test_f).unwrap();
+        let message = |payload: &str| super::pubsub::PubsubMessage {
+            channel: "test-topic".into(),
+            pattern: None,
+            payload: payload.into(),
+        };
+        assert_eq!(
+            result,
+            vec![
+                PubsubItem::Message(message("before")),
+                PubsubItem::Reconnected,
+                PubsubItem::Message(message("after")),
+            ]
+        );
+        assert_eq!(
+            server.received()[1][0],
+            resp::Command::new("SUBSCRIBE", vec!["test-topic".into()])
+        );
+    }
+
+    #[test]
+    fn pubsub_shared_subscription() {
+        let script = vec![
+            Action::Read(1),
+            Action::Delay(Duration::from_millis(100)),
+            Action::Send(resp_array!["subscribe", "test-topic", resp::RespValue::Integer(1)]),
+            Action::Send(resp_array!["message", "test-topic", "one"]),
+            Action::Delay(Duration::from_millis(200)),
+            Action::Send(resp_array!["message", "test-topic", "two"]),
+            Action::Read(1),
+            Action::Send(resp_array!["unsubscribe", "test-topic", resp::RespValue::Integer(0)]),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+
+        let test_f = super::pubsub_connect(&server.addr())
+            .and_then(|pubsub| {
+                pubsub
+                    .subscribe("test-topic")
+                    .join(pubsub.subscribe("test-topic"))
+            })
+            .and_then(|(msgs1, msgs2)| {
+                msgs1
+                    .into_future()
+                    .join(msgs2.into_future())
+                    .map_err(|(e, _)| e)
+            })
+            .and_then(|((first1, msgs1), (first2, msgs2))| {
+                // The other stream is still subscribed after one is dropped
+                drop(msgs1);
+                msgs2
+                    .into_future()
+                    .map(move |(second2, _)| (first1, first2, second2))
+                    .map_err(|(e, _)| e)
+            });
+        let (first1, first2, second2) = run_and_wait(test_f).unwrap();
+        assert_eq!(first1.map(|msg| msg.payload), Some("one".into()));
+        assert_eq!(first2.map(|msg| msg.payload), Some("one".into()));
+        assert_eq!(second2.map(|msg| msg.payload), Some("two".into()));
+        assert_eq!(
+            server.received()[0],
+            vec![
+                resp::Command::new("SUBSCRIBE", vec!["test-topic".into()]),
+                resp::Command::new("UNSUBSCRIBE", vec!["test-topic".into()]),
+            ]
+        );
+    }
+
+    /// Subscribes with room for two messages in the buffer, and doesn't read anything until `script` has
+    /// published more.  Returns the first `count` results, the number of messages dropped, and the commands
+    /// received.
+    fn slow_consumer(
+        policy: super::SlowConsumerPolicy,
+        script: Vec<Action>,
+        count: u64,
+    ) -> (Vec<Result<String, error::Error>>, usize, Vec<resp::Command>) {
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+        let test_f = super::ConnectionBuilder::new(&server.addr())
+            .pubsub_buffer(2, policy)
+            .pubsub_connect()
+            .and_then(|pubsub| pubsub.subscribe("test-topic"))
+            .and_then(|msgs| delay(200).map(|_| msgs))
+            .and_then(move |msgs| {
+                let dropped = msgs.dropped();
+                msgs.then(|msg| Ok::<_, error::Error>(msg.and_then(|msg| msg.decode())))
+                    .take(count)
+                    .collect()
+                    .map(move |results| (results, dropped))
+            });
+        let (results, dropped) = run_and_wait(test_f).unwrap();
+        (results, dropped, server.received()[0].clone())
+    }
+
+    fn publish_four() -> Vec<Action> {
+        let mut script = vec![
+            Action::Read(1),
+            Action::Send(resp_array!["subscribe", "test-topic", resp::RespValue::Integer(1)]),
+        ];
+        for msg in &["1", "2", "3", "4"] {
+            script.push(Action::Send(resp_array!["message", "test-topic", *msg]));
+        }
+        script
+    }
+
+    fn payloads(results: Vec<Result<String, error::Error>>) -> Vec<String> {
+        results.into_iter().map(|result| result.unwrap()).collect()
+    }
+
+    #[test]
+    fn slow_consumer_drop_oldest() {
+        let (results, dropped, _) = slow_consumer(super::SlowConsumerPolicy::DropOldest, publish_four(), 2);
+        assert_eq!(payloads(results), vec!["3", "4"]);
+        assert_eq!(dropped, 2);
+    }
+
+    #[test]
+    fn slow_consumer_drop_newest() {
+        let (results, dropped, _) = slow_consumer(super::SlowConsumerPolicy::DropNewest, publish_four(), 2);
+        assert_eq!(payloads(results), vec!["1", "2"]);
+        assert_eq!(dropped, 2);
+    }
+
+    #[test]
+    fn slow_consumer_disconnect() {
+        let mut script = publish_four();
+        script.push(Action::Read(1));
+        script.push(Action::Send(resp_array!["unsubscribe", "test-topic", resp::RespValue::Integer(0)]));
+        let (mut results, dropped, received) =
+            slow_consumer(super::SlowConsumerPolicy::Disconnect, script, 4);
+        assert_eq!(results.len(), 3);
+        match results.pop() {
+            Some(Err(error::Error::Lagged(_))) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(payloads(results), vec!["1", "2"]);
+        assert_eq!(dropped, 1);
+        assert_eq!(
+            received[1],
+            resp::Command::new("UNSUBSCRIBE", vec!["test-topic".into()])
+        );
+    }
+
+    #[test]
+    fn slow_consumer_backpressure() {
+        let (results, dropped, _) = slow_consumer(super::SlowConsumerPolicy::Backpressure, publish_four(), 4);
+        assert_eq!(payloads(results), vec!["1", "2", "3", "4"]);
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn slow_consumer_subscribe_again() {
+        let mut script = publish_four();
+        script.push(Action::Read(1));
+        script.push(Action::Send(resp_array!["subscribe", "other-topic", resp::RespValue::Integer(2)]));
+        script.push(Action::Send(resp_array!["message", "other-topic", "other"]));
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+        // The first stream isn't read, its buffer being full mustn't stop the second subscription
+        let test_f = super::ConnectionBuilder::new(&server.addr())
+            .pubsub_buffer(2, super::SlowConsumerPolicy::DropOldest)
+            .pubsub_connect()
+            .and_then(|pubsub| {
+                pubsub
+                    .subscribe("test-topic")
+                    .and_then(|msgs| delay(200).map(|_| msgs))
+                    .and_then(move |msgs| pubsub.subscribe("other-topic").map(|other| (msgs, other)))
+            })
+            .and_then(|(msgs, other)| {
+                other
+                    .into_future()
+                    .map_err(|(e, _)| e)
+                    .map(move |(message, _)| (message, msgs.dropped()))
+            });
+        let (message, dropped) = run_and_wait(test_f).unwrap();
+        assert_eq!(message.map(|message| message.payload), Some("other".into()));
+        assert_eq!(dropped, 2);
+    }
+
+    #[test]
+    fn subscribe_many() {
+        let topics = vec!["a", "b", "c"];
+        let mut script = vec![Action::Read(1)];
+        for (idx, topic) in topics.iter().enumerate() {
+            let count = resp::RespValue::Integer(idx as i64 + 1);
+            script.push(Action::Send(resp_array!["subscribe", *topic, count]));
+        }
+        script.push(Action::Send(resp_array!["message", "b", "test-message"]));
+        script.push(Action::Read(1));
+        for (idx, topic) in topics.iter().enumerate() {
+            let count = resp::RespValue::Integer(topics.len() as i64 - idx as i64 - 1);
+            script.push(Action::Send(resp_array!["unsubscribe", *topic, count]));
+        }
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+
+        let test_f = super::pubsub_connect(&server.addr())
+            .and_then(move |pubsub| {
+                pubsub.subscribe_many(topics.clone()).and_then(move |streams| {
+                    super::pubsub::MergedStream::new(streams)
+                        .into_future()
+                        .map_err(|(e, _)| e)
+                        .and_then(move |(first, rest)| {
+                            pubsub
+                                .unsubscribe_many(topics)
+                                .join(rest.collect())
+                                .map(move |(_, rest)| (first, rest))
+                        })
+                })
+            })
+            .and_then(|result| delay(100).map(move |_| result));
+        let (first, rest) = run_and_wait(test_f).unwrap();
+        let first = first.expect("No message");
+        assert_eq!(first.channel, "b");
+        assert_eq!(first.payload, "test-message".into());
+        assert!(rest.is_empty());
+        let args = vec!["a".into(), "b".into(), "c".into()];
+        assert_eq!(
+            server.received()[0],
+            vec![
+                resp::Command::new("SUBSCRIBE", args.clone()),
+                resp::Command::new("UNSUBSCRIBE", args),
+            ]
+        );
+    }
+
+    #[test]
+    fn keyspace_notifications() {
+        use super::notifications::{EventClass, KeyEvent, Notification, Notifications};
+
+        let pattern = "__keyspace@0__:user:*";
+        let script = vec![
+            Action::Read(1),
+            Action::Send(resp_array!["psubscribe", pattern, resp::RespValue::Integer(1)]),
+            Action::Send(resp_array!["pmessage", pattern, "__keyspace@0__:user:1", "set"]),
+            Action::Send(resp_array!["pmessage", pattern, "__keyspace@0__:user:1", "lpush"]),
+            Action::Send(resp_array!["pmessage", pattern, "__keyspace@0__:user:__:2", "expired"]),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+        let notifications = Notifications::new()
+            .db(0)
+            .key_pattern("user:*")
+            .classes(vec![EventClass::String, EventClass::Expired]);
+        assert_eq!(notifications.config_value(), "K$x");
+        let test_f = super::pubsub_connect(&server.addr())
+            .and_then(move |pubsub| notifications.subscribe(&pubsub))
+            .and_then(|notifications| notifications.take(2).collect());
+        let result = run_and_wait(test_f).unwrap();
+        assert_eq!(
+            result,
+            vec![
+                Notification {
+                    db: 0,
+                    key: "user:1".into(),
+                    event: KeyEvent::Set,
+                },
+                Notification {
+                    db: 0,
+                    key: "user:__:2".into(),
+                    event: KeyEvent::Expired,
+                },
+            ]
+        );
+        assert_eq!(
+            server.received()[0][0],
+            resp::Command::new("PSUBSCRIBE", vec![pattern.into()])
+        );
+
+        let keyevent = super::pubsub::PubsubMessage {
+            channel: "__keyevent@3__:lpush".into(),
+            pattern: None,
+            payload: "queue".into(),
+        };
+        let notification = Notification::parse(&keyevent).unwrap();
+        assert_eq!(notification.db, 3);
+        assert_eq!(notification.key, "queue");
+        assert_eq!(notification.event, KeyEvent::Other("lpush".into()));
+        assert_eq!(notification.event.class(), Some(EventClass::List));
+    }
+
+    fn configure_notifications(
+        current: &str,
+        notifications: super::notifications::Notifications,
+    ) -> Vec<Vec<resp::Command>> {
+        let script = vec![
+            Action::Read(1),
+            Action::Send(resp_array!["notify-keyspace-events", current]),
+            Action::Read(1),
+            Action::Send(resp::RespValue::SimpleString("OK".into())),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+        let test_f = super::paired_connect(&server.addr()).and_then(move |paired| notifications.configure(&paired));
+        run_and_wait(test_f).unwrap();
+        server.received()
+    }
+
+    #[test]
+    fn keyspace_notifications_configure() {
+        let get = resp::Command::new("CONFIG", vec!["GET".into(), "notify-keyspace-events".into()]);
+        assert_eq!(
+            configure_notifications("", super::notifications::Notifications::new()),
+            vec![vec![
+                get.clone(),
+                resp::Command::new("CONFIG", vec!["SET".into(), "notify-keyspace-events".into(), "KA".into()]),
+            ]]
+        );
+
+        // Already configured, `A` covering the expired class
+        let expired = super::notifications::Notifications::new()
+            .classes(vec![super::notifications::EventClass::Expired]);
+        assert_eq!(configure_notifications("AKE", expired), vec![vec![get.clone()]]);
+
+        // Flags needed by other clients are kept
+        let list = super::notifications::Notifications::new()
+            .classes(vec![super::notifications::EventClass::List, super::notifications::EventClass::Expired]);
+        assert_eq!(
+            configure_notifications("xE", list),
+            vec![vec![
+                get,
+                resp::Command::new("CONFIG", vec!["SET".into(), "notify-keyspace-events".into(), "xEKl".into()]),
+            ]]
+        );
+    }
+
+    #[test]
+    fn fault_error_reply() {
+        let script = vec![
+            Action::Read(1),
+            Action::Send(resp::RespValue::Error("ERR broken".into())),
+        ];
+        let results = send_with_script(script, vec![resp_array!["GET", "X"]]);
+        match results[0] {
+            Err(error::Error::Remote(ref msg)) => assert_eq!(msg, "ERR broken"),
+            ref x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn fault_late_replies() {
+        let script = vec![
+            Action::Read(2),
+            Action::Delay(Duration::from_millis(100)),
+            Action::Send("FIRST".into()),
+            Action::Delay(Duration::from_millis(100)),
+            Action::Send("SECOND".into()),
+        ];
+        let results = send_with_script(
+            script,
+            vec![resp_array!["GET", "A"], resp_array!["GET", "B"]],
+        );
+        assert_eq!(results[0].as_ref().unwrap(), &"FIRST".into());
+        assert_eq!(results[1].as_ref().unwrap(), &"SECOND".into());
+    }
+
+    #[test]
+    fn fault_close_mid_frame() {
+        let script = vec![
+            Action::Read(1),
+            Action::Raw(b"$10\r\nabc".to_vec()),
+            Action::Close,
+        ];
+        let results = send_with_script(script, vec![resp_array!["GET", "X"]]);
+        assert!(results[0].is_err());
+    }
+
+    #[test]
+    fn fault_garbage() {
+        let script = vec![Action::Read(1), Action::Raw(b"?garbage\r\n".to_vec())];
+        let results = send_with_script(script, vec![resp_array!["GET", "X"]]);
+        assert!(results[0].is_err());
+    }
+
+    #[test]
+    fn fault_unexpected_reply() {
+        let script = vec![
+            Action::Send("UNEXPECTED".into()),
+            Action::Read(1),
+            Action::Send("PONG".into()),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+        let test_f = super::paired_connect(&server.addr()).and_then(|connection| {
+            // Give the connection time to receive the unexpected message
+            Delay::new(Instant::now() + Duration::from_millis(100))
+                .then(move |_| connection.send::<String>(resp_array!["PING"]).then(Ok))
+        });
+        let result: Result<String, error::Error> = run_and_wait(test_f).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn fault_backpressure() {
+        let script = vec![
+            Action::Delay(Duration::from_millis(200)),
+            Action::Read(2),
+            Action::Send(resp::RespValue::SimpleString("OK".into())),
+            Action::Send("PONG".into()),
+        ];
+        let big_value = vec![b'x'; 8 * 1024 * 1024];
+        let results = send_with_script(
+            script,
+            vec![resp_array!["SET", "X", big_value], resp_array!["PING"]],
+        );
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap(), &"PONG".into());
+    }
+
+    #[test]
+    fn fault_pubsub_garbage() {
+        let script = vec![
+            Action::Read(1),
+            Action::Send(resp_array!["subscribe", "test-topic", resp::RespValue::Integer(1)]),
+            Action::Send(resp_array!["message", "test-topic", "test-message"]),
+            Action::Send(resp_array!["message", "test-topic"]),
+        ];
+        let server = FakeServer::start(vec![script]).expect("Cannot start server");
+        let test_f = super::pubsub_connect(&server.addr())
+            .and_then(|pubsub| pubsub.subscribe("test-topic"))
+            .and_then(|msgs| msgs.then(Ok::<_, error::Error>).collect());
+        let result = run_and_wait(test_f).unwrap();
         assert_eq!(result.len(), 2);
-        assert_eq!(result[0], "test-message".into());
-        assert_eq!(result[1], "test-message2".into());
+        assert_eq!(result[0].as_ref().unwrap().payload, "test-message".into());
+        match result[1] {
+            Err(error::Error::Connection(_)) => (),
+            ref x => panic!("Unexpected result: {:?}", x),
+        }
     }
 }
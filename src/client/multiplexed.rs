@@ -0,0 +1,136 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Spreading commands across several connections to the same server.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+use futures::Future;
+
+use resp;
+use super::paired::{PairedConnection, SendBox};
+
+/// How a `MultiplexedClient` chooses the connection for each command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distribution {
+    /// Each connection in turn.  Commands sent on different connections may be run in a different order to
+    /// the one they were sent in.
+    RoundRobin,
+
+    /// The connection with the fewest commands waiting for a reply, the first if there's a tie.  As with
+    /// `RoundRobin`, commands may be run out of order.
+    LeastInFlight,
+
+    /// Chosen by a hash of the command's first argument, which is the key for most commands, so commands for
+    /// the same key are always sent on the same connection and run in the order they were sent.  Commands
+    /// with no arguments are sent round-robin.
+    ByKey,
+}
+
+struct Member {
+    connection: PairedConnection,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Decrements the number of commands in flight on a connection when dropped, i.e. once the reply has been
+/// received, or the command's future dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Several `PairedConnection`s to the same server, each with its own socket and task, used as one.  This
+/// allows more throughput than one connection, which is limited to one thread at a time.
+///
+/// Made with `ConnectionBuilder::multiplexed_connect`, or `new` from existing connections.  Clones share the
+/// same connections.
+#[derive(Clone)]
+pub struct MultiplexedClient {
+    members: Arc<Vec<Member>>,
+    distribution: Distribution,
+    next: Arc<AtomicUsize>,
+}
+
+impl MultiplexedClient {
+    /// Spreads commands across `connections`, which should all be to the same server, and the same database.
+    ///
+    /// # Panics
+    ///
+    /// If there are no connections.
+    pub fn new(connections: Vec<PairedConnection>, distribution: Distribution) -> Self {
+        assert!(!connections.is_empty(), "At least one connection is needed");
+        let members = connections
+            .into_iter()
+            .map(|connection| Member {
+                connection: connection,
+                in_flight: Arc::new(AtomicUsize::new(0)),
+            })
+            .collect();
+        MultiplexedClient {
+            members: Arc::new(members),
+            distribution: distribution,
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Sends a command to Redis, on the connection chosen by the `Distribution`.  Otherwise the same as
+    /// `PairedConnection::send`.
+    pub fn send<T: resp::FromResp + Send + 'static>(&self, msg: resp::RespValue) -> SendBox<T> {
+        let member = &self.members[self.choose(&msg)];
+        member.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight(member.in_flight.clone());
+        Box::new(member.connection.send(msg).then(move |result| {
+            drop(in_flight);
+            result
+        }))
+    }
+
+    /// The index of the connection to send `msg` on.
+    fn choose(&self, msg: &resp::RespValue) -> usize {
+        match self.distribution {
+            Distribution::RoundRobin => self.round_robin(),
+            Distribution::LeastInFlight => self.members
+                .iter()
+                .enumerate()
+                .min_by_key(|&(_, member)| member.in_flight.load(Ordering::SeqCst))
+                .map(|(idx, _)| idx)
+                .expect("No connections"),
+            Distribution::ByKey => match key_hash(msg) {
+                Some(hash) => (hash % self.members.len() as u64) as usize,
+                None => self.round_robin(),
+            },
+        }
+    }
+
+    fn round_robin(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % self.members.len()
+    }
+}
+
+/// A hash of the first argument of a command, if it has one.
+fn key_hash(msg: &resp::RespValue) -> Option<u64> {
+    let key = match *msg {
+        resp::RespValue::Array(ref args) => args.get(1)?,
+        _ => return None,
+    };
+    let mut hasher = DefaultHasher::new();
+    match *key {
+        resp::RespValue::BulkString(ref bytes) => bytes.hash(&mut hasher),
+        resp::RespValue::SimpleString(ref string) => string.as_bytes().hash(&mut hasher),
+        resp::RespValue::Integer(i) => i.to_string().as_bytes().hash(&mut hasher),
+        _ => return None,
+    }
+    Some(hasher.finish())
+}
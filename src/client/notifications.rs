@@ -0,0 +1,385 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Keyspace notifications, which Redis publishes when keys change if `notify-keyspace-events` is set.  See
+//! https://redis.io/docs/manual/keyspace-notifications/ for the events each command generates.
+
+use std::collections::HashMap;
+
+use futures::{future, Async, Future, Poll, Stream};
+
+use error;
+use super::paired::{PairedConnection, SendBox};
+use super::pubsub::{PubsubConnection, PubsubMessage, PubsubStream};
+
+const KEYSPACE_PREFIX: &str = "__keyspace@";
+const KEYEVENT_PREFIX: &str = "__keyevent@";
+const NOTIFY_KEYSPACE_EVENTS: &str = "notify-keyspace-events";
+
+/// The classes the `A` flag of `notify-keyspace-events` stands for.
+const ALL_FLAGS: &str = "g$lshzxetd";
+
+/// The flags of a value of `notify-keyspace-events`, with `A` expanded.
+fn expand_flags(value: &str) -> Vec<char> {
+    value
+        .chars()
+        .flat_map(|flag| match flag {
+            'A' => ALL_FLAGS.chars().collect(),
+            flag => vec![flag],
+        })
+        .collect()
+}
+
+/// The value of `notify-keyspace-events` that adds the flags of `required` to `current`, or `None` if they are
+/// all there already.
+fn merge_flags(current: &str, required: &str) -> Option<String> {
+    let present = expand_flags(current);
+    let missing = required
+        .chars()
+        .filter(|flag| !expand_flags(&flag.to_string()).iter().all(|flag| present.contains(flag)))
+        .collect::<String>();
+    if missing.is_empty() {
+        None
+    } else {
+        Some(format!("{}{}", current, missing))
+    }
+}
+
+/// A class of events, as enabled by each flag of `notify-keyspace-events`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventClass {
+    /// Commands that apply to any type of key, e.g. `DEL`, `EXPIRE` and `RENAME`.
+    Generic,
+    String,
+    List,
+    Set,
+    Hash,
+    SortedSet,
+    Stream,
+
+    /// A key expired.
+    Expired,
+
+    /// A key was evicted, because of `maxmemory`.
+    Evicted,
+
+    /// A key that doesn't exist was read.
+    KeyMiss,
+
+    /// A key was created.
+    New,
+}
+
+impl EventClass {
+    /// The flag that enables this class in `notify-keyspace-events`.
+    fn flag(&self) -> char {
+        match *self {
+            EventClass::Generic => 'g',
+            EventClass::String => '$',
+            EventClass::List => 'l',
+            EventClass::Set => 's',
+            EventClass::Hash => 'h',
+            EventClass::SortedSet => 'z',
+            EventClass::Stream => 't',
+            EventClass::Expired => 'x',
+            EventClass::Evicted => 'e',
+            EventClass::KeyMiss => 'm',
+            EventClass::New => 'n',
+        }
+    }
+
+    /// The class of an event, `None` for those Redis doesn't document, e.g. events generated by modules.
+    fn of(event: &str) -> Option<EventClass> {
+        let class = match event {
+            "del" | "expire" | "persist" | "rename_from" | "rename_to" | "move_from" | "move_to" | "copy_to"
+            | "restore" => EventClass::Generic,
+            "set" | "setrange" | "incrby" | "incrbyfloat" | "append" => EventClass::String,
+            "lpush" | "rpush" | "lpop" | "rpop" | "linsert" | "lset" | "lrem" | "ltrim" | "sortstore" => {
+                EventClass::List
+            }
+            "sadd" | "srem" | "spop" | "sinterstore" | "sunionstore" | "sdiffstore" => EventClass::Set,
+            "hset" | "hincrby" | "hincrbyfloat" | "hdel" | "hexpire" | "hpersist" | "hexpired" => EventClass::Hash,
+            "zadd" | "zincr" | "zrem" | "zremrangebyscore" | "zremrangebyrank" | "zremrangebylex"
+            | "zinterstore" | "zunionstore" | "zdiffstore" | "zrangestore" | "zpopmin" | "zpopmax" => {
+                EventClass::SortedSet
+            }
+            "xadd" | "xtrim" | "xdel" | "xsetid" | "xgroup-create" | "xgroup-createconsumer"
+            | "xgroup-delconsumer" | "xgroup-destroy" | "xgroup-setid" => EventClass::Stream,
+            "expired" => EventClass::Expired,
+            "evicted" => EventClass::Evicted,
+            "keymiss" => EventClass::KeyMiss,
+            "new" => EventClass::New,
+            _ => return None,
+        };
+        Some(class)
+    }
+}
+
+/// What happened to a key.  The most common events have their own variant, the rest are `Other`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyEvent {
+    Set,
+    Del,
+    Expire,
+    Persist,
+    RenameFrom,
+    RenameTo,
+    Expired,
+    Evicted,
+    New,
+    KeyMiss,
+
+    /// Any other event, by the name Redis gives it, e.g. `lpush`.
+    Other(String),
+}
+
+impl KeyEvent {
+    fn from_name(name: String) -> KeyEvent {
+        match name.as_str() {
+            "set" => KeyEvent::Set,
+            "del" => KeyEvent::Del,
+            "expire" => KeyEvent::Expire,
+            "persist" => KeyEvent::Persist,
+            "rename_from" => KeyEvent::RenameFrom,
+            "rename_to" => KeyEvent::RenameTo,
+            "expired" => KeyEvent::Expired,
+            "evicted" => KeyEvent::Evicted,
+            "new" => KeyEvent::New,
+            "keymiss" => KeyEvent::KeyMiss,
+            _ => KeyEvent::Other(name),
+        }
+    }
+
+    /// The name Redis gives the event.
+    pub fn name(&self) -> &str {
+        match *self {
+            KeyEvent::Set => "set",
+            KeyEvent::Del => "del",
+            KeyEvent::Expire => "expire",
+            KeyEvent::Persist => "persist",
+            KeyEvent::RenameFrom => "rename_from",
+            KeyEvent::RenameTo => "rename_to",
+            KeyEvent::Expired => "expired",
+            KeyEvent::Evicted => "evicted",
+            KeyEvent::New => "new",
+            KeyEvent::KeyMiss => "keymiss",
+            KeyEvent::Other(ref name) => name,
+        }
+    }
+
+    /// The class of the event, if known.
+    pub fn class(&self) -> Option<EventClass> {
+        EventClass::of(self.name())
+    }
+}
+
+/// An event that happened to a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    /// The database the key is in.
+    pub db: u32,
+    pub key: String,
+    pub event: KeyEvent,
+}
+
+impl Notification {
+    /// Parses a message published to a keyspace channel (`__keyspace@<db>__:<key>`, the payload is the event),
+    /// or a keyevent channel (`__keyevent@<db>__:<event>`, the payload is the key).
+    pub fn parse(message: &PubsubMessage) -> Result<Notification, error::Error> {
+        let not_notification = || {
+            error::resp(
+                "Not a keyspace or keyevent notification",
+                message.channel.as_str().into(),
+            )
+        };
+        let (keyspace, rest) = if message.channel.starts_with(KEYSPACE_PREFIX) {
+            (true, &message.channel[KEYSPACE_PREFIX.len()..])
+        } else if message.channel.starts_with(KEYEVENT_PREFIX) {
+            (false, &message.channel[KEYEVENT_PREFIX.len()..])
+        } else {
+            return Err(not_notification());
+        };
+        // The database is a number, so the first separator found is the one that follows it
+        let separator = rest.find("__:").ok_or_else(&not_notification)?;
+        let db = rest[..separator].parse().map_err(|_| not_notification())?;
+        let name = rest[separator + 3..].to_string();
+        let payload: String = message.decode()?;
+        let (key, event) = if keyspace {
+            (name, payload)
+        } else {
+            (payload, name)
+        };
+        Ok(Notification {
+            db: db,
+            key: key,
+            event: KeyEvent::from_name(event),
+        })
+    }
+}
+
+/// Which notifications to receive, subscribing to the keyspace channels that match.
+///
+/// ```rust,no_run
+/// # extern crate futures;
+/// # extern crate redis_async;
+/// # use futures::Future;
+/// # use redis_async::client::{self, notifications::{EventClass, Notifications}};
+/// # fn main() {
+/// let addr = "127.0.0.1:6379".parse().unwrap();
+/// let notifications = Notifications::new()
+///     .db(0)
+///     .key_pattern("user:*")
+///     .classes(vec![EventClass::Generic, EventClass::Expired]);
+/// let stream_f = client::pubsub_connect(&addr).and_then(move |pubsub| notifications.subscribe(&pubsub));
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Notifications {
+    db: Option<u32>,
+    key_pattern: String,
+    classes: Option<Vec<EventClass>>,
+}
+
+impl Notifications {
+    /// Every event, for every key in every database.
+    pub fn new() -> Self {
+        Notifications {
+            db: None,
+            key_pattern: "*".into(),
+            classes: None,
+        }
+    }
+
+    /// Only events in this database.
+    pub fn db(mut self, db: u32) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Only events for keys matching a glob-style pattern, e.g. `user:*`.
+    pub fn key_pattern<T: Into<String>>(mut self, key_pattern: T) -> Self {
+        self.key_pattern = key_pattern.into();
+        self
+    }
+
+    /// Only events in these classes.  Redis publishes each event to every subscriber, so the others are
+    /// discarded by the client, unless they are also left out of `notify-keyspace-events`, see `configure`.
+    pub fn classes<I: IntoIterator<Item = EventClass>>(mut self, classes: I) -> Self {
+        self.classes = Some(classes.into_iter().collect());
+        self
+    }
+
+    /// The value of `notify-keyspace-events` that enables keyspace notifications for the chosen classes.
+    pub fn config_value(&self) -> String {
+        let mut value = "K".to_string();
+        match self.classes {
+            Some(ref classes) => value.extend(classes.iter().map(EventClass::flag)),
+            None => value.push('A'),
+        }
+        value
+    }
+
+    /// Enables the notifications, if the server isn't already configured for them.  The current value of
+    /// `notify-keyspace-events` is read with `CONFIG GET`, and any missing flags are added to it with `CONFIG SET`,
+    /// so notifications enabled for other clients are kept.
+    pub fn configure(&self, connection: &PairedConnection) -> SendBox<()> {
+        let required = self.config_value();
+        let set_connection = connection.clone();
+        Box::new(
+            connection
+                .send::<HashMap<String, String>>(resp_array!["CONFIG", "GET", NOTIFY_KEYSPACE_EVENTS])
+                .and_then(move |mut config| -> SendBox<()> {
+                    let current = config.remove(NOTIFY_KEYSPACE_EVENTS).unwrap_or_default();
+                    match merge_flags(&current, &required) {
+                        Some(value) => {
+                            set_connection.send(resp_array!["CONFIG", "SET", NOTIFY_KEYSPACE_EVENTS, value])
+                        }
+                        None => Box::new(future::ok(())),
+                    }
+                }),
+        )
+    }
+
+    /// The pattern subscribed to.
+    fn channel_pattern(&self) -> String {
+        let db = match self.db {
+            Some(db) => db.to_string(),
+            None => "*".into(),
+        };
+        format!("{}{}__:{}", KEYSPACE_PREFIX, db, self.key_pattern)
+    }
+
+    /// Subscribes to the notifications with `PSUBSCRIBE`, returning a future that resolves to a stream of them
+    /// once the subscription is confirmed.  As with `PubsubConnection::psubscribe`, the stream ends once
+    /// unsubscribed from, or dropped.
+    pub fn subscribe(
+        &self,
+        connection: &PubsubConnection,
+    ) -> Box<Future<Item = NotificationStream, Error = error::Error> + Send> {
+        let classes = self.classes.clone();
+        Box::new(
+            connection
+                .psubscribe(self.channel_pattern())
+                .map(|messages| NotificationStream {
+                    messages: messages,
+                    classes: classes,
+                }),
+        )
+    }
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Notifications::new()
+    }
+}
+
+/// Keyspace notifications, see `Notifications::subscribe`.
+pub struct NotificationStream {
+    messages: PubsubStream,
+    classes: Option<Vec<EventClass>>,
+}
+
+impl NotificationStream {
+    /// The number of messages discarded because this stream wasn't keeping up, see `PubsubStream::dropped`.
+    pub fn dropped(&self) -> usize {
+        self.messages.dropped()
+    }
+
+    fn wanted(&self, notification: &Notification) -> bool {
+        match self.classes {
+            Some(ref classes) => match notification.event.class() {
+                Some(class) => classes.contains(&class),
+                None => false,
+            },
+            None => true,
+        }
+    }
+}
+
+impl Stream for NotificationStream {
+    type Item = Notification;
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.messages.poll()? {
+                Async::Ready(Some(message)) => {
+                    let notification = Notification::parse(&message)?;
+                    if self.wanted(&notification) {
+                        return Ok(Async::Ready(Some(notification)));
+                    }
+                }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
@@ -12,16 +12,32 @@ use std::collections::VecDeque;
 use std::mem;
 use std::net::SocketAddr;
 
-use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream, sync::{mpsc, oneshot}};
+use futures::{future::{self, Loop}, Async, AsyncSink, Future, IntoFuture, Poll, Sink, Stream,
+              sync::{mpsc, oneshot}};
 
 use tokio_executor::{DefaultExecutor, Executor};
 
 use error;
 use resp;
-use super::connect::{connect, RespConnection};
+use super::builder::ConnectionBuilder;
+use super::connect::RespConnection;
+use super::pipeline::Pipeline;
+use super::reconnect::Reconnect;
+use super::transaction::Transaction;
 
 type PairedConnectionBox = Box<Future<Item = PairedConnection, Error = error::Error> + Send>;
 
+type Response = Result<resp::RespValue, error::Error>;
+
+enum Request {
+    /// Commands to send, each with a sender for its reply.  Commands sent together are written one after
+    /// another, with no commands from other handles in between.
+    Commands(Vec<(resp::RespValue, oneshot::Sender<Response>)>),
+
+    /// Requests from a leased handle, which are the only ones sent until every clone of it is dropped.
+    Lease(mpsc::UnboundedReceiver<Request>),
+}
+
 enum SendStatus {
     Ok,
     End,
@@ -46,9 +62,16 @@ enum ReceiveStatus {
 }
 
 struct PairedConnectionInner {
-    connection: RespConnection,
-    out_rx: mpsc::UnboundedReceiver<(resp::RespValue, oneshot::Sender<resp::RespValue>)>,
-    waiting: VecDeque<oneshot::Sender<resp::RespValue>>,
+    /// `None` while reconnecting.
+    connection: Option<RespConnection>,
+    reconnect: Option<Reconnect>,
+
+    out_rx: mpsc::UnboundedReceiver<Request>,
+    /// Leases in effect, requests are only taken from the last one, which may itself have been leased.
+    leases: Vec<mpsc::UnboundedReceiver<Request>>,
+    /// The rest of the commands sent together with the last one taken from `out_rx`.
+    queued: VecDeque<resp::RespValue>,
+    waiting: VecDeque<oneshot::Sender<Response>>,
 
     send_status: SendStatus,
     flush_status: FlushStatus,
@@ -57,22 +80,29 @@ struct PairedConnectionInner {
 impl PairedConnectionInner {
     fn new(
         con: RespConnection,
-        out_rx: mpsc::UnboundedReceiver<(resp::RespValue, oneshot::Sender<resp::RespValue>)>,
+        out_rx: mpsc::UnboundedReceiver<Request>,
+        reconnect: Option<Reconnect>,
     ) -> Self {
         PairedConnectionInner {
-            connection: con,
+            connection: Some(con),
+            reconnect: reconnect,
             out_rx: out_rx,
+            leases: Vec::new(),
+            queued: VecDeque::new(),
             waiting: VecDeque::new(),
             send_status: SendStatus::Ok,
             flush_status: FlushStatus::Ok,
         }
     }
 
-    fn impl_start_send(&mut self, msg: resp::RespValue) -> Result<bool, ()> {
-        match self.connection
-            .start_send(msg)
-            .map_err(|e| error!("Error sending message to connection: {}", e))?
-        {
+    fn connection(&mut self) -> Result<&mut RespConnection, error::Error> {
+        self.connection
+            .as_mut()
+            .ok_or_else(|| error::Error::Connection("Not connected".into()))
+    }
+
+    fn impl_start_send(&mut self, msg: resp::RespValue) -> Result<bool, error::Error> {
+        match self.connection()?.start_send(msg)? {
             AsyncSink::Ready => {
                 self.send_status = SendStatus::Ok;
                 self.flush_status = FlushStatus::Required;
@@ -86,44 +116,64 @@ impl PairedConnectionInner {
         }
     }
 
-    fn poll_start_send(&mut self) -> Result<bool, ()> {
-        let message = match self.send_status {
-            SendStatus::End | SendStatus::Full(_, false) => return Ok(false),
-            SendStatus::Full(ref mut msg_rf, true) => unsafe {
-                mem::replace(msg_rf, mem::uninitialized())
-            },
-            SendStatus::Ok => match self.out_rx
-                .poll()
-                .map_err(|_| error!("Error polling for messages to send"))?
-            {
-                Async::Ready(Some((msg, tx))) => {
-                    self.waiting.push_back(tx);
-                    msg
-                }
-                Async::Ready(None) => {
-                    self.send_status = SendStatus::End;
-                    return Ok(false);
-                }
-                Async::NotReady => return Ok(false),
+    fn poll_start_send(&mut self) -> Result<bool, error::Error> {
+        let message = match mem::replace(&mut self.send_status, SendStatus::Ok) {
+            status @ SendStatus::End | status @ SendStatus::Full(_, false) => {
+                self.send_status = status;
+                return Ok(false);
+            }
+            SendStatus::Full(msg, true) => msg,
+            SendStatus::Ok => match self.queued.pop_front() {
+                Some(msg) => msg,
+                None => return self.poll_request(),
             },
         };
 
         self.impl_start_send(message)
     }
 
-    fn poll_complete(&mut self) -> Result<(), ()> {
+    /// Takes the next request, from the current lease if there is one.  Returns true if there may be more
+    /// to send.
+    fn poll_request(&mut self) -> Result<bool, error::Error> {
+        let polled = match self.leases.last_mut() {
+            Some(lease) => lease.poll(),
+            None => self.out_rx.poll(),
+        };
+        match polled.map_err(|_| error::internal("Error polling for messages to send"))? {
+            Async::Ready(Some(Request::Commands(commands))) => {
+                for (msg, tx) in commands {
+                    self.queued.push_back(msg);
+                    self.waiting.push_back(tx);
+                }
+                // Sent from `queued` on the next pass, which also skips empty requests
+                Ok(true)
+            }
+            Async::Ready(Some(Request::Lease(lease))) => {
+                self.leases.push(lease);
+                Ok(true)
+            }
+            Async::Ready(None) => {
+                if self.leases.pop().is_some() {
+                    // The lease has ended, carry on with the requests that were waiting for it
+                    return Ok(true);
+                }
+                self.send_status = SendStatus::End;
+                Ok(false)
+            }
+            Async::NotReady => Ok(false),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Result<(), error::Error> {
         match self.flush_status {
             FlushStatus::Ok => (),
             FlushStatus::Required => {
-                match self.connection
-                    .poll_complete()
-                    .map_err(|e| error!("Error polling for completeness: {}", e))?
-                {
+                match self.connection()?.poll_complete()? {
                     Async::Ready(()) => self.flush_status = FlushStatus::Ok,
                     Async::NotReady => (),
                 }
                 if let SendStatus::Full(_, ref mut post) = self.send_status {
-                    if *post == false {
+                    if !*post {
                         *post = true;
                     }
                 }
@@ -132,17 +182,20 @@ impl PairedConnectionInner {
         Ok(())
     }
 
-    fn receive(&mut self) -> Result<ReceiveStatus, ()> {
-        match self.connection
-            .poll()
-            .map_err(|e| error!("Error polling to receive messages: {}", e))?
-        {
-            Async::Ready(None) => Ok(ReceiveStatus::ReadyFinished),
+    fn receive(&mut self) -> Result<ReceiveStatus, error::Error> {
+        match self.connection()?.poll()? {
+            Async::Ready(None) => Err(error::Error::EndOfStream),
             Async::Ready(Some(msg)) => {
-                let tx = self.waiting
-                    .pop_front()
-                    .expect(&format!("Received unexpected message: {:?}", msg));
-                let _ = tx.send(msg);
+                let tx = match self.waiting.pop_front() {
+                    Some(tx) => tx,
+                    None => {
+                        return Err(error::internal(format!(
+                            "Received unexpected message: {:?}",
+                            msg
+                        )))
+                    }
+                };
+                let _ = tx.send(Ok(msg));
                 if let SendStatus::End = self.send_status {
                     if self.waiting.is_empty() {
                         return Ok(ReceiveStatus::ReadyFinished);
@@ -153,13 +206,8 @@ impl PairedConnectionInner {
             Async::NotReady => Ok(ReceiveStatus::NotReady),
         }
     }
-}
-
-impl Future for PairedConnectionInner {
-    type Item = ();
-    type Error = ();
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    fn poll_connected(&mut self) -> Poll<(), error::Error> {
         // If there's something to send, send it...
         let mut sending = true;
         while sending {
@@ -168,6 +216,13 @@ impl Future for PairedConnectionInner {
 
         self.poll_complete()?;
 
+        // If every handle has been dropped, and there's nothing left to receive, the work is done
+        if let SendStatus::End = self.send_status {
+            if self.waiting.is_empty() {
+                return Ok(Async::Ready(()));
+            }
+        }
+
         // If there's something to receive, receive it...
         let mut receiving = true;
         while receiving {
@@ -180,29 +235,103 @@ impl Future for PairedConnectionInner {
 
         Ok(Async::NotReady)
     }
+
+    /// Fails any commands sent while there is no connection, returns `Ready` once every handle has been
+    /// dropped.
+    fn reject_requests(&mut self) -> Poll<(), ()> {
+        if let SendStatus::End = self.send_status {
+            return Ok(Async::Ready(()));
+        }
+        loop {
+            match self.out_rx.poll()? {
+                Async::Ready(Some(Request::Commands(commands))) => for (_, tx) in commands {
+                    let _ = tx.send(Err(error::Error::Connection(
+                        "Not connected to Redis, reconnecting".into(),
+                    )));
+                },
+                // Dropping the lease closes it, so anything sent to it fails
+                Async::Ready(Some(Request::Lease(_))) => (),
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+
+    /// Called when the connection is lost, returns true if it is to be re-established.
+    fn disconnected(&mut self, e: error::Error) -> bool {
+        error!("Connection to Redis lost: {}", e);
+        self.connection = None;
+        let message = format!("Connection lost: {}", e);
+        self.queued.clear();
+        // A lease doesn't carry over to a new connection, as Redis won't know about anything done with it, e.g.
+        // keys that were watched
+        self.leases.clear();
+        for tx in self.waiting.drain(..) {
+            let _ = tx.send(Err(error::Error::Connection(message.clone())));
+        }
+        self.flush_status = FlushStatus::Ok;
+        match self.send_status {
+            SendStatus::End => return false,
+            _ => self.send_status = SendStatus::Ok,
+        }
+        self.reconnect.is_some()
+    }
+}
+
+impl Future for PairedConnectionInner {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if self.connection.is_some() {
+                match self.poll_connected() {
+                    Ok(poll) => return Ok(poll),
+                    Err(e) => if !self.disconnected(e) {
+                        return Ok(Async::Ready(()));
+                    },
+                }
+            }
+
+            let reconnected = match self.reconnect {
+                Some(ref mut reconnect) => reconnect.poll(),
+                None => return Ok(Async::Ready(())),
+            };
+            match reconnected {
+                Ok(Async::Ready(connection)) => self.connection = Some(connection),
+                Ok(Async::NotReady) => return self.reject_requests(),
+                // Giving up, the connection is closed for good
+                Err(()) => return Ok(Async::Ready(())),
+            }
+        }
+    }
 }
 
 /// A shareable and cheaply cloneable connection to which Redis commands can be sent
 #[derive(Clone)]
 pub struct PairedConnection {
-    out_tx: mpsc::UnboundedSender<(resp::RespValue, oneshot::Sender<resp::RespValue>)>,
+    out_tx: mpsc::UnboundedSender<Request>,
 }
 
 /// The default starting point to use most default Redis functionality.
 ///
 /// Returns a future that resolves to a `PairedConnection`.
+///
+/// To authenticate, or use a database other than the default, see `ConnectionBuilder`.
 pub fn paired_connect(addr: &SocketAddr) -> PairedConnectionBox {
-    let pc_f = connect(addr).map_err(|e| e.into()).map(|connection| {
-        let (out_tx, out_rx) = mpsc::unbounded();
-        let paired_connection_inner = Box::new(PairedConnectionInner::new(connection, out_rx));
-        let mut executor = DefaultExecutor::current();
-        executor
-            .spawn(paired_connection_inner)
-            .expect("Cannot spawn paired connection");
-        PairedConnection { out_tx }
-    });
+    ConnectionBuilder::new(addr).paired_connect()
+}
 
-    Box::new(pc_f)
+/// Spawns the task that owns `connection`, returning a handle to send commands to it.  If `reconnect` is set
+/// it is used to re-establish the connection if it's lost.
+pub(crate) fn spawn(connection: RespConnection, reconnect: Option<Reconnect>) -> PairedConnection {
+    let (out_tx, out_rx) = mpsc::unbounded();
+    let paired_connection_inner = Box::new(PairedConnectionInner::new(connection, out_rx, reconnect));
+    let mut executor = DefaultExecutor::current();
+    executor
+        .spawn(paired_connection_inner)
+        .expect("Cannot spawn paired connection");
+    PairedConnection { out_tx }
 }
 
 pub type SendBox<T> = Box<Future<Item = T, Error = error::Error> + Send>;
@@ -227,7 +356,8 @@ impl PairedConnection {
     /// returned from Redis.  The type must be one for which the `resp::FromResp` trait is defined.
     ///
     /// The future will fail for numerous reasons, including but not limited to: IO issues, conversion
-    /// problems, and server-side errors being returned by Redis.
+    /// problems, and server-side errors being returned by Redis.  If the connection is lost, or is being
+    /// re-established (see `ConnectionBuilder::reconnect`), it fails with `error::Error::Connection`.
     ///
     /// Behind the scenes the message is queued up and sent to Redis asynchronously before the
     /// future is realised.  As such, it is guaranteed that messages are sent in the same order
@@ -243,14 +373,163 @@ impl PairedConnection {
         }
 
         let (tx, rx) = oneshot::channel();
-        self.out_tx
-            .unbounded_send((msg, tx))
-            .expect("Cannot send message!");
+        if self.out_tx
+            .unbounded_send(Request::Commands(vec![(msg, tx)]))
+            .is_err()
+        {
+            return Box::new(future::err(error::Error::Connection(
+                "Connection is closed".into(),
+            )));
+        }
 
         let future = rx.then(|v| match v {
-            Ok(v) => future::result(T::from_resp(v)),
+            Ok(Ok(v)) => future::result(T::from_resp(v)),
+            Ok(Err(e)) => future::err(e),
             Err(e) => future::err(e.into()),
         });
         Box::new(future)
     }
+
+    /// Starts a transaction, the commands added to it are sent to Redis with `MULTI` and `EXEC` once
+    /// `Transaction::exec` is called.
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.clone())
+    }
+
+    /// Starts a pipeline, the commands added to it are sent together once `Pipeline::send` is called, and their
+    /// results returned together.
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline::new(self.clone())
+    }
+
+    /// Leases the connection, for commands that rely on it not being used by anyone else in the meantime, e.g.
+    /// `WATCH`.
+    ///
+    /// Returns a handle to the same connection, which can be used as any other.  Once the commands already sent
+    /// from other handles have been, only commands sent from the leased handle and its clones are, until every
+    /// one of them is dropped.  Commands from other handles wait until then, so a lease should be kept no
+    /// longer than necessary.
+    ///
+    /// The lease ends if the connection is lost, commands sent after that fail, even if the connection is
+    /// re-established, as anything that relied on the lease will be lost with the connection.
+    pub fn lease(&self) -> PairedConnection {
+        let (out_tx, out_rx) = mpsc::unbounded();
+        // If the connection has closed, so will the lease
+        let _ = self.out_tx.unbounded_send(Request::Lease(out_rx));
+        PairedConnection { out_tx }
+    }
+
+    /// Runs a transaction with optimistic locking, retrying if a watched key changes before it is executed.
+    ///
+    /// For each attempt the connection is leased, and `WATCH` sent for `keys`.  The leased connection is passed
+    /// to `f`, which can read the keys to decide what the transaction should do, and returns a future that
+    /// resolves to the `Transaction`, made from the leased connection, and a value.  The transaction is then
+    /// executed.  If `EXEC` is aborted, because a watched key changed, `f` is called again, up to `attempts`
+    /// times in all; once exhausted the future fails with `error::Error::TransactionAborted`.
+    ///
+    /// Returns a future that resolves to the value from the attempt that succeeded, this could be the futures
+    /// of the transaction's commands.  If `f`'s future fails the keys are unwatched, and so does the
+    /// returned future.
+    ///
+    /// ```rust,no_run
+    /// # #[macro_use] extern crate redis_async;
+    /// # extern crate futures;
+    /// # use futures::Future;
+    /// # use redis_async::client;
+    /// # fn main() {
+    /// let addr = "127.0.0.1:6379".parse().unwrap();
+    /// let doubled_f = client::paired_connect(&addr).and_then(|connection| {
+    ///     connection
+    ///         .watch(vec!["counter"], 10, |leased| {
+    ///             leased.send::<Option<String>>(resp_array!["GET", "counter"]).map(move |value| {
+    ///                 let doubled = value.map_or(1, |value| value.parse().unwrap()) * 2;
+    ///                 let mut transaction = leased.transaction();
+    ///                 transaction.send::<()>(resp_array!["SET", "counter", doubled.to_string()]);
+    ///                 (transaction, doubled)
+    ///             })
+    ///         })
+    /// });
+    /// # }
+    /// ```
+    pub fn watch<K, F, R, T>(&self, keys: Vec<K>, attempts: usize, f: F) -> SendBox<T>
+    where
+        K: Into<resp::RespValue>,
+        F: FnMut(PairedConnection) -> R + Send + 'static,
+        R: IntoFuture<Item = (Transaction, T), Error = error::Error>,
+        R::Future: Send + 'static,
+        T: Send + 'static,
+    {
+        let mut watch = vec!["WATCH".into()];
+        watch.extend(keys.into_iter().map(Into::into));
+        let watch = resp::RespValue::Array(watch);
+        let connection = self.clone();
+        Box::new(future::loop_fn((f, 1), move |(mut f, attempt)| {
+            let leased = connection.lease();
+            let watched = leased.send::<()>(watch.clone());
+            let unwatch = leased.clone();
+            f(leased)
+                .into_future()
+                .join(watched)
+                .or_else(move |e| {
+                    faf!(unwatch.send(resp_array!["UNWATCH"]));
+                    Err(e)
+                })
+                .and_then(|((transaction, value), ())| transaction.exec().map(|()| value))
+                .then(move |result| match result {
+                    Ok(value) => Ok(Loop::Break(value)),
+                    Err(error::Error::TransactionAborted) if attempt < attempts => {
+                        Ok(Loop::Continue((f, attempt + 1)))
+                    }
+                    Err(e) => Err(e),
+                })
+        }))
+    }
+
+    /// Sends commands together, so no commands sent by other clones of this connection come between them.
+    ///
+    /// Returns a future that resolves to each command's reply once they have all been received, error replies
+    /// are returned as `RespValue::Error`.  Each of the others fails if the connection is lost.
+    pub(crate) fn send_all(
+        &self,
+        msgs: Vec<resp::RespValue>,
+    ) -> Box<Future<Item = Vec<Response>, Error = error::Error> + Send> {
+        if msgs.iter().any(|msg| !matches!(*msg, resp::RespValue::Array(_))) {
+            return Box::new(future::err(error::internal(
+                "Command must be a RespValue::Array",
+            )));
+        }
+
+        let mut request = Vec::with_capacity(msgs.len());
+        let mut replies = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            let (tx, rx) = oneshot::channel();
+            request.push((msg, tx));
+            replies.push(rx.then(|v| match v {
+                Ok(v) => Ok(v),
+                Err(e) => Ok(Err(e.into())),
+            }));
+        }
+        if self.out_tx
+            .unbounded_send(Request::Commands(request))
+            .is_err()
+        {
+            return Box::new(future::err(error::Error::Connection(
+                "Connection is closed".into(),
+            )));
+        }
+
+        Box::new(future::join_all(replies))
+    }
+
+    /// Publishes a message to a shard channel with `SPUBLISH`, for Redis 7's sharded PUBSUB, see
+    /// `PubsubConnection::ssubscribe`.  Resolves to the number of clients that received the message.
+    ///
+    /// In a cluster, this connection must be to the node that serves the channel's hash slot.
+    pub fn spublish<C, M>(&self, channel: C, message: M) -> SendBox<i64>
+    where
+        C: Into<resp::RespValue>,
+        M: Into<resp::RespValue>,
+    {
+        self.send(resp_array!["SPUBLISH", channel.into(), message.into()])
+    }
 }
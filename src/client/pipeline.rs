@@ -0,0 +1,76 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Explicit pipelines, sending a batch of commands in one go.
+
+use futures::Future;
+
+use error;
+use resp::{self, FromResp};
+use super::paired::{PairedConnection, SendBox};
+
+/// A batch of commands, sent together, see `PairedConnection::pipeline`.
+///
+/// The commands are written to the connection one after another, with no commands sent by other clones of the
+/// connection in between, and their results are returned together.  Unlike a `Transaction`, Redis may run
+/// other clients' commands between them.
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate redis_async;
+/// # extern crate futures;
+/// # use futures::Future;
+/// # use redis_async::{client, error};
+/// # fn main() {
+/// let addr = "127.0.0.1:6379".parse().unwrap();
+/// let results_f = client::paired_connect(&addr).and_then(|connection| {
+///     let mut pipeline = connection.pipeline();
+///     pipeline
+///         .add(resp_array!["INCR", "counter"])
+///         .add(resp_array!["GET", "name"]);
+///     // Each command's result separately, an error doesn't affect the other's
+///     pipeline.send::<(Result<i64, error::Error>, Result<String, error::Error>)>()
+/// });
+/// # }
+/// ```
+pub struct Pipeline {
+    connection: PairedConnection,
+    commands: Vec<resp::RespValue>,
+}
+
+impl Pipeline {
+    pub(crate) fn new(connection: PairedConnection) -> Self {
+        Pipeline {
+            connection: connection,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Adds a command to the pipeline.
+    pub fn add(&mut self, msg: resp::RespValue) -> &mut Self {
+        self.commands.push(msg);
+        self
+    }
+
+    /// Sends the commands to Redis.
+    ///
+    /// Returns a future that resolves to the results, once every command has been replied to, converted as an
+    /// array to any type for which `resp::FromResp` is implemented: a `Vec` or tuple with an element for each
+    /// command.  If any command fails the future fails, unless the elements are `Result`s, e.g.
+    /// `Vec<Result<String, error::Error>>`, then each command's error is returned in its place.  If the
+    /// connection is lost the future fails, regardless.
+    pub fn send<T: FromResp + Send + 'static>(self) -> SendBox<T> {
+        Box::new(self.connection.send_all(self.commands).and_then(|replies| {
+            let values = replies
+                .into_iter()
+                .collect::<Result<Vec<_>, error::Error>>()?;
+            T::from_resp(resp::RespValue::Array(values))
+        }))
+    }
+}
@@ -0,0 +1,351 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! A pool of connections, each used by one caller at a time.
+//!
+//! A `PairedConnection` is shared by every clone of it, so a command that blocks the connection, e.g. `BLPOP`,
+//! `BRPOPLPUSH`, `XREAD BLOCK` or `WAIT`, holds up every other caller.  Checking a connection out of a pool
+//! instead gives exclusive use of it, for as long as it's checked out, which also suits transactions and
+//! `WATCH`.
+
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::{future::{self, Either, Loop}, Future, Poll, sync::oneshot};
+
+use tokio_timer::Delay;
+
+use error;
+use resp;
+use super::builder::ConnectionBuilder;
+use super::paired::PairedConnection;
+
+/// The settings for a `Pool`.
+///
+/// ```rust,no_run
+/// # extern crate redis_async;
+/// # use std::time::Duration;
+/// # use redis_async::client::{pool::PoolBuilder, ConnectionBuilder};
+/// # fn main() {
+/// let addr = "127.0.0.1:6379".parse().unwrap();
+/// let pool = PoolBuilder::new(ConnectionBuilder::new(&addr))
+///     .max_size(4)
+///     .checkout_timeout(Duration::from_secs(5))
+///     .build();
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PoolBuilder {
+    connection: ConnectionBuilder,
+    max_size: usize,
+    idle_timeout: Duration,
+    checkout_timeout: Duration,
+    health_check: bool,
+}
+
+impl PoolBuilder {
+    /// Connections are made with `connection`, by default: up to ten of them, closed after five minutes idle,
+    /// waiting up to thirty seconds for one to be checked out, and checking each idle connection with `PING`
+    /// before it's checked out.
+    pub fn new(connection: ConnectionBuilder) -> Self {
+        PoolBuilder {
+            connection: connection,
+            max_size: 10,
+            idle_timeout: Duration::from_secs(300),
+            checkout_timeout: Duration::from_secs(30),
+            health_check: true,
+        }
+    }
+
+    /// The most connections there can be, whether checked out or idle.
+    ///
+    /// # Panics
+    ///
+    /// If `max_size` is zero.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        assert!(max_size > 0, "A pool must allow at least one connection");
+        self.max_size = max_size;
+        self
+    }
+
+    /// How long a connection can be idle in the pool before it's closed.  There's no timer for this, idle
+    /// connections are only checked each time one is checked out or returned, so the limit applies only while
+    /// the pool is being used.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// How long to wait for a connection when checking one out, if every connection is checked out and no more
+    /// can be made.
+    pub fn checkout_timeout(mut self, checkout_timeout: Duration) -> Self {
+        self.checkout_timeout = checkout_timeout;
+        self
+    }
+
+    /// Whether to send `PING` to an idle connection before it's checked out, if it fails the connection is
+    /// closed and another used instead.
+    pub fn health_check(mut self, health_check: bool) -> Self {
+        self.health_check = health_check;
+        self
+    }
+
+    /// Makes the pool, connections aren't made until they are needed.
+    pub fn build(&self) -> Pool {
+        Pool {
+            shared: Arc::new(Shared {
+                config: self.clone(),
+                state: Mutex::new(State {
+                    idle: VecDeque::new(),
+                    size: 0,
+                    waiting: VecDeque::new(),
+                }),
+            }),
+        }
+    }
+}
+
+struct Idle {
+    connection: PairedConnection,
+    since: Instant,
+}
+
+struct State {
+    /// Connections that aren't checked out, the one returned most recently last.
+    idle: VecDeque<Idle>,
+
+    /// The number of connections, whether checked out, idle, or being made.
+    size: usize,
+
+    /// Notified when a connection may be available, to try again.
+    waiting: VecDeque<oneshot::Sender<()>>,
+}
+
+impl State {
+    fn notify_waiting(&mut self) {
+        while let Some(waiting) = self.waiting.pop_front() {
+            if waiting.send(()).is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+struct Shared {
+    config: PoolBuilder,
+    state: Mutex<State>,
+}
+
+enum Checkout {
+    Idle(PooledConnection),
+    Connect(PooledConnection),
+    Wait(Waiter),
+}
+
+type CheckoutBox = Box<Future<Item = Loop<PooledConnection, (Pool, bool)>, Error = error::Error> + Send>;
+
+/// A pool of `PairedConnection`s, see `PoolBuilder`.  Clones share the same connections.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+impl Pool {
+    /// Checks out a connection, for exclusive use until the returned `PooledConnection` is dropped.
+    ///
+    /// Returns a future that resolves to an idle connection if there is one, otherwise a new connection if the
+    /// pool isn't full, otherwise the next connection to be returned.  It fails with
+    /// `error::Error::Connection` if none is available within the checkout timeout, or if a new connection
+    /// can't be made.
+    pub fn get(&self) -> Box<Future<Item = PooledConnection, Error = error::Error> + Send> {
+        let health_check = self.shared.config.health_check;
+        let checkout = future::loop_fn((self.clone(), false), move |(pool, woken)| -> CheckoutBox {
+            match pool.checkout(woken) {
+                Checkout::Idle(pooled) => {
+                    if !health_check {
+                        return Box::new(future::ok(Loop::Break(pooled)));
+                    }
+                    Box::new(
+                        pooled
+                            .send::<resp::RespValue>(resp_array!["PING"])
+                            .then(move |result| match result {
+                                Ok(_) => Ok(Loop::Break(pooled)),
+                                Err(e) => {
+                                    warn!("Closing pooled connection, health check failed: {}", e);
+                                    pooled.discard();
+                                    Ok(Loop::Continue((pool, woken)))
+                                }
+                            }),
+                    )
+                }
+                // If the connection can't be made, dropping `reserved` gives up its place in the pool
+                Checkout::Connect(mut reserved) => Box::new(
+                    pool.shared
+                        .config
+                        .connection
+                        .paired_connect()
+                        .map(move |connection| {
+                            reserved.connection = Some(connection);
+                            Loop::Break(reserved)
+                        }),
+                ),
+                Checkout::Wait(notified) => Box::new(notified.then(move |_| Ok(Loop::Continue((pool, true))))),
+            }
+        });
+        let timeout = Delay::new(Instant::now() + self.shared.config.checkout_timeout);
+        Box::new(checkout.select2(timeout).then(|result| match result {
+            Ok(Either::A((pooled, _))) => Ok(pooled),
+            Ok(Either::B(_)) => Err(error::Error::Connection(
+                "Timed out waiting for a connection from the pool".into(),
+            )),
+            Err(Either::A((e, _))) => Err(e),
+            Err(Either::B((e, _))) => Err(error::internal(format!("Timer error: {}", e))),
+        }))
+    }
+
+    /// The number of connections, whether checked out or idle.
+    pub fn size(&self) -> usize {
+        self.state().size
+    }
+
+    /// The number of idle connections.
+    pub fn idle(&self) -> usize {
+        self.state().idle.len()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().expect("Poisoned pool")
+    }
+
+    /// Closes connections that have been idle for too long.
+    fn close_idle(&self, state: &mut State) {
+        let idle_timeout = self.shared.config.idle_timeout;
+        while state
+            .idle
+            .front()
+            .is_some_and(|idle| idle.since.elapsed() >= idle_timeout)
+        {
+            state.idle.pop_front();
+            state.size -= 1;
+        }
+    }
+
+    /// Takes an idle connection, or a place for a new one, otherwise joins the queue of those waiting.  Having
+    /// been `woken` from the queue, but beaten to the connection, goes back to the front.
+    fn checkout(&self, woken: bool) -> Checkout {
+        let mut state = self.state();
+        self.close_idle(&mut state);
+        if let Some(idle) = state.idle.pop_back() {
+            return Checkout::Idle(PooledConnection {
+                connection: Some(idle.connection),
+                pool: self.clone(),
+            });
+        }
+        if state.size < self.shared.config.max_size {
+            state.size += 1;
+            return Checkout::Connect(PooledConnection {
+                connection: None,
+                pool: self.clone(),
+            });
+        }
+        let (tx, rx) = oneshot::channel();
+        let waiter = Waiter {
+            notified: rx,
+            pool: self.clone(),
+        };
+        if woken {
+            state.waiting.push_front(tx);
+        } else {
+            state.waiting.push_back(tx);
+        }
+        Checkout::Wait(waiter)
+    }
+
+    fn release(&self, connection: PairedConnection) {
+        let mut state = self.state();
+        state.idle.push_back(Idle {
+            connection: connection,
+            since: Instant::now(),
+        });
+        self.close_idle(&mut state);
+        state.notify_waiting();
+    }
+
+    /// Gives up a place in the pool, closing its connection if it has one.
+    fn remove(&self) {
+        let mut state = self.state();
+        state.size -= 1;
+        state.notify_waiting();
+    }
+}
+
+/// Waits in the queue for a connection to become available.  If it's dropped after being notified, but before
+/// trying to check out again (e.g. the checkout timed out at the same time), the next in the queue is notified
+/// instead, so the connection isn't left idle while others wait.
+struct Waiter {
+    notified: oneshot::Receiver<()>,
+    pool: Pool,
+}
+
+impl Future for Waiter {
+    type Item = ();
+    type Error = oneshot::Canceled;
+
+    fn poll(&mut self) -> Poll<(), oneshot::Canceled> {
+        self.notified.poll()
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.notified.close();
+        if let Ok(Some(())) = self.notified.try_recv() {
+            self.pool.state().notify_waiting();
+        }
+    }
+}
+
+/// A connection checked out of a `Pool`, which can be used as a `PairedConnection`.  It's returned to the pool
+/// when dropped.
+///
+/// The connection shouldn't be cloned, as the clone could then be used by someone else who checks the same
+/// connection out.  Anything still in progress when the connection is returned, e.g. a blocking command whose
+/// future was dropped, holds up whoever checks it out next.
+pub struct PooledConnection {
+    /// `None` while the connection is being made.
+    connection: Option<PairedConnection>,
+    pool: Pool,
+}
+
+impl PooledConnection {
+    /// Closes the connection, rather than returning it to the pool, e.g. if it's been left in an unknown state.
+    pub fn discard(mut self) {
+        self.connection = None;
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = PairedConnection;
+
+    fn deref(&self) -> &PairedConnection {
+        self.connection.as_ref().expect("Connection not made")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        match self.connection.take() {
+            Some(connection) => self.pool.release(connection),
+            None => self.pool.remove(),
+        }
+    }
+}
@@ -8,173 +8,557 @@
  * except according to those terms.
  */
 
-use std::collections::{HashMap, hash_map::Entry};
+use std::collections::{HashMap, HashSet, VecDeque, hash_map::Entry};
 use std::net::SocketAddr;
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
 
-use futures::{Async, AsyncSink, Future, Poll, Sink, Stream, stream::Fuse, sync::{mpsc, oneshot}};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream, stream::Fuse, sync::{mpsc, oneshot}};
 
 use tokio_executor::{DefaultExecutor, Executor};
 
 use error;
 use resp;
 use resp::FromResp;
-use super::connect::{connect, RespConnection};
+use super::buffer::{self, BufferConfig, Pressure, Sent};
+use super::builder::ConnectionBuilder;
+use super::connect::RespConnection;
+use super::reconnect::Reconnect;
+
+/// Subscriptions are either to a channel, by name, to every channel matching a pattern, or to a shard channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SubscriptionType {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+impl SubscriptionType {
+    fn subscribe_command(self) -> &'static str {
+        match self {
+            SubscriptionType::Channel => "SUBSCRIBE",
+            SubscriptionType::Pattern => "PSUBSCRIBE",
+            SubscriptionType::Shard => "SSUBSCRIBE",
+        }
+    }
+
+    fn unsubscribe_command(self) -> &'static str {
+        match self {
+            SubscriptionType::Channel => "UNSUBSCRIBE",
+            SubscriptionType::Pattern => "PUNSUBSCRIBE",
+            SubscriptionType::Shard => "SUNSUBSCRIBE",
+        }
+    }
+}
+
+/// Identifies a subscription: its type, and the channel or pattern.
+type SubscriptionKey = (SubscriptionType, String);
+
+/// A new subscriber, with a unique ID.
+type NewSubscriber = (SubscriptionKey, usize, PubsubSink, SubscribedSignal);
+
+/// The commands to subscribe, or unsubscribe, to each of `keys`.  All the channels, and all the patterns, are
+/// each done with one command; but shard channels are done one by one, as they can only be combined if they're
+/// in the same slot.
+fn subscription_commands<F>(keys: &[SubscriptionKey], command: F) -> Vec<resp::RespValue>
+where
+    F: Fn(SubscriptionType) -> &'static str,
+{
+    let mut commands = Vec::new();
+    for subscription_type in &[SubscriptionType::Channel, SubscriptionType::Pattern] {
+        let topics = keys.iter()
+            .filter(|key| key.0 == *subscription_type)
+            .map(|key| key.1.as_str().into())
+            .collect::<Vec<resp::RespValue>>();
+        if !topics.is_empty() {
+            let mut parts = vec![command(*subscription_type).into()];
+            parts.extend(topics);
+            commands.push(resp::RespValue::Array(parts));
+        }
+    }
+    commands.extend(
+        keys.iter()
+            .filter(|key| key.0 == SubscriptionType::Shard)
+            .map(|key| resp_array![command(SubscriptionType::Shard), key.1.as_str()]),
+    );
+    commands
+}
+
+/// Who is unsubscribing from a topic.
+#[derive(Debug)]
+enum Unsubscriber {
+    /// A stream that has been dropped.
+    Stream(usize),
+
+    /// Everything subscribed to the topic, signalled once Redis confirms it.
+    All(oneshot::Sender<()>),
+}
+
+/// An unsubscription waiting to be confirmed by Redis.
+struct Unsubscribing {
+    /// These carry on receiving messages until Redis confirms the unsubscription, then their streams end.
+    subscribers: Vec<(usize, PubsubSink)>,
+    signal: Option<oneshot::Sender<()>>,
+}
+
+impl Unsubscribing {
+    fn confirm(self) {
+        if let Some(signal) = self.signal {
+            let _ = signal.send(());
+        }
+    }
+}
 
 #[derive(Debug)]
 enum PubsubEvent {
-    Subscribe(String, PubsubSink, oneshot::Sender<()>),
-    Unsubscribe(String),
+    Subscribe(Vec<NewSubscriber>),
+    Unsubscribe(Vec<(SubscriptionKey, Unsubscriber)>),
+}
+
+/// A message published to a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PubsubMessage {
+    /// The channel the message was published to.
+    pub channel: String,
+
+    /// The pattern that matched the channel, only set for subscriptions made with `psubscribe`.
+    pub pattern: Option<String>,
+
+    pub payload: resp::RespValue,
+}
+
+impl PubsubMessage {
+    /// Converts the payload to any type for which `resp::FromResp` is implemented, e.g. `String`.
+    pub fn decode<T: resp::FromResp>(&self) -> Result<T, error::Error> {
+        T::from_resp(self.payload.clone())
+    }
 }
 
-pub type PubsubStreamInner = mpsc::UnboundedReceiver<resp::RespValue>;
-pub type PubsubSink = mpsc::UnboundedSender<resp::RespValue>;
+/// An item sent to a subscription, see `PubsubStream::with_reconnect_notices`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PubsubItem {
+    Message(PubsubMessage),
+
+    /// The connection was lost and has been re-established, see `ConnectionBuilder::reconnect`.  Any messages
+    /// published while disconnected will have been missed.
+    Reconnected,
+}
+
+/// Each subscriber is sent an error, rather than the buffer just being closed, if its subscription ends other
+/// than by being unsubscribed.
+type PubsubStreamInner = buffer::Receiver<Result<PubsubItem, error::Error>>;
+type PubsubSink = buffer::Sender<Result<PubsubItem, error::Error>>;
+
+/// Signals once a subscription is confirmed, or fails.
+type SubscribedSignal = oneshot::Sender<Result<(), error::Error>>;
 
 struct PubsubConnectionInner {
-    connection: RespConnection,
+    /// `None` while reconnecting.
+    connection: Option<RespConnection>,
+    reconnect: Option<Reconnect>,
     out_rx: Fuse<mpsc::UnboundedReceiver<PubsubEvent>>,
-    subscriptions: HashMap<String, PubsubSink>,
-    pending_subs: HashMap<String, (PubsubSink, oneshot::Sender<()>)>,
-    send_pending: Option<resp::RespValue>,
+    /// Each subscription may have many subscribers, Redis is only asked to unsubscribe once the last is gone.
+    subscriptions: HashMap<SubscriptionKey, Vec<(usize, PubsubSink)>>,
+    pending_subs: HashMap<SubscriptionKey, Vec<(usize, PubsubSink, SubscribedSignal)>>,
+
+    /// Redis confirms each `UNSUBSCRIBE` in order, even if a topic is unsubscribed from more than once.
+    unsubscribing: HashMap<SubscriptionKey, VecDeque<Unsubscribing>>,
+
+    /// Subscribed to again after reconnecting, each is sent a notice once the subscription is confirmed.
+    resubscribing: HashSet<SubscriptionKey>,
+    send_pending: VecDeque<resp::RespValue>,
+
+    /// Whether any subscriber's buffer is full, shared with every subscriber's buffer.
+    pressure: Arc<Pressure>,
 }
 
 impl PubsubConnectionInner {
-    fn new(con: RespConnection, out_rx: mpsc::UnboundedReceiver<PubsubEvent>) -> Self {
+    fn new(
+        con: RespConnection,
+        out_rx: mpsc::UnboundedReceiver<PubsubEvent>,
+        reconnect: Option<Reconnect>,
+        pressure: Arc<Pressure>,
+    ) -> Self {
         PubsubConnectionInner {
-            connection: con,
+            connection: Some(con),
+            reconnect: reconnect,
             out_rx: out_rx.fuse(),
             subscriptions: HashMap::new(),
             pending_subs: HashMap::new(),
-            send_pending: None,
+            unsubscribing: HashMap::new(),
+            resubscribing: HashSet::new(),
+            send_pending: VecDeque::new(),
+            pressure: pressure,
         }
     }
 
+    fn connection(&mut self) -> Result<&mut RespConnection, error::Error> {
+        self.connection
+            .as_mut()
+            .ok_or_else(|| error::Error::Connection("Not connected".into()))
+    }
+
     /// Returns true = OK, more can be sent, or false = sink is full, needs flushing
-    fn do_send(&mut self, msg: resp::RespValue) -> Result<bool, ()> {
-        match self.connection
-            .start_send(msg)
-            .map_err(|e| error!("Cannot send subscription request to Redis: {}", e))?
-        {
+    fn do_send(&mut self, msg: resp::RespValue) -> Result<bool, error::Error> {
+        match self.connection()?.start_send(msg)? {
             AsyncSink::Ready => Ok(true),
             AsyncSink::NotReady(msg) => {
-                self.send_pending = Some(msg);
+                self.send_pending.push_front(msg);
                 Ok(false)
             }
         }
     }
 
-    fn do_flush(&mut self) -> Result<(), ()> {
-        self.connection
-            .poll_complete()
-            .map(|_| ())
-            .map_err(|e| error!("Error polling for completeness: {}", e))
+    fn do_flush(&mut self) -> Result<(), error::Error> {
+        self.connection()?.poll_complete()?;
+        Ok(())
     }
 
-    // Returns true = flushing required.  false = no flushing required
-    fn handle_new_subs(&mut self) -> Result<bool, ()> {
-        let mut flushing_req = false;
-        if let Some(msg) = self.send_pending.take() {
-            flushing_req = true;
-            if !self.do_send(msg)? {
-                return Ok(flushing_req);
-            }
-        }
+    /// Handles new subscribers, and those that have gone, queueing any commands that need to be sent to Redis.
+    /// While reconnecting nothing is queued, as everything is subscribed to again once reconnected.
+    fn handle_new_subs(&mut self) -> Result<(), error::Error> {
         loop {
-            match self.out_rx
+            let commands = match self.out_rx
                 .poll()
-                .map_err(|_| error!("Cannot poll for new subscriptions"))?
+                .map_err(|_| error::internal("Cannot poll for new subscriptions"))?
             {
-                Async::Ready(None) => {
-                    return Ok(flushing_req);
+                Async::Ready(Some(PubsubEvent::Subscribe(subscribers))) => {
+                    let mut keys = Vec::new();
+                    for (key, id, sender, signal) in subscribers {
+                        if self.add_subscriber(key.clone(), id, sender, signal) {
+                            keys.push(key);
+                        }
+                    }
+                    subscription_commands(&keys, SubscriptionType::subscribe_command)
                 }
-                Async::Ready(Some(pubsub_event)) => {
-                    let message = match pubsub_event {
-                        PubsubEvent::Subscribe(topic, sender, signal) => {
-                            self.pending_subs.insert(topic.clone(), (sender, signal));
-                            resp_array!["SUBSCRIBE", topic]
+                Async::Ready(Some(PubsubEvent::Unsubscribe(unsubscribers))) => {
+                    let mut keys = Vec::new();
+                    for (key, unsubscriber) in unsubscribers {
+                        let (id, signal) = match unsubscriber {
+                            Unsubscriber::Stream(id) => (Some(id), None),
+                            Unsubscriber::All(signal) => (None, Some(signal)),
+                        };
+                        let unsubscribing = match self.remove_subscriber(&key, id) {
+                            Some(subscribers) => Unsubscribing {
+                                subscribers: subscribers,
+                                signal: signal,
+                            },
+                            None => continue,
+                        };
+                        if self.await_unsubscription(key.clone(), unsubscribing) {
+                            keys.push(key);
                         }
-                        PubsubEvent::Unsubscribe(topic) => resp_array!["UNSUBSCRIBE", topic],
-                    };
-                    flushing_req = true;
-                    if !self.do_send(message)? {
-                        return Ok(flushing_req);
                     }
+                    subscription_commands(&keys, SubscriptionType::unsubscribe_command)
                 }
-                Async::NotReady => {
-                    return Ok(flushing_req);
+                Async::Ready(None) | Async::NotReady => return Ok(()),
+            };
+            if self.connection.is_some() {
+                self.send_pending.extend(commands);
+            }
+        }
+    }
+
+    /// Returns true if Redis needs to be asked to subscribe, i.e. this is the first subscriber.
+    fn add_subscriber(
+        &mut self,
+        key: SubscriptionKey,
+        id: usize,
+        sender: PubsubSink,
+        signal: SubscribedSignal,
+    ) -> bool {
+        if let Some(subscribers) = self.subscriptions.get_mut(&key) {
+            subscribers.push((id, sender));
+            let _ = signal.send(Ok(()));
+            return false;
+        }
+        let pending = self.pending_subs.entry(key).or_default();
+        pending.push((id, sender, signal));
+        pending.len() == 1
+    }
+
+    /// Removes one subscriber, or every subscriber if `id` is `None`.  Returns `None` if there are subscribers
+    /// left, otherwise Redis needs to be asked to unsubscribe and the subscribers that should carry on receiving
+    /// messages until it confirms this are returned.
+    fn remove_subscriber(
+        &mut self,
+        key: &SubscriptionKey,
+        id: Option<usize>,
+    ) -> Option<Vec<(usize, PubsubSink)>> {
+        let id = match id {
+            Some(id) => id,
+            None => {
+                let mut subscribers = self.subscriptions.remove(key).unwrap_or_default();
+                // The subscription will be confirmed before the unsubscription
+                for (id, sender, signal) in self.pending_subs.remove(key).unwrap_or_default() {
+                    let _ = signal.send(Ok(()));
+                    subscribers.push((id, sender));
                 }
+                return Some(subscribers);
+            }
+        };
+        if let Entry::Occupied(mut entry) = self.subscriptions.entry(key.clone()) {
+            entry.get_mut().retain(|&(sub_id, _)| sub_id != id);
+            if !entry.get().is_empty() {
+                return None;
+            }
+            entry.remove();
+            return Some(Vec::new());
+        }
+        // A subscriber may go before the subscription is confirmed
+        if let Entry::Occupied(mut entry) = self.pending_subs.entry(key.clone()) {
+            entry.get_mut().retain(|&(sub_id, _, _)| sub_id != id);
+            if !entry.get().is_empty() {
+                return None;
             }
+            entry.remove();
+            return Some(Vec::new());
         }
+        None
     }
 
-    fn handle_message(&mut self, msg: resp::RespValue) -> Result<bool, ()> {
-        let (message_type, topic, msg) = match msg {
-            resp::RespValue::Array(mut messages) => match (
-                messages.pop(),
-                messages.pop(),
-                messages.pop(),
-                messages.pop(),
-            ) {
-                (Some(msg), Some(topic), Some(message_type), None) => {
-                    match (msg, String::from_resp(topic), message_type) {
-                        (msg, Ok(topic), resp::RespValue::BulkString(bytes)) => (bytes, topic, msg),
-                        _ => {
-                            error!("Incorrect format of PUBSUB message");
-                            return Err(());
-                        }
-                    }
+    /// Waits for Redis to confirm an unsubscription, returns false if there's no connection and so nothing to
+    /// wait for: it's complete straight away.
+    fn await_unsubscription(&mut self, key: SubscriptionKey, unsubscribing: Unsubscribing) -> bool {
+        if self.connection.is_none() {
+            unsubscribing.confirm();
+            return false;
+        }
+        self.unsubscribing
+            .entry(key)
+            .or_default()
+            .push_back(unsubscribing);
+        true
+    }
+
+    fn confirm_unsubscription(&mut self, key: SubscriptionKey) {
+        let unsubscribing = match self.unsubscribing.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                let unsubscribing = entry.get_mut().pop_front();
+                if entry.get().is_empty() {
+                    entry.remove();
                 }
-                _ => {
-                    error!("Wrong number of parts for a PUBSUB message");
-                    return Err(());
+                unsubscribing
+            }
+            Entry::Vacant(_) => None,
+        };
+        match unsubscribing {
+            Some(unsubscribing) => unsubscribing.confirm(),
+            // Redis unsubscribes from shard channels itself if their slot moves to another node, the streams fail
+            // so subscribers know to subscribe again.
+            None if key.0 == SubscriptionType::Shard => if let Some(subscribers) = self.subscriptions.remove(&key) {
+                let message = format!("Unsubscribed from shard channel {} by Redis", key.1);
+                warn!("{}", message);
+                for (_, sender) in subscribers {
+                    sender.force(Err(error::Error::Remote(message.clone())));
                 }
             },
-            _ => {
-                error!("PUBSUB message should be encoded as an array");
-                return Err(());
+            None => (),
+        }
+    }
+
+    /// Completes every unsubscription, as the connection has closed.
+    fn confirm_unsubscriptions(&mut self) {
+        for (_, unsubscribing) in self.unsubscribing.drain() {
+            for unsubscribing in unsubscribing {
+                unsubscribing.confirm();
+            }
+        }
+    }
+
+    // Returns true = flushing required.  false = no flushing required
+    fn send_queued(&mut self) -> Result<bool, error::Error> {
+        let mut flushing_req = false;
+        while let Some(msg) = self.send_pending.pop_front() {
+            flushing_req = true;
+            if !self.do_send(msg)? {
+                break;
+            }
+        }
+        Ok(flushing_req)
+    }
+
+    fn handle_message(&mut self, msg: resp::RespValue) -> Result<(), error::Error> {
+        let parts = match msg {
+            resp::RespValue::Array(parts) => parts,
+            msg => return Err(error::resp("PUBSUB message should be encoded as an array", msg)),
+        };
+        // Messages received by pattern subscriptions also contain the pattern that matched
+        let expected_parts = match parts.first() {
+            Some(resp::RespValue::BulkString(bytes)) if &bytes[..] == b"pmessage" => 4,
+            _ => 3,
+        };
+        if parts.len() != expected_parts {
+            return Err(error::resp(
+                "Wrong number of parts for a PUBSUB message",
+                resp::RespValue::Array(parts),
+            ));
+        }
+
+        let mut parts = parts.into_iter();
+        let (message_type, topic) = match (parts.next(), parts.next().map(String::from_resp)) {
+            (Some(resp::RespValue::BulkString(message_type)), Some(Ok(topic))) => {
+                (message_type, topic)
             }
+            _ => return Err(error::Error::RESP("Incorrect format of PUBSUB message".into(), None)),
         };
+        let payload = parts.next().expect("Checked number of parts");
 
-        if message_type == b"subscribe" {
-            if let Some((sender, signal)) = self.pending_subs.remove(&topic) {
-                self.subscriptions.insert(topic, sender);
-                signal
-                    .send(())
-                    .map_err(|_| error!("Error confirming subscription"))?;
+        match &message_type[..] {
+            b"subscribe" => self.confirm_subscription((SubscriptionType::Channel, topic)),
+            b"psubscribe" => self.confirm_subscription((SubscriptionType::Pattern, topic)),
+            b"ssubscribe" => self.confirm_subscription((SubscriptionType::Shard, topic)),
+            b"message" | b"smessage" => {
+                let subscription_type = if &message_type[..] == b"message" {
+                    SubscriptionType::Channel
+                } else {
+                    SubscriptionType::Shard
+                };
+                let message = PubsubMessage {
+                    channel: topic.clone(),
+                    pattern: None,
+                    payload: payload,
+                };
+                self.deliver((subscription_type, topic), message)
             }
-        } else if message_type == b"unsubscribe" {
-            if let Entry::Occupied(entry) = self.subscriptions.entry(topic) {
-                entry.remove_entry();
+            b"pmessage" => {
+                let message = PubsubMessage {
+                    channel: String::from_resp(payload)?,
+                    pattern: Some(topic.clone()),
+                    payload: parts.next().expect("Checked number of parts"),
+                };
+                self.deliver((SubscriptionType::Pattern, topic), message)
+            }
+            b"unsubscribe" => self.confirm_unsubscription((SubscriptionType::Channel, topic)),
+            b"punsubscribe" => self.confirm_unsubscription((SubscriptionType::Pattern, topic)),
+            b"sunsubscribe" => self.confirm_unsubscription((SubscriptionType::Shard, topic)),
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn deliver(&mut self, key: SubscriptionKey, message: PubsubMessage) {
+        // Messages are still delivered to subscribers that are unsubscribing, until Redis confirms it
+        if let Some(unsubscribing) = self.unsubscribing.get(&key) {
+            for (_, sender) in unsubscribing.iter().flat_map(|u| u.subscribers.iter()) {
+                sender.send(Ok(PubsubItem::Message(message.clone())));
             }
-            if self.subscriptions.is_empty() {
-                return Ok(false);
+        }
+        let lagged = match self.subscriptions.get(&key) {
+            Some(subscribers) => subscribers
+                .iter()
+                .filter(|(_, sender)| {
+                    // A subscriber that has just been dropped will be removed once its unsubscription is handled
+                    sender.send(Ok(PubsubItem::Message(message.clone()))) == Sent::Lagged
+                })
+                .map(|&(id, _)| id)
+                .collect::<Vec<_>>(),
+            None => return,
+        };
+        for id in lagged {
+            warn!("Subscriber to {} is too slow, disconnecting it", key.1);
+            let sender = self.subscriptions
+                .get(&key)
+                .and_then(|subscribers| subscribers.iter().find(|&&(sub_id, _)| sub_id == id));
+            if let Some((_, sender)) = sender {
+                let message = format!("Too slow to receive messages from {}", key.1);
+                sender.force(Err(error::Error::Lagged(message)));
             }
-        } else if message_type == b"message" {
-            if let Some(sender) = self.subscriptions.get(&topic) {
-                sender.unbounded_send(msg).expect("Cannot send message");
+            if let Some(subscribers) = self.remove_subscriber(&key, Some(id)) {
+                let unsubscribing = Unsubscribing {
+                    subscribers: subscribers,
+                    signal: None,
+                };
+                if self.await_unsubscription(key.clone(), unsubscribing) {
+                    self.send_pending
+                        .push_back(resp_array![key.0.unsubscribe_command(), key.1.as_str()]);
+                }
             }
         }
+    }
 
-        Ok(true)
+    /// Returns false if any subscriber's buffer is full, and its policy is to apply backpressure.  Nothing more
+    /// should be read from the connection until there's room.
+    fn ready_for_messages(&self) -> bool {
+        self.pressure.poll_ready().is_ready()
     }
 
-    /// Returns true, if there are still valid subscriptions at the end, or false if not, i.e. the whole thing can be dropped.
-    fn handle_messages(&mut self) -> Result<bool, ()> {
-        loop {
-            match self.connection
-                .poll()
-                .map_err(|e| error!("Polling error for messages: {}", e))?
-            {
-                Async::Ready(None) => return Ok(false),
-                Async::Ready(Some(message)) => {
-                    let message_result = self.handle_message(message)?;
-                    if !message_result {
-                        return Ok(false);
-                    }
+    fn confirm_subscription(&mut self, key: SubscriptionKey) {
+        if let Some(pending) = self.pending_subs.remove(&key) {
+            let subscribers = self.subscriptions.entry(key).or_default();
+            for (id, sender, signal) in pending {
+                subscribers.push((id, sender));
+                let _ = signal.send(Ok(()));
+            }
+        } else if self.resubscribing.remove(&key) {
+            if let Some(subscribers) = self.subscriptions.get(&key) {
+                for (_, sender) in subscribers {
+                    sender.force(Ok(PubsubItem::Reconnected));
                 }
-                Async::NotReady => return Ok(true),
             }
         }
     }
+
+    fn handle_messages(&mut self) -> Result<(), error::Error> {
+        loop {
+            if !self.ready_for_messages() {
+                return Ok(());
+            }
+            match self.connection()?.poll()? {
+                Async::Ready(None) => return Err(error::Error::EndOfStream),
+                Async::Ready(Some(message)) => self.handle_message(message)?,
+                Async::NotReady => return Ok(()),
+            }
+        }
+    }
+
+    /// Returns true if there's nothing left to do: nothing is subscribed to, and every handle has been dropped.
+    fn is_finished(&self) -> bool {
+        self.out_rx.is_done() && self.subscriptions.is_empty() && self.pending_subs.is_empty()
+    }
+
+    fn poll_connected(&mut self) -> Poll<(), error::Error> {
+        self.handle_new_subs()?;
+        // Slow subscribers may be disconnected while handling messages, so this is done before sending
+        self.handle_messages()?;
+        if self.send_queued()? {
+            self.do_flush()?;
+        }
+        if self.is_finished() {
+            self.confirm_unsubscriptions();
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Fails every subscription, and any waiting to be confirmed, as the connection is closed for good.
+    fn fail_subscriptions(&mut self, message: String) {
+        for (_, subscribers) in self.subscriptions.drain() {
+            for (_, sender) in subscribers {
+                sender.force(Err(error::Error::Connection(message.clone())));
+            }
+        }
+        for (_, pending) in self.pending_subs.drain() {
+            for (_, _, signal) in pending {
+                let _ = signal.send(Err(error::Error::Connection(message.clone())));
+            }
+        }
+    }
+
+    /// Subscribes again, on a new connection, to everything that was subscribed to, or was pending, before the
+    /// connection was lost.
+    fn resubscribe(&mut self, connection: RespConnection) {
+        self.connection = Some(connection);
+        self.resubscribing = self.subscriptions.keys().cloned().collect();
+        let keys = self.subscriptions
+            .keys()
+            .chain(self.pending_subs.keys())
+            .cloned()
+            .collect::<Vec<_>>();
+        self.send_pending
+            .extend(subscription_commands(&keys, SubscriptionType::subscribe_command));
+    }
 }
 
 impl Future for PubsubConnectionInner {
@@ -182,15 +566,41 @@ impl Future for PubsubConnectionInner {
     type Error = ();
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let flush_req = self.handle_new_subs()?;
-        if flush_req {
-            self.do_flush()?;
-        }
-        let cont = self.handle_messages()?;
-        if cont {
-            Ok(Async::NotReady)
-        } else {
-            Ok(Async::Ready(()))
+        loop {
+            if self.connection.is_some() {
+                match self.poll_connected() {
+                    Ok(poll) => return Ok(poll),
+                    Err(e) => {
+                        error!("Connection to Redis lost: {}", e);
+                        self.connection = None;
+                        self.send_pending.clear();
+                        // Unsubscribing from a closed connection, or from a new one, has the same effect
+                        self.confirm_unsubscriptions();
+                        if self.reconnect.is_none() {
+                            self.fail_subscriptions(format!("Connection lost: {}", e));
+                            return Err(());
+                        }
+                    }
+                }
+            }
+
+            self.handle_new_subs()
+                .map_err(|e| error!("Cannot handle new subscriptions: {}", e))?;
+            if self.is_finished() {
+                return Ok(Async::Ready(()));
+            }
+            let reconnected = match self.reconnect {
+                Some(ref mut reconnect) => reconnect.poll(),
+                None => return Err(()),
+            };
+            match reconnected {
+                Ok(Async::Ready(connection)) => self.resubscribe(connection),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(()) => {
+                    self.fail_subscriptions("Connection lost, and could not be re-established".into());
+                    return Err(());
+                }
+            }
         }
     }
 }
@@ -199,74 +609,388 @@ impl Future for PubsubConnectionInner {
 #[derive(Clone)]
 pub struct PubsubConnection {
     out_tx: mpsc::UnboundedSender<PubsubEvent>,
+    next_id: Arc<AtomicUsize>,
+    buffer: BufferConfig,
+    pressure: Arc<Pressure>,
 }
 
 /// Used for Redis's PUBSUB functionality.
 ///
-/// Returns a future that resolves to a `PubsubConnection`.
+/// Returns a future that resolves to a `PubsubConnection`.  To authenticate, see `ConnectionBuilder`.
 pub fn pubsub_connect(
     addr: &SocketAddr,
 ) -> Box<Future<Item = PubsubConnection, Error = error::Error> + Send> {
-    let pc_f = connect(addr).map_err(|e| e.into()).map(|connection| {
-        let (out_tx, out_rx) = mpsc::unbounded();
-        let pubsub_connection_inner = Box::new(PubsubConnectionInner::new(connection, out_rx));
-        let mut default_executor = DefaultExecutor::current();
-        default_executor
-            .spawn(pubsub_connection_inner)
-            .expect("Cannot spawn pubsub connection");
-        PubsubConnection { out_tx }
-    });
-    Box::new(pc_f)
+    ConnectionBuilder::new(addr).pubsub_connect()
+}
+
+/// Spawns the task that owns `connection`, returning a handle to subscribe with.  If `reconnect` is set it is
+/// used to re-establish the connection, and subscriptions, if it's lost.
+pub(crate) fn spawn(
+    connection: RespConnection,
+    reconnect: Option<Reconnect>,
+    buffer: BufferConfig,
+) -> PubsubConnection {
+    let (out_tx, out_rx) = mpsc::unbounded();
+    let pressure = Arc::new(Pressure::default());
+    let pubsub_connection_inner = Box::new(PubsubConnectionInner::new(
+        connection,
+        out_rx,
+        reconnect,
+        pressure.clone(),
+    ));
+    let mut default_executor = DefaultExecutor::current();
+    default_executor
+        .spawn(pubsub_connection_inner)
+        .expect("Cannot spawn pubsub connection");
+    PubsubConnection {
+        out_tx: out_tx,
+        next_id: Arc::new(AtomicUsize::new(0)),
+        buffer: buffer,
+        pressure: pressure,
+    }
 }
 
 impl PubsubConnection {
     /// Subscribes to a particular PUBSUB topic.
     ///
     /// Returns a future that resolves to a `Stream` that contains all the messages published on
-    /// that particular topic.
+    /// that particular topic.  The stream ends once unsubscribed, or fails if the subscription ends for any
+    /// other reason, e.g. the connection is lost and not re-established.
+    ///
+    /// A topic can be subscribed to more than once, each stream receives every message.  Redis is only asked
+    /// to subscribe for the first, and to unsubscribe once the last has been dropped.
     pub fn subscribe<T: Into<String>>(
         &self,
         topic: T,
     ) -> Box<Future<Item = PubsubStream, Error = error::Error> + Send> {
-        let topic = topic.into();
-        let (tx, rx) = mpsc::unbounded();
-        let (signal_t, signal_r) = oneshot::channel();
-        self.out_tx
-            .unbounded_send(PubsubEvent::Subscribe(topic.clone(), tx, signal_t))
-            .expect("Cannot queue subscription request");
-
-        let stream = PubsubStream {
-            topic: topic,
-            underlying: rx,
-            con: self.clone(),
-        };
-        Box::new(signal_r.map(|_| stream).map_err(|e| e.into()))
+        Box::new(
+            self.start_subscription((SubscriptionType::Channel, topic.into()))
+                .map(|subscription| PubsubStream { subscription }),
+        )
+    }
+
+    /// Subscribes to all channels matching a glob-style pattern, e.g. `news.*`, with `PSUBSCRIBE`.
+    ///
+    /// Returns a future that resolves to a `Stream` that contains each message published to a matching
+    /// channel, as `subscribe`.  Each message's `pattern` is set.
+    pub fn psubscribe<T: Into<String>>(
+        &self,
+        pattern: T,
+    ) -> Box<Future<Item = PubsubStream, Error = error::Error> + Send> {
+        Box::new(
+            self.start_subscription((SubscriptionType::Pattern, pattern.into()))
+                .map(|subscription| PubsubStream { subscription }),
+        )
+    }
+
+    /// Subscribes to a shard channel with `SSUBSCRIBE`, for Redis 7's sharded PUBSUB.  Messages are published to
+    /// shard channels with `SPUBLISH`, see `PairedConnection::spublish`.
+    ///
+    /// In a cluster, each shard channel belongs to a hash slot, the same as a key, and this connection must be
+    /// to the node that serves that slot.  If the slot moves, Redis ends the subscription and the stream fails
+    /// with `error::Error::Remote`.
+    pub fn ssubscribe<T: Into<String>>(
+        &self,
+        channel: T,
+    ) -> Box<Future<Item = PubsubStream, Error = error::Error> + Send> {
+        Box::new(
+            self.start_subscription((SubscriptionType::Shard, channel.into()))
+                .map(|subscription| PubsubStream { subscription }),
+        )
+    }
+
+    /// Subscribes to many topics with a single `SUBSCRIBE`, rather than one for each as `subscribe` does.
+    ///
+    /// Returns a future that resolves, once every subscription is confirmed, to a stream for each topic in the
+    /// same order.  These can be combined with `MergedStream::new` if one stream is more convenient, each
+    /// message contains the channel it was published to.
+    pub fn subscribe_many<I, T>(
+        &self,
+        topics: I,
+    ) -> Box<Future<Item = Vec<PubsubStream>, Error = error::Error> + Send>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        let keys = topics
+            .into_iter()
+            .map(|topic| (SubscriptionType::Channel, topic.into()))
+            .collect();
+        Box::new(self.start_subscriptions(keys).map(|subscriptions| {
+            subscriptions
+                .into_iter()
+                .map(|subscription| PubsubStream { subscription })
+                .collect()
+        }))
+    }
+
+    fn start_subscription(
+        &self,
+        key: SubscriptionKey,
+    ) -> Box<Future<Item = Subscription, Error = error::Error> + Send> {
+        Box::new(self.start_subscriptions(vec![key]).map(|mut subscriptions| {
+            subscriptions.pop().expect("One subscription")
+        }))
+    }
+
+    fn start_subscriptions(
+        &self,
+        keys: Vec<SubscriptionKey>,
+    ) -> Box<Future<Item = Vec<Subscription>, Error = error::Error> + Send> {
+        let mut subscribers = Vec::with_capacity(keys.len());
+        let mut subscriptions = Vec::with_capacity(keys.len());
+        let mut signals = Vec::with_capacity(keys.len());
+        for key in keys {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let (tx, rx) = buffer::channel(self.buffer, self.pressure.clone());
+            let (signal_t, signal_r) = oneshot::channel();
+            subscribers.push((key.clone(), id, tx, signal_t));
+            subscriptions.push(Subscription {
+                key: key,
+                id: id,
+                underlying: rx,
+                con: self.clone(),
+            });
+            signals.push(signal_r.then(|result| match result {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(e),
+                Err(e) => Err(e.into()),
+            }));
+        }
+        if self.out_tx
+            .unbounded_send(PubsubEvent::Subscribe(subscribers))
+            .is_err()
+        {
+            return Box::new(future::err(error::Error::Connection("Connection is closed".into())));
+        }
+
+        Box::new(future::join_all(signals).map(|_| subscriptions))
     }
 
-    pub fn unsubscribe<T: Into<String>>(&self, topic: T) {
-        self.out_tx
-            .unbounded_send(PubsubEvent::Unsubscribe(topic.into()))
-            .expect("Cannot queue unsubscription request");
+    /// Unsubscribes from a topic, ending every stream subscribed to it.
+    ///
+    /// Returns a future that resolves once Redis confirms the unsubscription, although it happens regardless of
+    /// whether the future is polled.  Messages published before then are still delivered to the streams, which
+    /// end once it's confirmed.  If the connection is lost, or has already closed, the future resolves straight
+    /// away.
+    pub fn unsubscribe<T: Into<String>>(
+        &self,
+        topic: T,
+    ) -> Box<Future<Item = (), Error = error::Error> + Send> {
+        self.end_subscriptions(vec![(SubscriptionType::Channel, topic.into())])
+    }
+
+    /// Unsubscribes from many topics with a single `UNSUBSCRIBE`, ending every stream subscribed to them.  The
+    /// future resolves once Redis has confirmed each, as `unsubscribe`.
+    pub fn unsubscribe_many<I, T>(
+        &self,
+        topics: I,
+    ) -> Box<Future<Item = (), Error = error::Error> + Send>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        let keys = topics
+            .into_iter()
+            .map(|topic| (SubscriptionType::Channel, topic.into()))
+            .collect();
+        self.end_subscriptions(keys)
+    }
+
+    /// Unsubscribes from a pattern, ending every stream subscribed to it, as `unsubscribe`.
+    pub fn punsubscribe<T: Into<String>>(
+        &self,
+        pattern: T,
+    ) -> Box<Future<Item = (), Error = error::Error> + Send> {
+        self.end_subscriptions(vec![(SubscriptionType::Pattern, pattern.into())])
+    }
+
+    /// Unsubscribes from a shard channel, ending every stream subscribed to it, as `unsubscribe`.
+    pub fn sunsubscribe<T: Into<String>>(
+        &self,
+        channel: T,
+    ) -> Box<Future<Item = (), Error = error::Error> + Send> {
+        self.end_subscriptions(vec![(SubscriptionType::Shard, channel.into())])
+    }
+
+    fn end_subscriptions(
+        &self,
+        keys: Vec<SubscriptionKey>,
+    ) -> Box<Future<Item = (), Error = error::Error> + Send> {
+        let mut unsubscribers = Vec::with_capacity(keys.len());
+        let mut signals = Vec::with_capacity(keys.len());
+        for key in keys {
+            let (signal_t, signal_r) = oneshot::channel();
+            unsubscribers.push((key, Unsubscriber::All(signal_t)));
+            signals.push(signal_r.map_err(error::Error::from));
+        }
+        if self.out_tx
+            .unbounded_send(PubsubEvent::Unsubscribe(unsubscribers))
+            .is_err()
+        {
+            // If the connection has closed there is nothing to unsubscribe from
+            return Box::new(future::ok(()));
+        }
+
+        Box::new(future::join_all(signals).map(|_| ()))
     }
 }
 
-pub struct PubsubStream {
-    topic: String,
+/// The receiving end of a subscription, which is unsubscribed from when dropped.
+struct Subscription {
+    key: SubscriptionKey,
+    id: usize,
     underlying: PubsubStreamInner,
     con: PubsubConnection,
 }
 
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let event = PubsubEvent::Unsubscribe(vec![(self.key.clone(), Unsubscriber::Stream(self.id))]);
+        // If the connection has closed there is nothing to unsubscribe from
+        let _ = self.con.out_tx.unbounded_send(event);
+    }
+}
+
+impl Subscription {
+    fn dropped(&self) -> usize {
+        self.underlying.dropped()
+    }
+}
+
+impl Stream for Subscription {
+    type Item = PubsubItem;
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.underlying
+            .poll()
+            .map_err(|_| error::internal("Cannot poll subscription"))?
+        {
+            Async::Ready(Some(Ok(item))) => Ok(Async::Ready(Some(item))),
+            Async::Ready(Some(Err(e))) => Err(e),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// The messages published to a topic, see `PubsubConnection::subscribe`.
+pub struct PubsubStream {
+    subscription: Subscription,
+}
+
+impl PubsubStream {
+    /// Includes a `PubsubItem::Reconnected` notice in the stream each time the connection is re-established,
+    /// so subscribers know to resynchronise any state that depends on every message being received.
+    pub fn with_reconnect_notices(self) -> PubsubNoticeStream {
+        PubsubNoticeStream {
+            subscription: self.subscription,
+        }
+    }
+
+    /// The number of messages discarded so far because this stream wasn't keeping up, see
+    /// `ConnectionBuilder::pubsub_buffer`.
+    pub fn dropped(&self) -> usize {
+        self.subscription.dropped()
+    }
+}
+
 impl Stream for PubsubStream {
-    type Item = resp::RespValue;
-    type Error = ();
+    type Item = PubsubMessage;
+    type Error = error::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.underlying.poll()
+        loop {
+            match self.subscription.poll()? {
+                Async::Ready(Some(PubsubItem::Message(message))) => return Ok(Async::Ready(Some(message))),
+                Async::Ready(Some(PubsubItem::Reconnected)) => (),
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
     }
 }
 
-impl Drop for PubsubStream {
-    fn drop(&mut self) {
-        self.con.unsubscribe(self.topic.as_ref());
+/// A subscription that includes notices of reconnections, see `PubsubStream::with_reconnect_notices`.
+pub struct PubsubNoticeStream {
+    subscription: Subscription,
+}
+
+impl PubsubNoticeStream {
+    /// As `PubsubStream::dropped`.
+    pub fn dropped(&self) -> usize {
+        self.subscription.dropped()
+    }
+}
+
+impl Stream for PubsubNoticeStream {
+    type Item = PubsubItem;
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.subscription.poll()
+    }
+}
+
+/// Combines the streams for many subscriptions into one, see `PubsubConnection::subscribe_many`.
+///
+/// The stream ends once all of the streams have ended.  If one fails the error is returned, and the others carry
+/// on.
+pub struct MergedStream {
+    streams: Vec<PubsubStream>,
+
+    /// The stream to poll first, so that each gets a turn.
+    next: usize,
+}
+
+impl MergedStream {
+    pub fn new(streams: Vec<PubsubStream>) -> Self {
+        MergedStream {
+            streams: streams,
+            next: 0,
+        }
+    }
+
+    /// The total number of messages dropped by each of the streams, see `PubsubStream::dropped`.
+    pub fn dropped(&self) -> usize {
+        self.streams.iter().map(|stream| stream.dropped()).sum()
+    }
+}
+
+impl Stream for MergedStream {
+    type Item = PubsubMessage;
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut polled = 0;
+        while polled < self.streams.len() {
+            if self.next >= self.streams.len() {
+                self.next = 0;
+            }
+            let idx = self.next;
+            match self.streams[idx].poll() {
+                Ok(Async::Ready(Some(message))) => {
+                    self.next = idx + 1;
+                    return Ok(Async::Ready(Some(message)));
+                }
+                Ok(Async::Ready(None)) => {
+                    self.streams.remove(idx);
+                }
+                Ok(Async::NotReady) => {
+                    self.next = idx + 1;
+                    polled += 1;
+                }
+                Err(e) => {
+                    self.next = idx + 1;
+                    return Err(e);
+                }
+            }
+        }
+        if self.streams.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
     }
 }
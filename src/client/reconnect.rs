@@ -0,0 +1,178 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Settings for reconnecting after a connection to Redis is lost.
+
+use std::cmp;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+
+use tokio_timer::Delay;
+
+use error;
+use super::connect::RespConnection;
+
+pub(crate) type ConnectBox = Box<Future<Item = RespConnection, Error = error::Error> + Send>;
+
+/// How long to wait between attempts to reconnect.
+///
+/// The first attempt is made straight away, if that fails the next is made after the `initial` delay, which is
+/// then doubled after each failed attempt, up to `max`.  A connection that is lost again before it has been up
+/// for `max` also counts as a failed attempt, so a server that accepts connections then closes them straight
+/// away isn't reconnected to in a tight loop.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: Option<usize>,
+}
+
+impl Default for Backoff {
+    /// Starts at 100ms, rising to 10s, and never gives up.
+    fn default() -> Self {
+        Backoff::new(Duration::from_millis(100), Duration::from_secs(10))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial: initial,
+            max: max,
+            max_attempts: None,
+        }
+    }
+
+    /// Gives up after this many attempts in a row have failed, after which the connection is closed for good.
+    pub fn max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// The delay before the next attempt, given the number of attempts that have failed so far; or `None` if
+    /// it's time to give up.
+    pub(crate) fn delay(&self, failed: usize) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| failed >= max) {
+            return None;
+        }
+        let mut delay = self.initial;
+        for _ in 1..failed {
+            delay = match delay.checked_mul(2) {
+                Some(delay) if delay < self.max => delay,
+                _ => return Some(self.max),
+            };
+        }
+        Some(cmp::min(delay, self.max))
+    }
+}
+
+enum ReconnectState {
+    Idle,
+    Waiting(Delay),
+    Connecting(ConnectBox),
+}
+
+/// Re-establishes a lost connection, retrying as set by a `Backoff`.
+///
+/// This is a future that resolves to the new connection, or fails once the `Backoff` gives up.  Once
+/// resolved it can be polled again to reconnect again, starting from the first attempt unless the connection
+/// was lost soon after being made, see `Backoff`.
+pub(crate) struct Reconnect {
+    connect: Box<Fn() -> ConnectBox + Send>,
+    backoff: Backoff,
+
+    /// The number of attempts that have failed in a row.
+    failed_attempts: usize,
+
+    /// When the last successful attempt was made.
+    connected_at: Option<Instant>,
+
+    state: ReconnectState,
+}
+
+impl Reconnect {
+    /// Reconnects by calling `connect`.
+    pub(crate) fn new<F>(backoff: Backoff, connect: F) -> Self
+    where
+        F: Fn() -> ConnectBox + Send + 'static,
+    {
+        Reconnect {
+            connect: Box::new(connect),
+            backoff: backoff,
+            failed_attempts: 0,
+            connected_at: None,
+            state: ReconnectState::Idle,
+        }
+    }
+
+    /// Waits before the next attempt, after one has failed; or fails if the `Backoff` gives up.
+    fn retry(&mut self, e: &fmt::Display) -> Result<ReconnectState, ()> {
+        self.failed_attempts += 1;
+        match self.backoff.delay(self.failed_attempts) {
+            Some(delay) => {
+                warn!("Cannot reconnect to Redis, retrying in {:?}: {}", delay, e);
+                Ok(ReconnectState::Waiting(Delay::new(Instant::now() + delay)))
+            }
+            None => {
+                error!("Cannot reconnect to Redis, giving up: {}", e);
+                self.failed_attempts = 0;
+                self.connected_at = None;
+                Err(())
+            }
+        }
+    }
+}
+
+impl Future for Reconnect {
+    type Item = RespConnection;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next_state = match self.state {
+                ReconnectState::Idle => match self.connected_at.take() {
+                    Some(connected_at) if connected_at.elapsed() < self.backoff.max => {
+                        match self.retry(&"Connection lost straight after reconnecting") {
+                            Ok(next_state) => next_state,
+                            Err(()) => return Err(()),
+                        }
+                    }
+                    _ => {
+                        self.failed_attempts = 0;
+                        ReconnectState::Connecting((self.connect)())
+                    }
+                },
+                ReconnectState::Waiting(ref mut delay) => match delay.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(())) | Err(_) => ReconnectState::Connecting((self.connect)()),
+                },
+                ReconnectState::Connecting(ref mut connection_f) => match connection_f.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(connection)) => {
+                        info!("Reconnected to Redis");
+                        self.state = ReconnectState::Idle;
+                        self.connected_at = Some(Instant::now());
+                        return Ok(Async::Ready(connection));
+                    }
+                    Err(e) => match self.retry(&e) {
+                        Ok(next_state) => next_state,
+                        Err(()) => {
+                            self.state = ReconnectState::Idle;
+                            return Err(());
+                        }
+                    },
+                },
+            };
+            self.state = next_state;
+        }
+    }
+}
@@ -0,0 +1,329 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Redis Sentinel, finding the master of a group of servers, and following it when it fails over.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use futures::{future::{self, Loop}, Future, Stream, sync::oneshot};
+
+use tokio_executor::{DefaultExecutor, Executor};
+use tokio_timer::Delay;
+
+use error;
+use resp::{self, FromResp};
+use super::builder::ConnectionBuilder;
+use super::paired::{paired_connect, PairedConnection, SendBox};
+use super::pubsub::{pubsub_connect, PubsubMessage};
+use super::reconnect::Backoff;
+
+/// The channel sentinels announce failovers on.
+const SWITCH_MASTER: &str = "+switch-master";
+
+/// How long to wait before subscribing to `+switch-master` again, if the subscription is lost.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+struct Config {
+    builder: ConnectionBuilder,
+    sentinels: Vec<SocketAddr>,
+    master_name: String,
+}
+
+struct Master {
+    addr: SocketAddr,
+    connection: PairedConnection,
+}
+
+struct Shared {
+    master: Mutex<Master>,
+
+    /// Stops the task following failovers when dropped.
+    _stop: oneshot::Sender<()>,
+}
+
+impl Shared {
+    fn master(&self) -> MutexGuard<'_, Master> {
+        self.master.lock().expect("Poisoned master")
+    }
+}
+
+/// Parses a host and port given by a sentinel.  Only an IP address is accepted, as resolving a hostname would
+/// block the thread, so sentinels must not be set with `resolve-hostnames` and `announce-hostnames`.
+fn master_addr(host: &str, port: &str) -> Result<SocketAddr, error::Error> {
+    let port: u16 = port.parse()
+        .map_err(|_| error::internal(format!("Invalid port from sentinel: {}", port)))?;
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, port)),
+        Err(_) => Err(error::Error::Connection(format!(
+            "Master has a hostname, not an IP address: {}",
+            host
+        ))),
+    }
+}
+
+/// Asks the sentinel at `sentinel` for the address of the master, with `SENTINEL get-master-addr-by-name`.
+fn ask_sentinel(sentinel: &SocketAddr, master_name: &str) -> SendBox<SocketAddr> {
+    let master_name = master_name.to_string();
+    Box::new(paired_connect(sentinel).and_then(move |connection| {
+        connection
+            .send::<Option<(String, String)>>(resp_array![
+                "SENTINEL",
+                "get-master-addr-by-name",
+                master_name.as_str()
+            ])
+            .and_then(move |reply| match reply {
+                Some((host, port)) => master_addr(&host, &port),
+                None => Err(error::Error::Connection(format!(
+                    "Sentinel doesn't know master: {}",
+                    master_name
+                ))),
+            })
+    }))
+}
+
+/// Connects to the server at `addr`, checking with `ROLE` that it is a master, as a sentinel's answer may be
+/// out of date, e.g. if it hasn't yet seen a failover.
+fn connect_master(builder: &ConnectionBuilder, addr: SocketAddr) -> SendBox<PairedConnection> {
+    Box::new(builder.with_addr(&addr).paired_connect().and_then(move |connection| {
+        connection
+            .send::<Vec<resp::RespValue>>(resp_array!["ROLE"])
+            .and_then(move |role| match role.into_iter().next().map(String::from_resp) {
+                Some(Ok(ref role)) if role == "master" => Ok(connection),
+                Some(Ok(role)) => Err(error::Error::Connection(format!(
+                    "{} is a {}, not a master",
+                    addr, role
+                ))),
+                _ => Err(error::internal("Unexpected reply to ROLE")),
+            })
+    }))
+}
+
+/// Asks each sentinel in turn for the master, until one gives an address that is confirmed to be a master.
+/// Resolves to the index of that sentinel, the address, and a connection to it.
+fn find_master(config: Arc<Config>) -> SendBox<(usize, SocketAddr, PairedConnection)> {
+    Box::new(future::loop_fn(0, move |idx| -> SendBox<Loop<_, usize>> {
+        let sentinel = match config.sentinels.get(idx) {
+            Some(sentinel) => *sentinel,
+            None => {
+                return Box::new(future::err(error::Error::Connection(format!(
+                    "No sentinel gave the address of master: {}",
+                    config.master_name
+                ))))
+            }
+        };
+        let connect_config = config.clone();
+        Box::new(
+            ask_sentinel(&sentinel, &config.master_name)
+                .and_then(move |addr| {
+                    connect_master(&connect_config.builder, addr).map(move |connection| (addr, connection))
+                })
+                .then(move |result| match result {
+                    Ok((addr, connection)) => Ok(Loop::Break((idx, addr, connection))),
+                    Err(e) => {
+                        warn!("Cannot find master through sentinel {}: {}", sentinel, e);
+                        Ok(Loop::Continue(idx + 1))
+                    }
+                }),
+        )
+    }))
+}
+
+/// The new address of the master, if `message` announces that it failed over.  The payload is the name of the
+/// master, followed by the old and new host and port.
+fn switched_to(message: &PubsubMessage, master_name: &str) -> Option<SocketAddr> {
+    let payload: String = message.decode().ok()?;
+    let parts = payload.split(' ').collect::<Vec<_>>();
+    if parts.len() != 5 || parts[0] != master_name {
+        return None;
+    }
+    master_addr(parts[3], parts[4]).ok()
+}
+
+/// Uses the master at `addr`, unless it's already being used.  If it can't be connected to, or isn't a master
+/// (e.g. it hasn't finished being promoted), the sentinels are asked for the master again, after a delay that
+/// rises as set by `Backoff::default`, until a master is confirmed or the connection is dropped.
+fn switch(shared: Weak<Shared>, config: Arc<Config>, addr: SocketAddr) -> SendBox<()> {
+    match shared.upgrade() {
+        Some(ref shared) if shared.master().addr != addr => (),
+        _ => return Box::new(future::ok(())),
+    }
+    let backoff = Backoff::default();
+    Box::new(future::loop_fn(0, move |failed| -> SendBox<Loop<(), usize>> {
+        if shared.upgrade().is_none() {
+            return Box::new(future::ok(Loop::Break(())));
+        }
+        let connect_f: SendBox<(SocketAddr, PairedConnection)> = if failed == 0 {
+            Box::new(connect_master(&config.builder, addr).map(move |connection| (addr, connection)))
+        } else {
+            Box::new(find_master(config.clone()).map(|(_, addr, connection)| (addr, connection)))
+        };
+        let shared = shared.clone();
+        let master_name = config.master_name.clone();
+        let delay = backoff.delay(failed + 1).unwrap_or(RESUBSCRIBE_DELAY);
+        Box::new(connect_f.then(move |result| -> SendBox<Loop<(), usize>> {
+            match result {
+                Ok((addr, connection)) => {
+                    if let Some(shared) = shared.upgrade() {
+                        info!("Master {} is now at {}", master_name, addr);
+                        *shared.master() = Master {
+                            addr: addr,
+                            connection: connection,
+                        };
+                    }
+                    Box::new(future::ok(Loop::Break(())))
+                }
+                Err(e) => {
+                    warn!("Cannot connect to new master {}, retrying in {:?}: {}", master_name, delay, e);
+                    Box::new(
+                        Delay::new(Instant::now() + delay)
+                            .then(move |_| Ok(Loop::Continue(failed + 1))),
+                    )
+                }
+            }
+        }))
+    }))
+}
+
+/// Spawns a task that subscribes to `+switch-master` through the sentinel at index `first`, switching to the
+/// new master on each failover, until `stop` is dropped.  If the subscription is lost, another sentinel is
+/// subscribed to, and asked for the master in case a failover was missed.
+fn follow_failovers(
+    shared: Weak<Shared>,
+    config: Arc<Config>,
+    first: usize,
+    stop: oneshot::Receiver<()>,
+) -> Result<(), error::Error> {
+    let follow_f = future::loop_fn((first, false), move |(idx, missed)| {
+        let sentinel = config.sentinels[idx % config.sentinels.len()];
+        let check_config = config.clone();
+        let check_shared = shared.clone();
+        let switch_config = config.clone();
+        let switch_shared = shared.clone();
+        pubsub_connect(&sentinel)
+            .and_then(|pubsub| pubsub.subscribe(SWITCH_MASTER).map(move |messages| (pubsub, messages)))
+            .and_then(move |(pubsub, messages)| -> SendBox<_> {
+                if !missed {
+                    return Box::new(future::ok((pubsub, messages)));
+                }
+                Box::new(
+                    ask_sentinel(&sentinel, &check_config.master_name)
+                        .and_then(move |addr| switch(check_shared, check_config, addr))
+                        .map(move |()| (pubsub, messages)),
+                )
+            })
+            .and_then(move |(pubsub, messages)| {
+                messages
+                    .for_each(move |message| match switched_to(&message, &switch_config.master_name) {
+                        Some(addr) => switch(switch_shared.clone(), switch_config.clone(), addr),
+                        None => Box::new(future::ok(())),
+                    })
+                    .then(move |result| {
+                        // The connection is kept until the subscription ends
+                        drop(pubsub);
+                        result
+                    })
+            })
+            .then(move |result| {
+                if let Err(e) = result {
+                    warn!("Lost subscription to {} on sentinel {}: {}", SWITCH_MASTER, sentinel, e);
+                }
+                Delay::new(Instant::now() + RESUBSCRIBE_DELAY)
+                    .then(move |_| Ok::<_, error::Error>(Loop::<(), _>::Continue((idx + 1, true))))
+            })
+    });
+    let task = follow_f.select2(stop).then(|_: Result<_, _>| Ok(()));
+    let mut executor = DefaultExecutor::current();
+    executor
+        .spawn(Box::new(task))
+        .map_err(|e| error::internal(format!("Cannot spawn sentinel task: {:?}", e)))
+}
+
+/// A connection to the master of a group of Redis servers monitored by Redis Sentinel, made with
+/// `sentinel_connect` or `ConnectionBuilder::sentinel_connect`.
+///
+/// The sentinels are asked for the master, which is connected to as a `PairedConnection`.  A sentinel is also
+/// subscribed to, and when it announces a failover the new master is connected to and used instead.  Commands
+/// in flight during a failover may fail, e.g. with `error::Error::Connection`, or a `READONLY` error from the
+/// old master.  Clones share the same connection.
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate redis_async;
+/// # extern crate futures;
+/// # use futures::Future;
+/// # use redis_async::client;
+/// # fn main() {
+/// let sentinels = vec!["10.0.0.1:26379".parse().unwrap(), "10.0.0.2:26379".parse().unwrap()];
+/// let value_f = client::sentinel_connect(sentinels, "mymaster")
+///     .and_then(|connection| connection.send::<Option<String>>(resp_array!["GET", "key"]));
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SentinelConnection {
+    shared: Arc<Shared>,
+}
+
+pub(crate) fn connect(
+    builder: ConnectionBuilder,
+    sentinels: Vec<SocketAddr>,
+    master_name: String,
+) -> Box<Future<Item = SentinelConnection, Error = error::Error> + Send> {
+    assert!(!sentinels.is_empty(), "At least one sentinel is needed");
+    let config = Arc::new(Config {
+        builder: builder,
+        sentinels: sentinels,
+        master_name: master_name,
+    });
+    Box::new(find_master(config.clone()).and_then(move |(idx, addr, connection)| {
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let shared = Arc::new(Shared {
+            master: Mutex::new(Master {
+                addr: addr,
+                connection: connection,
+            }),
+            _stop: stop_tx,
+        });
+        follow_failovers(Arc::downgrade(&shared), config, idx, stop_rx)?;
+        Ok(SentinelConnection { shared: shared })
+    }))
+}
+
+/// Connects to the master called `master_name`, asking each of `sentinels` in turn for its address, with no
+/// further setup, see `ConnectionBuilder::sentinel_connect`.
+///
+/// # Panics
+///
+/// If there are no sentinels.
+pub fn sentinel_connect<T: Into<String>>(
+    sentinels: Vec<SocketAddr>,
+    master_name: T,
+) -> Box<Future<Item = SentinelConnection, Error = error::Error> + Send> {
+    assert!(!sentinels.is_empty(), "At least one sentinel is needed");
+    ConnectionBuilder::new(&sentinels[0]).sentinel_connect(sentinels, master_name)
+}
+
+impl SentinelConnection {
+    /// Sends a command to the current master.  Otherwise the same as `PairedConnection::send`.
+    pub fn send<T: resp::FromResp + Send + 'static>(&self, msg: resp::RespValue) -> SendBox<T> {
+        self.connection().send(msg)
+    }
+
+    /// The connection to the current master, e.g. for a transaction or pipeline.  It isn't replaced after a
+    /// failover, so it should be fetched again for each use rather than kept.
+    pub fn connection(&self) -> PairedConnection {
+        self.shared.master().connection.clone()
+    }
+
+    /// The address of the current master.
+    pub fn master_addr(&self) -> SocketAddr {
+        self.shared.master().addr
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Transactions, with `MULTI` and `EXEC`.
+
+use futures::{Future, sync::oneshot};
+
+use error;
+use resp::{self, FromResp};
+use super::paired::{PairedConnection, SendBox};
+
+type CommandResult = Result<resp::RespValue, error::Error>;
+
+/// Commands to be run by Redis as a single transaction, see `PairedConnection::transaction`.
+///
+/// Nothing is sent until `exec` is called, then `MULTI`, each command and `EXEC` are sent together, so no
+/// commands sent by other clones of the connection can become part of the transaction.
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate redis_async;
+/// # extern crate futures;
+/// # use futures::Future;
+/// # use redis_async::client;
+/// # fn main() {
+/// let addr = "127.0.0.1:6379".parse().unwrap();
+/// let result_f = client::paired_connect(&addr).and_then(|connection| {
+///     let mut transaction = connection.transaction();
+///     let count = transaction.send::<i64>(resp_array!["INCR", "counter"]);
+///     transaction.send::<()>(resp_array!["SET", "updated", "yes"]);
+///     transaction.exec().and_then(|()| count)
+/// });
+/// # }
+/// ```
+pub struct Transaction {
+    connection: PairedConnection,
+    commands: Vec<resp::RespValue>,
+    results: Vec<oneshot::Sender<CommandResult>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(connection: PairedConnection) -> Self {
+        Transaction {
+            connection: connection,
+            commands: Vec::new(),
+            results: Vec::new(),
+        }
+    }
+
+    /// Adds a command to the transaction.
+    ///
+    /// Returns a future that resolves to the command's result, once the transaction has been executed.  If the
+    /// transaction fails, or is dropped without being executed, so does the future.
+    pub fn send<T: FromResp + Send + 'static>(&mut self, msg: resp::RespValue) -> SendBox<T> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.push(msg);
+        self.results.push(tx);
+        Box::new(rx.then(|result| match result {
+            Ok(Ok(value)) => T::from_resp(value),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(error::internal("Transaction was dropped without being executed")),
+        }))
+    }
+
+    /// Sends the transaction to Redis.
+    ///
+    /// Returns a future that resolves once `EXEC` succeeds, even if some of the commands failed when they were
+    /// run, their futures fail with `error::Error::Remote`.  If the transaction is not run at all, this future
+    /// and every command's fails: with `error::Error::ExecAbort` if a command was rejected when it was queued,
+    /// or `error::Error::TransactionAborted` if a watched key changed.
+    pub fn exec(self) -> SendBox<()> {
+        let Transaction {
+            connection,
+            mut commands,
+            results,
+        } = self;
+        let count = commands.len();
+        commands.insert(0, resp_array!["MULTI"]);
+        commands.push(resp_array!["EXEC"]);
+        Box::new(connection.send_all(commands).then(move |replies| {
+            match replies.and_then(|replies| exec_results(replies, count)) {
+                Ok(values) => {
+                    for (tx, value) in results.into_iter().zip(values) {
+                        let _ = tx.send(Ok(value));
+                    }
+                    Ok(())
+                }
+                Err(e) => {
+                    for tx in results {
+                        let _ = tx.send(Err(duplicate(&e)));
+                    }
+                    Err(e)
+                }
+            }
+        }))
+    }
+}
+
+/// The result of each command in a transaction, from the replies to `MULTI`, each command, and `EXEC`.
+fn exec_results(mut replies: Vec<CommandResult>, count: usize) -> Result<Vec<resp::RespValue>, error::Error> {
+    let exec = replies.pop().expect("No reply to EXEC")?;
+    let mut replies = replies.into_iter();
+    if let resp::RespValue::Error(e) = replies.next().expect("No reply to MULTI")? {
+        return Err(error::Error::Remote(e));
+    }
+    match exec {
+        resp::RespValue::Array(values) => if values.len() == count {
+            Ok(values)
+        } else {
+            Err(error::resp("Unexpected reply to EXEC", resp::RespValue::Array(values)))
+        },
+        resp::RespValue::Nil => Err(error::Error::TransactionAborted),
+        resp::RespValue::Error(ref e) if e.starts_with("EXECABORT") => {
+            // The reply to the command that was rejected is more useful than EXECABORT's
+            let reason = replies
+                .filter_map(|reply| match reply {
+                    Ok(resp::RespValue::Error(reason)) => Some(reason),
+                    _ => None,
+                })
+                .next()
+                .unwrap_or_else(|| e.clone());
+            Err(error::Error::ExecAbort(reason))
+        }
+        resp::RespValue::Error(e) => Err(error::Error::Remote(e)),
+        exec => Err(error::resp("Unexpected reply to EXEC", exec)),
+    }
+}
+
+/// A copy of an error, to fail each command's future as well as `exec`'s.
+fn duplicate(e: &error::Error) -> error::Error {
+    match *e {
+        error::Error::Remote(ref s) => error::Error::Remote(s.clone()),
+        error::Error::Connection(ref s) => error::Error::Connection(s.clone()),
+        error::Error::ExecAbort(ref s) => error::Error::ExecAbort(s.clone()),
+        error::Error::TransactionAborted => error::Error::TransactionAborted,
+        ref e => error::internal(format!("Transaction failed: {}", e)),
+    }
+}
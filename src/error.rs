@@ -27,9 +27,35 @@ pub enum Error {
     /// A RESP parsing/serialising error occurred
     RESP(String, Option<resp::RespValue>),
 
+    /// A frame received from the server exceeded one of the limits configured on the `RespCodec`
+    LimitExceeded(String),
+
     /// A remote error
     Remote(String),
 
+    /// The connection to Redis was lost, or is not currently available.  Commands that were in flight when the
+    /// connection was lost fail with this error, as it is unknown whether Redis processed them or not.
+    Connection(String),
+
+    /// A command sent while setting up a connection, e.g. `AUTH` or `SELECT`, failed.  Contains the name of the
+    /// command and the reason it failed.
+    Setup(String, Box<Error>),
+
+    /// A PUBSUB subscriber didn't keep up with the messages published, and was unsubscribed, see
+    /// `SlowConsumerPolicy::Disconnect`.
+    Lagged(String),
+
+    /// A transaction was discarded by `EXEC`, as a command was rejected when it was queued, e.g. for having the
+    /// wrong number of arguments.  Contains the reason the command was rejected.
+    ExecAbort(String),
+
+    /// A transaction was aborted by `EXEC`, as a key that was being watched with `WATCH` changed.
+    TransactionAborted,
+
+    /// A command's keys are in different hash slots of a Redis Cluster, so no one node can run it.  Keys that
+    /// share a `{hashtag}` are always in the same slot.
+    CrossSlot(String),
+
     /// End of stream - not necesserially an error if you're anticipating it
     EndOfStream,
 
@@ -75,7 +101,14 @@ impl error::Error for Error {
             Error::Internal(ref s) => s,
             Error::IO(ref err) => err.description(),
             Error::RESP(ref s, _) => s,
+            Error::LimitExceeded(ref s) => s,
             Error::Remote(ref s) => s,
+            Error::Connection(ref s) => s,
+            Error::Setup(_, ref err) => err.description(),
+            Error::Lagged(ref s) => s,
+            Error::ExecAbort(ref s) => s,
+            Error::TransactionAborted => "Transaction aborted, a watched key changed",
+            Error::CrossSlot(ref s) => s,
             Error::EndOfStream => "End of Stream",
             Error::Unexpected(ref err) => err,
         }
@@ -86,7 +119,14 @@ impl error::Error for Error {
             Error::Internal(_) => None,
             Error::IO(ref err) => Some(err),
             Error::RESP(_, _) => None,
+            Error::LimitExceeded(_) => None,
             Error::Remote(_) => None,
+            Error::Connection(_) => None,
+            Error::Setup(_, ref err) => Some(&**err),
+            Error::Lagged(_) => None,
+            Error::ExecAbort(_) => None,
+            Error::TransactionAborted => None,
+            Error::CrossSlot(_) => None,
             Error::EndOfStream => None,
             Error::Unexpected(_) => None,
         }
@@ -96,6 +136,9 @@ impl error::Error for Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use std::error::Error;
-        fmt::Display::fmt(self.description(), f)
+        match *self {
+            self::Error::Setup(ref name, ref err) => write!(f, "{} failed: {}", name, err),
+            _ => fmt::Display::fmt(self.description(), f),
+        }
     }
 }
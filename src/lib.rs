@@ -63,6 +63,7 @@ extern crate tokio;
 extern crate tokio_executor;
 extern crate tokio_io;
 extern crate tokio_tcp;
+extern crate tokio_timer;
 
 #[macro_use]
 pub mod resp;
@@ -71,3 +72,6 @@ pub mod resp;
 pub mod client;
 
 pub mod error;
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
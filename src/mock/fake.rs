@@ -0,0 +1,152 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! A server that follows a script, for testing how clients cope with faults.
+
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::Duration;
+
+use bytes::BytesMut;
+
+use resp::{Command, RespServerCodec, RespValue};
+
+use super::{read_command, write_replies, Listener};
+
+/// One step of the script followed by a connection to a `FakeServer`.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Wait for the client to send this many commands.
+    Read(usize),
+
+    /// Send a value, this could be a reply, an error, or a PUBSUB message.
+    Send(RespValue),
+
+    /// Send raw bytes, e.g. garbage or part of a frame.
+    Raw(Vec<u8>),
+
+    /// Pause before the next action.
+    Delay(Duration),
+
+    /// Close the connection.
+    Close,
+
+    /// Stop reading from the connection, but don't close it, until the server is dropped.  A client sending
+    /// more than fits in the socket's buffers will experience backpressure.
+    Stall,
+}
+
+/// A server which follows a script for each connection, in order to misbehave in a controlled way.
+///
+/// Once a connection's script has finished, the connection is kept open and any further commands are recorded,
+/// but not replied to.
+pub struct FakeServer {
+    listener: Listener,
+    received: Arc<Mutex<Vec<Vec<Command>>>>,
+}
+
+impl FakeServer {
+    /// Starts a server where the first connection follows the first script, the second connection the second
+    /// script, and so on.  Any connections beyond the number of scripts are closed straight away.
+    pub fn start(scripts: Vec<Vec<Action>>) -> io::Result<FakeServer> {
+        FakeServer::start_with(|_| scripts)
+    }
+
+    /// As `start`, but the scripts are made once the server's address is known, for scripts that send it, e.g.
+    /// in a `CLUSTER SLOTS` reply.
+    pub fn start_with<F>(scripts: F) -> io::Result<FakeServer>
+    where
+        F: FnOnce(SocketAddr) -> Vec<Vec<Action>>,
+    {
+        let shared_scripts: Arc<Mutex<Vec<Vec<Action>>>> = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let handler_scripts = shared_scripts.clone();
+        let handler_received = received.clone();
+        let listener = Listener::start(move |idx, stream, shutdown| {
+            let script = handler_scripts
+                .lock()
+                .expect("Poisoned scripts")
+                .get(idx)
+                .cloned();
+            match script {
+                Some(script) => {
+                    follow_script(idx, stream, &script, &handler_received, &shutdown);
+                }
+                None => {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+        })?;
+        // Nothing can have connected yet, as the address wasn't known
+        let scripts = scripts(listener.addr);
+        *received.lock().expect("Poisoned commands") = vec![Vec::new(); scripts.len()];
+        *shared_scripts.lock().expect("Poisoned scripts") = scripts;
+        Ok(FakeServer {
+            listener: listener,
+            received: received,
+        })
+    }
+
+    /// The address to connect to.
+    pub fn addr(&self) -> SocketAddr {
+        self.listener.addr
+    }
+
+    /// The commands received so far on each connection, one entry per script.
+    pub fn received(&self) -> Vec<Vec<Command>> {
+        self.received.lock().expect("Poisoned commands").clone()
+    }
+}
+
+fn follow_script(
+    idx: usize,
+    mut stream: TcpStream,
+    script: &[Action],
+    received: &Mutex<Vec<Vec<Command>>>,
+    shutdown: &AtomicBool,
+) {
+    let mut buf = BytesMut::new();
+    let mut codec = RespServerCodec::default();
+    let mut read = |stream: &mut TcpStream| match read_command(stream, &mut buf, &mut codec) {
+        Ok(Some(command)) => {
+            received.lock().expect("Poisoned commands")[idx].push(command);
+            true
+        }
+        _ => false,
+    };
+
+    for action in script {
+        let ok = match *action {
+            Action::Read(count) => (0..count).all(|_| read(&mut stream)),
+            Action::Send(ref value) => write_replies(&mut stream, vec![value.clone()]).is_ok(),
+            Action::Raw(ref bytes) => stream.write_all(bytes).is_ok(),
+            Action::Delay(duration) => {
+                thread::sleep(duration);
+                true
+            }
+            Action::Close => false,
+            Action::Stall => {
+                while !shutdown.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                false
+            }
+        };
+        if !ok {
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+
+    while read(&mut stream) {}
+    let _ = stream.shutdown(Shutdown::Both);
+}
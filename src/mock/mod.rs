@@ -0,0 +1,136 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Test support, available with the `mock` feature.
+//!
+//! Two servers are provided, both listen on a random local port:
+//!
+//! * [`MockServer`](server/struct.MockServer.html) speaks RESP and implements an in-memory subset of Redis's
+//!   commands, this allows code using this crate to be tested without a running Redis server.
+//! * [`FakeServer`](fake/struct.FakeServer.html) follows a script for each connection, this can be used to
+//!   test how clients behave when a server misbehaves: replying late, sending garbage, closing connections, etc.
+//!
+//! The servers run on their own threads, using blocking IO, so they are independent of whichever Tokio
+//! runtime the code being tested uses.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::thread;
+
+use bytes::BytesMut;
+
+use tokio_io::codec::{Decoder, Encoder};
+
+use resp::{Command, RespServerCodec, RespValue};
+
+pub mod fake;
+pub mod server;
+
+pub use self::fake::{Action, FakeServer};
+pub use self::server::MockServer;
+
+/// The parts common to all servers: accepting connections on a background thread, and closing them all when
+/// dropped.
+struct Listener {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Listener {
+    /// Starts listening, `handler` is called on a new thread for each connection, with the number of
+    /// connections accepted before it.
+    fn start<F>(handler: F) -> io::Result<Listener>
+    where
+        F: Fn(usize, TcpStream, Arc<AtomicBool>) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let streams = Arc::new(Mutex::new(Vec::new()));
+
+        let handler = Arc::new(handler);
+        let accept_shutdown = shutdown.clone();
+        let accept_streams = streams.clone();
+        thread::spawn(move || {
+            for (idx, stream) in listener.incoming().enumerate() {
+                if accept_shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match stream.and_then(|s| s.try_clone().map(|clone| (s, clone))) {
+                    Ok((stream, clone)) => {
+                        let _ = stream.set_nodelay(true);
+                        accept_streams.lock().expect("Poisoned streams").push(clone);
+                        stream
+                    }
+                    Err(e) => {
+                        error!("Mock server cannot accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let handler = handler.clone();
+                let shutdown = accept_shutdown.clone();
+                thread::spawn(move || handler(idx, stream, shutdown));
+            }
+        });
+
+        Ok(Listener {
+            addr: addr,
+            shutdown: shutdown,
+            streams: streams,
+        })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Ok(streams) = self.streams.lock() {
+            for stream in streams.iter() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        // Wake the thread waiting to accept connections, so it notices the shutdown
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+/// Reads the next command from a client, returns `None` when the client has closed the connection.
+fn read_command(
+    stream: &mut TcpStream,
+    buf: &mut BytesMut,
+    codec: &mut RespServerCodec,
+) -> io::Result<Option<Command>> {
+    let mut read_buf = [0; 4096];
+    loop {
+        if let Some(command) = codec
+            .decode(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+        {
+            return Ok(Some(command));
+        }
+        let read = stream.read(&mut read_buf)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&read_buf[..read]);
+    }
+}
+
+/// Writes one or more replies to a client, in a single write.
+fn write_replies(stream: &mut TcpStream, replies: Vec<RespValue>) -> io::Result<()> {
+    let mut codec = RespServerCodec::default();
+    let mut buf = BytesMut::new();
+    for reply in replies {
+        codec.encode(reply, &mut buf)?;
+    }
+    stream.write_all(&buf)
+}
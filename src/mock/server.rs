@@ -0,0 +1,928 @@
+/*
+ * Copyright 2018 Ben Ashford
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! An in-memory imitation of a Redis server.
+
+use std::collections::{HashMap, HashSet, VecDeque, hash_map::Entry};
+use std::io;
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use bytes::{Bytes, BytesMut};
+
+use resp::{Command, FromResp, RespServerCodec, RespValue};
+
+use super::{read_command, write_replies, Listener};
+
+const DATABASES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Set(HashSet<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+}
+
+type Db = HashMap<Bytes, Value>;
+
+/// The result of a single command, an `Err` is sent to the client as a RESP error.
+type Reply = Result<RespValue, RespValue>;
+
+/// State shared between all connections.
+struct State {
+    dbs: Vec<Db>,
+
+    /// Each client's queue of replies and published messages, written to its socket by a thread of its own, so
+    /// a client that stops reading doesn't hold up the others.
+    clients: HashMap<usize, mpsc::Sender<Vec<RespValue>>>,
+
+    /// The IDs of the clients subscribed to each channel.
+    channels: HashMap<Bytes, HashSet<usize>>,
+
+    /// The IDs of the clients subscribed to each pattern.
+    patterns: HashMap<Bytes, HashSet<usize>>,
+
+    /// The IDs of the clients subscribed to each shard channel.
+    shard_channels: HashMap<Bytes, HashSet<usize>>,
+}
+
+/// State specific to one connection.
+struct Client {
+    id: usize,
+    db: usize,
+
+    /// Set by `CLIENT SETNAME`.
+    name: Option<Bytes>,
+
+    /// The commands queued since `MULTI`.
+    multi: Option<Vec<(String, Vec<Bytes>)>>,
+
+    /// Set if an invalid command was queued, the transaction will fail on `EXEC`.
+    multi_failed: bool,
+
+    /// The keys watched by `WATCH`, by database, with their values at the time.  The transaction is aborted on
+    /// `EXEC` if any have changed.
+    watched: Vec<(usize, Bytes, Option<Value>)>,
+
+    channels: HashSet<Bytes>,
+    patterns: HashSet<Bytes>,
+    shard_channels: HashSet<Bytes>,
+}
+
+impl Client {
+    fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    fn subscribed(&mut self, kind: Kind) -> &mut HashSet<Bytes> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+}
+
+/// Subscriptions are either to a channel, by name, to every channel matching a pattern, or to a shard channel.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+impl Kind {
+    fn subscribe_reply(self) -> &'static str {
+        match self {
+            Kind::Channel => "subscribe",
+            Kind::Pattern => "psubscribe",
+            Kind::Shard => "ssubscribe",
+        }
+    }
+
+    fn unsubscribe_reply(self) -> &'static str {
+        match self {
+            Kind::Channel => "unsubscribe",
+            Kind::Pattern => "punsubscribe",
+            Kind::Shard => "sunsubscribe",
+        }
+    }
+}
+
+/// A local server that implements a subset of Redis's commands, storing data in memory.
+///
+/// The supported commands are:
+///
+/// * Connection: `PING`, `ECHO`, `SELECT`, `QUIT`, `AUTH` (always failing, as no password is configured),
+///   `CLIENT SETNAME`, `CLIENT GETNAME`
+/// * Keys and databases: `DEL`, `EXISTS`, `TYPE`, `DBSIZE`, `FLUSHDB`, `FLUSHALL`
+/// * Strings: `GET`, `SET` (with `NX` or `XX`, but not expiry), `SETNX`, `GETSET`, `MGET`, `MSET`, `INCR`,
+///   `INCRBY`, `DECR`, `DECRBY`, `APPEND`, `STRLEN`
+/// * Lists: `LPUSH`, `RPUSH`, `LPOP`, `RPOP`, `LLEN`, `LRANGE`, `LINDEX`
+/// * Sets: `SADD`, `SREM`, `SMEMBERS`, `SISMEMBER`, `SCARD`
+/// * Hashes: `HSET`, `HGET`, `HMGET`, `HDEL`, `HEXISTS`, `HGETALL`, `HLEN`, `HKEYS`, `HVALS`, `HINCRBY`
+/// * PUBSUB: `PUBLISH`, `SUBSCRIBE`, `UNSUBSCRIBE`, `PSUBSCRIBE`, `PUNSUBSCRIBE`, `SPUBLISH`, `SSUBSCRIBE`,
+///   `SUNSUBSCRIBE` (as there is only one node, every shard channel is on it)
+/// * Transactions: `MULTI`, `EXEC`, `DISCARD`, `WATCH`, `UNWATCH` (a watched key counts as modified only if its
+///   value has changed)
+///
+/// The server stops, and closes all connections, when dropped.
+pub struct MockServer {
+    listener: Listener,
+}
+
+impl MockServer {
+    /// Starts a server listening on a random port on the loopback interface.
+    pub fn start() -> io::Result<MockServer> {
+        let state = Arc::new(Mutex::new(State {
+            dbs: (0..DATABASES).map(|_| HashMap::new()).collect(),
+            clients: HashMap::new(),
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+            shard_channels: HashMap::new(),
+        }));
+        let listener = Listener::start(move |id, stream, _| {
+            handle_connection(id, stream, state.clone())
+        })?;
+        Ok(MockServer { listener: listener })
+    }
+
+    /// The address to connect to.
+    pub fn addr(&self) -> SocketAddr {
+        self.listener.addr
+    }
+}
+
+/// Writes each batch of replies sent to `replies` to a client, until the client is removed from the state.
+fn write_queued(mut stream: TcpStream, replies: mpsc::Receiver<Vec<RespValue>>) {
+    for batch in replies {
+        if write_replies(&mut stream, batch).is_err() {
+            break;
+        }
+    }
+}
+
+fn handle_connection(id: usize, mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let writer = match stream.try_clone() {
+        Ok(clone) => {
+            let (tx, rx) = mpsc::channel();
+            state.lock().expect("Poisoned state").clients.insert(id, tx);
+            thread::spawn(move || write_queued(clone, rx))
+        }
+        Err(e) => {
+            error!("MockServer cannot clone socket: {}", e);
+            return;
+        }
+    };
+    let mut client = Client {
+        id: id,
+        db: 0,
+        name: None,
+        multi: None,
+        multi_failed: false,
+        watched: Vec::new(),
+        channels: HashSet::new(),
+        patterns: HashSet::new(),
+        shard_channels: HashSet::new(),
+    };
+
+    let mut buf = BytesMut::new();
+    let mut codec = RespServerCodec::default();
+    loop {
+        let command = match read_command(&mut stream, &mut buf, &mut codec) {
+            Ok(Some(command)) => command,
+            Ok(None) => break,
+            Err(e) => {
+                let state = state.lock().expect("Poisoned state");
+                if let Some(replies) = state.clients.get(&client.id) {
+                    let _ = replies.send(vec![RespValue::Error(format!("ERR {}", e))]);
+                }
+                break;
+            }
+        };
+        let quit = command.is("QUIT");
+        let mut state = state.lock().expect("Poisoned state");
+        let replies = state.execute(&mut client, command);
+        let sent = match state.clients.get(&client.id) {
+            Some(queue) => queue.send(replies),
+            None => break,
+        };
+        if quit || sent.is_err() {
+            break;
+        }
+    }
+
+    {
+        let mut state = state.lock().expect("Poisoned state");
+        state.unsubscribe_all(&mut client);
+        state.clients.remove(&client.id);
+    }
+    // Removing the client closes its queue, the writer stops once everything queued has been written
+    let _ = writer.join();
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+fn ok() -> RespValue {
+    RespValue::SimpleString("OK".into())
+}
+
+fn error<T: Into<String>>(message: T) -> RespValue {
+    RespValue::Error(message.into())
+}
+
+fn wrong_type() -> RespValue {
+    error("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+fn bulk(bytes: &Bytes) -> RespValue {
+    RespValue::BulkString(bytes.clone())
+}
+
+fn integer(i: usize) -> RespValue {
+    RespValue::Integer(i as i64)
+}
+
+fn parse_int(bytes: &Bytes) -> Result<i64, RespValue> {
+    String::from_utf8_lossy(bytes)
+        .parse()
+        .map_err(|_| error("ERR value is not an integer or out of range"))
+}
+
+/// The number of arguments, not including the name, accepted by each command: a minimum and, optionally, a
+/// maximum.  Returns `None` for unknown commands.
+fn arity(name: &str) -> Option<(usize, Option<usize>)> {
+    let arity = match name {
+        "PING" => (0, Some(1)),
+        "ECHO" | "SELECT" | "GET" | "INCR" | "DECR" | "STRLEN" | "TYPE" | "LPOP" | "RPOP" | "LLEN"
+        | "SMEMBERS" | "SCARD" | "HGETALL" | "HLEN" | "HKEYS" | "HVALS" => (1, Some(1)),
+        "QUIT" | "DBSIZE" | "MULTI" | "EXEC" | "DISCARD" | "UNWATCH" => (0, Some(0)),
+        "FLUSHDB" | "FLUSHALL" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" => (0, None),
+        "DEL" | "EXISTS" | "MGET" | "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" | "CLIENT" | "WATCH" => {
+            (1, None)
+        }
+        "AUTH" => (1, Some(2)),
+        "SET" | "MSET" | "LPUSH" | "RPUSH" | "SADD" | "SREM" | "HDEL" | "HMGET" => (2, None),
+        "SETNX" | "GETSET" | "INCRBY" | "DECRBY" | "APPEND" | "LINDEX" | "SISMEMBER" | "HGET"
+        | "HEXISTS" | "PUBLISH" | "SPUBLISH" => (2, Some(2)),
+        "LRANGE" | "HINCRBY" => (3, Some(3)),
+        "HSET" => (3, None),
+        _ => return None,
+    };
+    Some(arity)
+}
+
+/// Commands that can still be used once a connection has subscribed to something.
+fn allowed_when_subscribed(name: &str) -> bool {
+    matches!(
+        name,
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE"
+            | "PING" | "QUIT"
+    )
+}
+
+/// Checks a command is known, and has the right number of arguments.
+fn check_command(name: &str, args: &[Bytes]) -> Result<(), RespValue> {
+    match arity(name) {
+        None => Err(error(format!(
+            "ERR unknown command '{}'",
+            name.to_lowercase()
+        ))),
+        Some((min, max)) => {
+            if args.len() < min || max.is_some_and(|max| args.len() > max) {
+                Err(error(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    name.to_lowercase()
+                )))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Gets a value of a particular type, or returns a `WRONGTYPE` error if the key holds a different type.
+macro_rules! get_typed {
+    ($db:expr, $key:expr, $variant:ident) => {
+        match $db.get_mut($key) {
+            None => None,
+            Some(&mut Value::$variant(ref mut value)) => Some(value),
+            Some(_) => return Err(wrong_type()),
+        }
+    };
+}
+
+/// As `get_typed`, but creates an empty value if the key doesn't exist.
+macro_rules! get_or_create_typed {
+    ($db:expr, $key:expr, $variant:ident) => {
+        match *$db.entry($key.clone())
+            .or_insert_with(|| Value::$variant(Default::default()))
+        {
+            Value::$variant(ref mut value) => value,
+            _ => return Err(wrong_type()),
+        }
+    };
+}
+
+/// Removes a collection that has had its last element removed, as Redis doesn't keep empty collections.
+fn remove_if_empty(db: &mut Db, key: &Bytes) {
+    let empty = match db.get(key) {
+        Some(Value::List(list)) => list.is_empty(),
+        Some(Value::Set(set)) => set.is_empty(),
+        Some(Value::Hash(hash)) => hash.is_empty(),
+        _ => false,
+    };
+    if empty {
+        db.remove(key);
+    }
+}
+
+/// Matches `string` against a glob-style `pattern`, as Redis does for `PSUBSCRIBE`: `*` matches any sequence
+/// of bytes, `?` any single byte, `[...]` any one of a set of bytes (which may include ranges, such as `a-z`, and
+/// may be negated with `^`), and `\` escapes the byte that follows.
+fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => string.is_empty(),
+        Some((&b'*', rest)) => (0..string.len() + 1).any(|idx| glob_match(rest, &string[idx..])),
+        Some((&b'?', rest)) => !string.is_empty() && glob_match(rest, &string[1..]),
+        Some((&b'[', rest)) => match string.split_first() {
+            Some((&c, string_rest)) => {
+                let (matched, rest) = match_class(rest, c);
+                matched && glob_match(rest, string_rest)
+            }
+            None => false,
+        },
+        Some((&b'\\', rest)) if !rest.is_empty() => {
+            string.first() == Some(&rest[0]) && glob_match(&rest[1..], &string[1..])
+        }
+        Some((&p, rest)) => string.first() == Some(&p) && glob_match(rest, &string[1..]),
+    }
+}
+
+/// Matches `c` against a `[...]` class, `class` being the pattern following the `[`.  Returns whether it matched
+/// and the rest of the pattern following the `]`.
+fn match_class(class: &[u8], c: u8) -> (bool, &[u8]) {
+    let (negate, class) = match class.split_first() {
+        Some((&b'^', rest)) => (true, rest),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut idx = 0;
+    while idx < class.len() && class[idx] != b']' {
+        if class[idx] == b'\\' && idx + 1 < class.len() {
+            idx += 1;
+            matched |= class[idx] == c;
+        } else if idx + 2 < class.len() && class[idx + 1] == b'-' && class[idx + 2] != b']' {
+            let (start, end) = (class[idx].min(class[idx + 2]), class[idx].max(class[idx + 2]));
+            matched |= start <= c && c <= end;
+            idx += 2;
+        } else {
+            matched |= class[idx] == c;
+        }
+        idx += 1;
+    }
+    // As with Redis, a class without a closing `]` runs to the end of the pattern
+    let rest = if idx < class.len() {
+        &class[idx + 1..]
+    } else {
+        &class[idx..]
+    };
+    (matched != negate, rest)
+}
+
+/// Converts Redis-style indexes, where negative numbers count from the end, into a range of a list of `len`
+/// elements.
+fn list_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+impl State {
+    /// Runs a command, returning the replies to be sent to the client.  Most commands return exactly one reply,
+    /// but subscriptions return one per channel.
+    fn execute(&mut self, client: &mut Client, command: Command) -> Vec<RespValue> {
+        let name = command.name().to_uppercase();
+        let args = match command
+            .into_args()
+            .into_iter()
+            .map(Bytes::from_resp)
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(args) => args,
+            Err(_) => return vec![error("ERR Protocol error: arguments must be strings")],
+        };
+
+        if let Err(e) = check_command(&name, &args) {
+            if client.multi.is_some() {
+                client.multi_failed = true;
+            }
+            return vec![e];
+        }
+        if client.subscriptions() > 0 && !allowed_when_subscribed(&name) {
+            return vec![error(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT are allowed \
+                 in this context",
+                name.to_lowercase()
+            ))];
+        }
+
+        match name.as_ref() {
+            "SUBSCRIBE" => return self.subscribe(client, Kind::Channel, args),
+            "UNSUBSCRIBE" => return self.unsubscribe(client, Kind::Channel, args),
+            "PSUBSCRIBE" => return self.subscribe(client, Kind::Pattern, args),
+            "PUNSUBSCRIBE" => return self.unsubscribe(client, Kind::Pattern, args),
+            "SSUBSCRIBE" => return self.subscribe(client, Kind::Shard, args),
+            "SUNSUBSCRIBE" => return self.unsubscribe(client, Kind::Shard, args),
+            "PING" if client.subscriptions() > 0 => {
+                let message = args.first().cloned().unwrap_or_default();
+                return vec![resp_array!["pong", message]];
+            }
+            _ => (),
+        }
+
+        let reply = match (name.as_ref(), client.multi.is_some()) {
+            ("MULTI", true) => Err(error("ERR MULTI calls can not be nested")),
+            ("MULTI", false) => {
+                client.multi = Some(Vec::new());
+                client.multi_failed = false;
+                Ok(ok())
+            }
+            ("EXEC", true) => self.exec(client),
+            ("DISCARD", true) => {
+                client.multi = None;
+                client.watched.clear();
+                Ok(ok())
+            }
+            ("EXEC", false) | ("DISCARD", false) => {
+                Err(error(format!("ERR {} without MULTI", name)))
+            }
+            ("WATCH", true) => Err(error("ERR WATCH inside MULTI is not allowed")),
+            ("WATCH", false) => {
+                for key in args {
+                    let value = self.dbs[client.db].get(&key).cloned();
+                    client.watched.push((client.db, key, value));
+                }
+                Ok(ok())
+            }
+            ("UNWATCH", _) => {
+                client.watched.clear();
+                Ok(ok())
+            }
+            (_, true) => {
+                if let Some(ref mut queued) = client.multi {
+                    queued.push((name, args));
+                }
+                Ok(RespValue::SimpleString("QUEUED".into()))
+            }
+            (_, false) => self.run(client, &name, args),
+        };
+        vec![reply.unwrap_or_else(|e| e)]
+    }
+
+    fn exec(&mut self, client: &mut Client) -> Reply {
+        let queued = client.multi.take().unwrap_or_default();
+        let watched = mem::take(&mut client.watched);
+        if client.multi_failed {
+            return Err(error(
+                "EXECABORT Transaction discarded because of previous errors.",
+            ));
+        }
+        if watched
+            .iter()
+            .any(|&(db, ref key, ref value)| self.dbs[db].get(key) != value.as_ref())
+        {
+            return Ok(RespValue::Nil);
+        }
+        let replies = queued
+            .into_iter()
+            .map(|(name, args)| self.run(client, &name, args).unwrap_or_else(|e| e))
+            .collect();
+        Ok(RespValue::Array(replies))
+    }
+
+    /// Runs a single command, that isn't to do with transactions or subscriptions.
+    fn run(&mut self, client: &mut Client, name: &str, args: Vec<Bytes>) -> Reply {
+        match name {
+            "PUBLISH" => return Ok(integer(self.publish(&args[0], &args[1]))),
+            "SPUBLISH" => return Ok(integer(self.spublish(&args[0], &args[1]))),
+            _ => (),
+        }
+        let db = &mut self.dbs[client.db];
+        match name {
+            "PING" => match args.first() {
+                Some(message) => Ok(bulk(message)),
+                None => Ok(RespValue::SimpleString("PONG".into())),
+            },
+            "ECHO" => Ok(bulk(&args[0])),
+            "QUIT" => Ok(ok()),
+            "AUTH" => Err(error(
+                "ERR AUTH <password> called without any password configured for the default user. Are you \
+                 sure your configuration is correct?",
+            )),
+            "CLIENT" => {
+                let subcommand = String::from_utf8_lossy(&args[0]).to_uppercase();
+                match (subcommand.as_ref(), args.len()) {
+                    ("SETNAME", 2) => {
+                        if args[1].iter().any(|b| *b <= b' ' || *b > b'~') {
+                            return Err(error(
+                                "ERR Client names cannot contain spaces, newlines or special characters.",
+                            ));
+                        }
+                        client.name = if args[1].is_empty() {
+                            None
+                        } else {
+                            Some(args[1].clone())
+                        };
+                        Ok(ok())
+                    }
+                    ("GETNAME", 1) => Ok(client.name.as_ref().map_or(RespValue::Nil, bulk)),
+                    _ => Err(error(format!(
+                        "ERR Unknown subcommand or wrong number of arguments for '{}'",
+                        subcommand
+                    ))),
+                }
+            }
+            "SELECT" => {
+                let idx = parse_int(&args[0])?;
+                if idx < 0 || idx as usize >= DATABASES {
+                    return Err(error("ERR DB index is out of range"));
+                }
+                client.db = idx as usize;
+                Ok(ok())
+            }
+            "DBSIZE" => Ok(integer(db.len())),
+            "FLUSHDB" => {
+                db.clear();
+                Ok(ok())
+            }
+            "FLUSHALL" => {
+                for db in self.dbs.iter_mut() {
+                    db.clear();
+                }
+                Ok(ok())
+            }
+            "DEL" => Ok(integer(
+                args.iter().filter(|key| db.remove(*key).is_some()).count(),
+            )),
+            "EXISTS" => Ok(integer(
+                args.iter().filter(|key| db.contains_key(*key)).count(),
+            )),
+            "TYPE" => {
+                let type_name = match db.get(&args[0]) {
+                    None => "none",
+                    Some(&Value::String(_)) => "string",
+                    Some(&Value::List(_)) => "list",
+                    Some(&Value::Set(_)) => "set",
+                    Some(&Value::Hash(_)) => "hash",
+                };
+                Ok(RespValue::SimpleString(type_name.into()))
+            }
+
+            // Strings
+            "GET" => Ok(get_typed!(db, &args[0], String).map_or(RespValue::Nil, |v| bulk(v))),
+            "SET" => {
+                let (mut nx, mut xx) = (false, false);
+                for option in &args[2..] {
+                    match String::from_utf8_lossy(option).to_uppercase().as_ref() {
+                        "NX" => nx = true,
+                        "XX" => xx = true,
+                        "EX" | "PX" => return Err(error("ERR expiry is not supported by MockServer")),
+                        _ => return Err(error("ERR syntax error")),
+                    }
+                }
+                let exists = db.contains_key(&args[0]);
+                if (nx && exists) || (xx && !exists) {
+                    return Ok(RespValue::Nil);
+                }
+                db.insert(args[0].clone(), Value::String(args[1].clone()));
+                Ok(ok())
+            }
+            "SETNX" => {
+                if db.contains_key(&args[0]) {
+                    Ok(integer(0))
+                } else {
+                    db.insert(args[0].clone(), Value::String(args[1].clone()));
+                    Ok(integer(1))
+                }
+            }
+            "GETSET" => {
+                let old = get_typed!(db, &args[0], String).map_or(RespValue::Nil, |v| bulk(v));
+                db.insert(args[0].clone(), Value::String(args[1].clone()));
+                Ok(old)
+            }
+            "MGET" => Ok(RespValue::Array(
+                args.iter()
+                    .map(|key| match db.get(key) {
+                        Some(Value::String(value)) => bulk(value),
+                        _ => RespValue::Nil,
+                    })
+                    .collect(),
+            )),
+            "MSET" => {
+                if args.len() % 2 == 1 {
+                    return Err(error("ERR wrong number of arguments for 'mset' command"));
+                }
+                for pair in args.chunks(2) {
+                    db.insert(pair[0].clone(), Value::String(pair[1].clone()));
+                }
+                Ok(ok())
+            }
+            "INCR" | "DECR" | "INCRBY" | "DECRBY" => {
+                let by = match name {
+                    "INCR" => 1,
+                    "DECR" => -1,
+                    "INCRBY" => parse_int(&args[1])?,
+                    _ => -parse_int(&args[1])?,
+                };
+                let current = match get_typed!(db, &args[0], String) {
+                    Some(value) => parse_int(value)?,
+                    None => 0,
+                };
+                let new = current
+                    .checked_add(by)
+                    .ok_or_else(|| error("ERR increment or decrement would overflow"))?;
+                db.insert(args[0].clone(), Value::String(new.to_string().into()));
+                Ok(RespValue::Integer(new))
+            }
+            "APPEND" => {
+                let mut value = match get_typed!(db, &args[0], String) {
+                    Some(value) => BytesMut::from(&value[..]),
+                    None => BytesMut::new(),
+                };
+                value.extend_from_slice(&args[1]);
+                let len = value.len();
+                db.insert(args[0].clone(), Value::String(value.freeze()));
+                Ok(integer(len))
+            }
+            "STRLEN" => Ok(integer(get_typed!(db, &args[0], String).map_or(0, |v| v.len()))),
+
+            // Lists
+            "LPUSH" | "RPUSH" => {
+                let list = get_or_create_typed!(db, &args[0], List);
+                for value in &args[1..] {
+                    if name == "LPUSH" {
+                        list.push_front(value.clone());
+                    } else {
+                        list.push_back(value.clone());
+                    }
+                }
+                Ok(integer(list.len()))
+            }
+            "LPOP" | "RPOP" => {
+                let popped = match get_typed!(db, &args[0], List) {
+                    Some(list) => {
+                        if name == "LPOP" {
+                            list.pop_front()
+                        } else {
+                            list.pop_back()
+                        }
+                    }
+                    None => None,
+                };
+                remove_if_empty(db, &args[0]);
+                Ok(popped.map_or(RespValue::Nil, |v| bulk(&v)))
+            }
+            "LLEN" => Ok(integer(get_typed!(db, &args[0], List).map_or(0, |l| l.len()))),
+            "LRANGE" => {
+                let (start, stop) = (parse_int(&args[1])?, parse_int(&args[2])?);
+                let values = match get_typed!(db, &args[0], List) {
+                    Some(list) => match list_range(start, stop, list.len()) {
+                        Some((start, stop)) => list.iter()
+                            .skip(start)
+                            .take(stop - start + 1)
+                            .map(bulk)
+                            .collect(),
+                        None => Vec::new(),
+                    },
+                    None => Vec::new(),
+                };
+                Ok(RespValue::Array(values))
+            }
+            "LINDEX" => {
+                let idx = parse_int(&args[1])?;
+                let value = match get_typed!(db, &args[0], List) {
+                    Some(list) => list_range(idx, idx, list.len())
+                        .and_then(|(idx, _)| list.get(idx))
+                        .map(bulk),
+                    None => None,
+                };
+                Ok(value.unwrap_or(RespValue::Nil))
+            }
+
+            // Sets
+            "SADD" => {
+                let set = get_or_create_typed!(db, &args[0], Set);
+                Ok(integer(
+                    args[1..].iter().filter(|v| set.insert((*v).clone())).count(),
+                ))
+            }
+            "SREM" => {
+                let removed = match get_typed!(db, &args[0], Set) {
+                    Some(set) => args[1..].iter().filter(|v| set.remove(*v)).count(),
+                    None => 0,
+                };
+                remove_if_empty(db, &args[0]);
+                Ok(integer(removed))
+            }
+            "SMEMBERS" => Ok(RespValue::Array(match get_typed!(db, &args[0], Set) {
+                Some(set) => set.iter().map(bulk).collect(),
+                None => Vec::new(),
+            })),
+            "SISMEMBER" => Ok(integer(
+                get_typed!(db, &args[0], Set).map_or(0, |s| s.contains(&args[1]) as usize),
+            )),
+            "SCARD" => Ok(integer(get_typed!(db, &args[0], Set).map_or(0, |s| s.len()))),
+
+            // Hashes
+            "HSET" => {
+                if args.len() % 2 != 1 {
+                    return Err(error("ERR wrong number of arguments for 'hset' command"));
+                }
+                let hash = get_or_create_typed!(db, &args[0], Hash);
+                Ok(integer(
+                    args[1..]
+                        .chunks(2)
+                        .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+                        .count(),
+                ))
+            }
+            "HGET" => Ok(get_typed!(db, &args[0], Hash)
+                .and_then(|h| h.get(&args[1]).map(bulk))
+                .unwrap_or(RespValue::Nil)),
+            "HMGET" => {
+                let hash = get_typed!(db, &args[0], Hash);
+                Ok(RespValue::Array(
+                    args[1..]
+                        .iter()
+                        .map(|field| match hash {
+                            Some(ref hash) => hash.get(field).map_or(RespValue::Nil, bulk),
+                            None => RespValue::Nil,
+                        })
+                        .collect(),
+                ))
+            }
+            "HDEL" => {
+                let removed = match get_typed!(db, &args[0], Hash) {
+                    Some(hash) => args[1..]
+                        .iter()
+                        .filter(|field| hash.remove(*field).is_some())
+                        .count(),
+                    None => 0,
+                };
+                remove_if_empty(db, &args[0]);
+                Ok(integer(removed))
+            }
+            "HEXISTS" => Ok(integer(
+                get_typed!(db, &args[0], Hash).map_or(0, |h| h.contains_key(&args[1]) as usize),
+            )),
+            "HGETALL" | "HKEYS" | "HVALS" => {
+                let mut values = Vec::new();
+                if let Some(hash) = get_typed!(db, &args[0], Hash) {
+                    for (field, value) in hash.iter() {
+                        if name != "HVALS" {
+                            values.push(bulk(field));
+                        }
+                        if name != "HKEYS" {
+                            values.push(bulk(value));
+                        }
+                    }
+                }
+                Ok(RespValue::Array(values))
+            }
+            "HLEN" => Ok(integer(get_typed!(db, &args[0], Hash).map_or(0, |h| h.len()))),
+            "HINCRBY" => {
+                let by = parse_int(&args[2])?;
+                let hash = get_or_create_typed!(db, &args[0], Hash);
+                let current = match hash.get(&args[1]) {
+                    Some(value) => parse_int(value)?,
+                    None => 0,
+                };
+                let new = current
+                    .checked_add(by)
+                    .ok_or_else(|| error("ERR increment or decrement would overflow"))?;
+                hash.insert(args[1].clone(), new.to_string().into());
+                Ok(RespValue::Integer(new))
+            }
+
+            _ => Err(error(format!("ERR unknown command '{}'", name.to_lowercase()))),
+        }
+    }
+
+    /// Sends a message to every client subscribed to `channel`, returning the number of clients.
+    fn publish(&mut self, channel: &Bytes, message: &Bytes) -> usize {
+        let mut deliveries = Vec::new();
+        if let Some(subscribers) = self.channels.get(channel) {
+            for id in subscribers {
+                deliveries.push((*id, resp_array!["message", channel.clone(), message.clone()]));
+            }
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if glob_match(pattern, channel) {
+                for id in subscribers {
+                    let pmessage = resp_array!["pmessage", pattern.clone(), channel.clone(), message.clone()];
+                    deliveries.push((*id, pmessage));
+                }
+            }
+        }
+        self.deliver(deliveries)
+    }
+
+    /// Sends a message to every client subscribed to the shard channel `channel`, returning the number of
+    /// clients.
+    fn spublish(&mut self, channel: &Bytes, message: &Bytes) -> usize {
+        let mut deliveries = Vec::new();
+        if let Some(subscribers) = self.shard_channels.get(channel) {
+            for id in subscribers {
+                deliveries.push((*id, resp_array!["smessage", channel.clone(), message.clone()]));
+            }
+        }
+        self.deliver(deliveries)
+    }
+
+    fn deliver(&mut self, deliveries: Vec<(usize, RespValue)>) -> usize {
+        let count = deliveries.len();
+        for (id, message) in deliveries {
+            if let Some(replies) = self.clients.get(&id) {
+                let _ = replies.send(vec![message]);
+            }
+        }
+        count
+    }
+
+    fn subscribers(&mut self, kind: Kind) -> &mut HashMap<Bytes, HashSet<usize>> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    fn subscribe(&mut self, client: &mut Client, kind: Kind, names: Vec<Bytes>) -> Vec<RespValue> {
+        let mut replies = Vec::with_capacity(names.len());
+        for name in names {
+            client.subscribed(kind).insert(name.clone());
+            self.subscribers(kind)
+                .entry(name.clone())
+                .or_default()
+                .insert(client.id);
+            replies.push(resp_array![
+                kind.subscribe_reply(),
+                name,
+                RespValue::Integer(client.subscriptions() as i64)
+            ]);
+        }
+        replies
+    }
+
+    fn unsubscribe(&mut self, client: &mut Client, kind: Kind, names: Vec<Bytes>) -> Vec<RespValue> {
+        let names = if names.is_empty() {
+            client.subscribed(kind).iter().cloned().collect()
+        } else {
+            names
+        };
+        if names.is_empty() {
+            return vec![resp_array![
+                kind.unsubscribe_reply(),
+                RespValue::Nil,
+                RespValue::Integer(client.subscriptions() as i64)
+            ]];
+        }
+        let mut replies = Vec::with_capacity(names.len());
+        for name in names {
+            client.subscribed(kind).remove(&name);
+            if let Entry::Occupied(mut entry) = self.subscribers(kind).entry(name.clone()) {
+                entry.get_mut().remove(&client.id);
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+            replies.push(resp_array![
+                kind.unsubscribe_reply(),
+                name,
+                RespValue::Integer(client.subscriptions() as i64)
+            ]);
+        }
+        replies
+    }
+
+    fn unsubscribe_all(&mut self, client: &mut Client) {
+        self.unsubscribe(client, Kind::Channel, Vec::new());
+        self.unsubscribe(client, Kind::Pattern, Vec::new());
+        self.unsubscribe(client, Kind::Shard, Vec::new());
+    }
+}
@@ -10,10 +10,13 @@
 
 //! An implementation of the RESP protocol
 
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 use std::io;
 use std::str;
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 use tokio_io::codec::{Decoder, Encoder};
 
@@ -24,16 +27,21 @@ use super::error::Error;
 ///
 /// It is cloneable to allow multiple copies to be delivered in certain circumstances, e.g. multiple
 /// subscribers to the same topic.
-#[derive(Debug, Clone, Eq, PartialEq)]
+///
+/// The variants after `SimpleString` are only sent by servers that have been switched to RESP3 (e.g. with
+/// `HELLO 3`).  A RESP3 null is decoded as `Nil`, and a RESP3 blob error as `Error`, as they mean the same
+/// thing as their RESP2 equivalents.
+#[derive(Debug, Clone, PartialEq)]
 pub enum RespValue {
     Nil,
 
     /// Zero, one or more other `RespValue`s.
     Array(Vec<RespValue>),
 
-    /// A bulk string.  In Redis terminology a string is a byte-array, so this is stored as a
-    /// vector of `u8`s to allow clients to interpret the bytes as appropriate.
-    BulkString(Vec<u8>),
+    /// A bulk string.  In Redis terminology a string is a byte-array, so this is stored as
+    /// `Bytes` to allow clients to interpret the bytes as appropriate.  When decoded these share the
+    /// buffer they were read in to, so neither decoding nor cloning copies the data.
+    BulkString(Bytes),
 
     /// An error from the Redis server
     Error(String),
@@ -43,12 +51,37 @@ pub enum RespValue {
     Integer(i64),
 
     SimpleString(String),
+
+    /// Key/value pairs, in the order they were sent by Redis.  Keys are not guaranteed to be unique.
+    Map(Vec<(RespValue, RespValue)>),
+
+    /// An unordered collection of values.
+    Set(Vec<RespValue>),
+
+    /// A floating point number, this includes infinities and NaN.
+    Double(f64),
+
+    Boolean(bool),
+
+    /// An integer outside of the range of `Integer`, kept in its string form.
+    BigNumber(String),
+
+    /// A string with a three byte format hint (e.g. `txt` or `mkd`) and the raw bytes.  A format of any other
+    /// length can't be encoded.
+    VerbatimString(String, Bytes),
+
+    /// Out-of-band data (e.g. client-side caching hints) attached to the value that follows it.
+    Attribute(Vec<(RespValue, RespValue)>, Box<RespValue>),
+
+    /// Data pushed by the server that isn't a reply to a command, e.g. a PUBSUB message.
+    Push(Vec<RespValue>),
 }
 
 impl RespValue {
     fn to_result(self) -> Result<RespValue, Error> {
         match self {
             RespValue::Error(string) => Err(Error::Remote(string)),
+            RespValue::Attribute(_, value) => value.to_result(),
             x => Ok(x),
         }
     }
@@ -80,6 +113,10 @@ impl FromResp for String {
             RespValue::BulkString(ref bytes) => Ok(String::from_utf8_lossy(bytes).into_owned()),
             RespValue::Integer(i) => Ok(i.to_string()),
             RespValue::SimpleString(string) => Ok(string),
+            RespValue::BigNumber(string) => Ok(string),
+            RespValue::VerbatimString(_, ref bytes) => {
+                Ok(String::from_utf8_lossy(bytes).into_owned())
+            }
             _ => Err(error::resp("Cannot convert into a string", resp)),
         }
     }
@@ -87,8 +124,19 @@ impl FromResp for String {
 
 impl FromResp for Vec<u8> {
     fn from_resp_int(resp: RespValue) -> Result<Vec<u8>, Error> {
+        match resp {
+            RespValue::BulkString(bytes) => Ok(bytes.to_vec()),
+            RespValue::VerbatimString(_, bytes) => Ok(bytes.to_vec()),
+            _ => Err(error::resp("Not a bulk string", resp)),
+        }
+    }
+}
+
+impl FromResp for Bytes {
+    fn from_resp_int(resp: RespValue) -> Result<Bytes, Error> {
         match resp {
             RespValue::BulkString(bytes) => Ok(bytes),
+            RespValue::VerbatimString(_, bytes) => Ok(bytes),
             _ => Err(error::resp("Not a bulk string", resp)),
         }
     }
@@ -109,6 +157,35 @@ impl FromResp for usize {
     }
 }
 
+impl FromResp for f64 {
+    fn from_resp_int(resp: RespValue) -> Result<f64, Error> {
+        match resp {
+            RespValue::Double(d) => Ok(d),
+            RespValue::Integer(i) => Ok(i as f64),
+            // RESP2 servers return floating point numbers (e.g. from `INCRBYFLOAT`) as bulk strings
+            RespValue::BulkString(ref bytes) => {
+                match str::from_utf8(bytes).ok().and_then(|s| s.parse().ok()) {
+                    Some(d) => Ok(d),
+                    None => Err(error::resp("Cannot be converted into an f64", resp.clone())),
+                }
+            }
+            _ => Err(error::resp("Cannot be converted into an f64", resp)),
+        }
+    }
+}
+
+impl FromResp for bool {
+    fn from_resp_int(resp: RespValue) -> Result<bool, Error> {
+        match resp {
+            RespValue::Boolean(b) => Ok(b),
+            // RESP2 servers return booleans (e.g. from `SISMEMBER`) as zero or one
+            RespValue::Integer(0) => Ok(false),
+            RespValue::Integer(1) => Ok(true),
+            _ => Err(error::resp("Cannot be converted into a bool", resp)),
+        }
+    }
+}
+
 impl<T: FromResp> FromResp for Option<T> {
     fn from_resp_int(resp: RespValue) -> Result<Option<T>, Error> {
         match resp {
@@ -118,10 +195,22 @@ impl<T: FromResp> FromResp for Option<T> {
     }
 }
 
+/// Keeps an error reply, or a failed conversion, as a value rather than failing.  This allows the other
+/// elements of an array to be converted regardless, e.g. the results of a `client::Pipeline`.
+impl<T: FromResp> FromResp for Result<T, Error> {
+    fn from_resp(resp: RespValue) -> Result<Result<T, Error>, Error> {
+        Ok(T::from_resp(resp))
+    }
+
+    fn from_resp_int(resp: RespValue) -> Result<Result<T, Error>, Error> {
+        Ok(T::from_resp_int(resp))
+    }
+}
+
 impl<T: FromResp> FromResp for Vec<T> {
     fn from_resp_int(resp: RespValue) -> Result<Vec<T>, Error> {
         match resp {
-            RespValue::Array(ary) => {
+            RespValue::Array(ary) | RespValue::Set(ary) | RespValue::Push(ary) => {
                 let mut ar = Vec::with_capacity(ary.len());
                 for value in ary {
                     ar.push(T::from_resp(value)?);
@@ -133,6 +222,51 @@ impl<T: FromResp> FromResp for Vec<T> {
     }
 }
 
+impl<T: FromResp + Hash + Eq> FromResp for HashSet<T> {
+    fn from_resp_int(resp: RespValue) -> Result<HashSet<T>, Error> {
+        match resp {
+            RespValue::Array(ary) | RespValue::Set(ary) => {
+                let mut set = HashSet::with_capacity(ary.len());
+                for value in ary {
+                    set.insert(T::from_resp(value)?);
+                }
+                Ok(set)
+            }
+            _ => Err(error::resp("Cannot be converted into a set", resp)),
+        }
+    }
+}
+
+/// Accepts either a RESP3 map, or a RESP2 array of alternating keys and values (e.g. from `HGETALL`).
+impl<K: FromResp + Hash + Eq, V: FromResp> FromResp for HashMap<K, V> {
+    fn from_resp_int(resp: RespValue) -> Result<HashMap<K, V>, Error> {
+        match resp {
+            RespValue::Map(pairs) => {
+                let mut map = HashMap::with_capacity(pairs.len());
+                for (key, value) in pairs {
+                    map.insert(K::from_resp(key)?, V::from_resp(value)?);
+                }
+                Ok(map)
+            }
+            RespValue::Array(ary) => {
+                if ary.len() % 2 != 0 {
+                    return Err(Error::RESP(
+                        format!("Array needs an even number of elements, is: {}", ary.len()),
+                        None,
+                    ));
+                }
+                let mut map = HashMap::with_capacity(ary.len() / 2);
+                let mut ary_iter = ary.into_iter();
+                while let (Some(key), Some(value)) = (ary_iter.next(), ary_iter.next()) {
+                    map.insert(K::from_resp(key)?, V::from_resp(value)?);
+                }
+                Ok(map)
+            }
+            _ => Err(error::resp("Cannot be converted into a map", resp)),
+        }
+    }
+}
+
 impl FromResp for () {
     fn from_resp_int(resp: RespValue) -> Result<(), Error> {
         match resp {
@@ -255,39 +389,46 @@ macro_rules! string_into_resp {
 
 impl ToRespString for String {
     fn to_resp_string(self) -> RespValue {
-        RespValue::BulkString(self.into_bytes())
+        RespValue::BulkString(self.into())
     }
 }
 string_into_resp!(String);
 
 impl<'a> ToRespString for &'a String {
     fn to_resp_string(self) -> RespValue {
-        RespValue::BulkString(self.as_bytes().into())
+        RespValue::BulkString(self.as_str().into())
     }
 }
 string_into_resp!(&'a String);
 
 impl<'a> ToRespString for &'a str {
     fn to_resp_string(self) -> RespValue {
-        RespValue::BulkString(self.as_bytes().into())
+        RespValue::BulkString(self.into())
     }
 }
 string_into_resp!(&'a str);
 
 impl<'a> ToRespString for &'a [u8] {
     fn to_resp_string(self) -> RespValue {
-        RespValue::BulkString(self.to_vec())
+        RespValue::BulkString(self.into())
     }
 }
 string_into_resp!(&'a [u8]);
 
 impl ToRespString for Vec<u8> {
     fn to_resp_string(self) -> RespValue {
-        RespValue::BulkString(self)
+        RespValue::BulkString(self.into())
     }
 }
 string_into_resp!(Vec<u8>);
 
+impl ToRespString for Bytes {
+    fn to_resp_string(self) -> RespValue {
+        RespValue::BulkString(self)
+    }
+}
+string_into_resp!(Bytes);
+
 pub trait ToRespInteger {
     fn to_resp_integer(self) -> RespValue;
 }
@@ -306,7 +447,74 @@ impl ToRespInteger for usize {
 integer_into_resp!(usize);
 
 /// Codec to read frames
-pub struct RespCodec;
+///
+/// Decoding is incremental, each part of a frame is parsed and removed from the buffer as soon as it has been
+/// received, the partially built value is kept until the rest of the frame arrives.  This means large values
+/// (e.g. an array with a million elements) are only parsed once, however they happen to be split across
+/// reads.
+///
+/// As the sizes of values are read from the stream they can't be trusted, so the decoder enforces a number of
+/// limits, frames that exceed them fail with `Error::LimitExceeded`.  The defaults are generous, they can be
+/// changed with `max_bulk_length`, `max_aggregate_length` and `max_depth`.
+#[derive(Debug)]
+pub struct RespCodec {
+    max_bulk_length: usize,
+    max_aggregate_length: usize,
+    max_depth: usize,
+
+    /// Aggregates still waiting for some of their elements, the innermost last.
+    stack: Vec<Aggregate>,
+
+    /// The type and length of a bulk string whose header has been read, but not yet its payload.
+    bulk: Option<(BulkKind, usize)>,
+
+    /// How much of the buffer has already been searched for the end of the current line.
+    scanned: usize,
+}
+
+/// The same as Redis's own default for `proto-max-bulk-len`
+const DEFAULT_MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+const DEFAULT_MAX_AGGREGATE_LENGTH: usize = i32::MAX as usize;
+const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// The most elements that will be allocated up-front for an aggregate, regardless of the size it claims to be.
+const MAX_PREALLOCATE: usize = 1024;
+
+impl Default for RespCodec {
+    fn default() -> Self {
+        RespCodec {
+            max_bulk_length: DEFAULT_MAX_BULK_LENGTH,
+            max_aggregate_length: DEFAULT_MAX_AGGREGATE_LENGTH,
+            max_depth: DEFAULT_MAX_DEPTH,
+            stack: Vec::new(),
+            bulk: None,
+            scanned: 0,
+        }
+    }
+}
+
+impl RespCodec {
+    /// The maximum length, in bytes, of a bulk string.  This also limits the length of any single line (e.g. a
+    /// simple string).
+    pub fn max_bulk_length(mut self, max_bulk_length: usize) -> Self {
+        self.max_bulk_length = max_bulk_length;
+        self
+    }
+
+    /// The maximum number of elements in an array, set or push; or the maximum number of pairs in a map or
+    /// attribute.
+    pub fn max_aggregate_length(mut self, max_aggregate_length: usize) -> Self {
+        self.max_aggregate_length = max_aggregate_length;
+        self
+    }
+
+    /// The maximum depth to which aggregates can be nested, a single array containing only strings has a
+    /// depth of one.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+}
 
 fn write_rn(buf: &mut BytesMut) {
     buf.put_u8(b'\r');
@@ -358,7 +566,7 @@ impl Encoder for RespCodec {
                 let len = bstr.len();
                 write_header(b'$', len as i64, buf);
                 check_and_reserve(buf, len + 2);
-                buf.extend(bstr);
+                buf.put_slice(&bstr);
                 write_rn(buf);
             }
             RespValue::Error(ref string) => {
@@ -371,6 +579,70 @@ impl Encoder for RespCodec {
             RespValue::SimpleString(ref string) => {
                 write_simple_string(b'+', string, buf);
             }
+            RespValue::Map(pairs) => {
+                write_header(b'%', pairs.len() as i64, buf);
+                self.encode_pairs(pairs, buf)?;
+            }
+            RespValue::Set(values) => {
+                write_header(b'~', values.len() as i64, buf);
+                for v in values {
+                    self.encode(v, buf)?;
+                }
+            }
+            RespValue::Double(val) => {
+                let string = if val.is_nan() {
+                    "nan".to_string()
+                } else {
+                    val.to_string()
+                };
+                write_simple_string(b',', &string, buf);
+            }
+            RespValue::Boolean(val) => {
+                write_simple_string(b'#', if val { "t" } else { "f" }, buf);
+            }
+            RespValue::BigNumber(ref string) => {
+                write_simple_string(b'(', string, buf);
+            }
+            RespValue::VerbatimString(format, bstr) => {
+                if format.len() != 3 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Verbatim string format must be three bytes: {:?}", format),
+                    ));
+                }
+                let len = format.len() + 1 + bstr.len();
+                write_header(b'=', len as i64, buf);
+                check_and_reserve(buf, len + 2);
+                buf.put_slice(format.as_bytes());
+                buf.put_u8(b':');
+                buf.put_slice(&bstr);
+                write_rn(buf);
+            }
+            RespValue::Attribute(pairs, value) => {
+                write_header(b'|', pairs.len() as i64, buf);
+                self.encode_pairs(pairs, buf)?;
+                self.encode(*value, buf)?;
+            }
+            RespValue::Push(values) => {
+                write_header(b'>', values.len() as i64, buf);
+                for v in values {
+                    self.encode(v, buf)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RespCodec {
+    fn encode_pairs(
+        &mut self,
+        pairs: Vec<(RespValue, RespValue)>,
+        buf: &mut BytesMut,
+    ) -> Result<(), io::Error> {
+        for (k, v) in pairs {
+            self.encode(k, buf)?;
+            self.encode(v, buf)?;
         }
         Ok(())
     }
@@ -381,195 +653,550 @@ fn parse_error(message: String) -> Error {
     Error::RESP(message, None)
 }
 
-/// Many RESP types have their length (which is either bytes or "number of elements", depending on context)
-/// encoded as a string, terminated by "\r\n", this looks for them.
-///
-/// Only return the string if the whole sequence is complete, including the terminator bytes (but those final
-/// two bytes will not be returned)
-///
-/// TODO - rename this function potentially, it's used for simple integers too
-fn scan_integer<'a>(buf: &'a mut BytesMut, idx: usize) -> Result<Option<(usize, &'a [u8])>, Error> {
-    let length = buf.len();
-    let mut at_end = false;
-    let mut pos = idx;
-    loop {
-        if length <= pos {
-            return Ok(None);
+/// The types of aggregate value that can be partially decoded.
+#[derive(Debug)]
+enum AggregateKind {
+    Array,
+    Set,
+    Push,
+    Map,
+    /// The key/value pairs of an attribute, the value being described follows.
+    Attribute,
+    /// A complete set of attribute pairs, waiting for the value being described.
+    Attributed(Vec<(RespValue, RespValue)>),
+}
+
+/// An aggregate value for which not all elements have been received yet.
+#[derive(Debug)]
+struct Aggregate {
+    kind: AggregateKind,
+    remaining: usize,
+    values: Vec<RespValue>,
+}
+
+/// The types of value that have a length-prefixed payload.
+#[derive(Debug, Clone, Copy)]
+enum BulkKind {
+    String,
+    VerbatimString,
+    Error,
+}
+
+/// The outcome of reading one line from the buffer.
+enum Step {
+    /// Not enough data yet
+    Incomplete,
+    /// The header of a bulk string or an aggregate was read, its contents follow
+    Started,
+    Value(RespValue),
+}
+
+/// Converts the elements of a completed map or attribute into pairs.
+fn into_pairs(values: Vec<RespValue>) -> Vec<(RespValue, RespValue)> {
+    let mut pairs = Vec::with_capacity(values.len() / 2);
+    let mut values = values.into_iter();
+    while let (Some(k), Some(v)) = (values.next(), values.next()) {
+        pairs.push((k, v));
+    }
+    pairs
+}
+
+/// Redis integers (and the lengths of bulk strings and aggregates) are transmitted as strings, so we first
+/// convert the raw bytes into a string and then parse the string.
+fn parse_integer(line: &[u8]) -> Result<i64, Error> {
+    match str::from_utf8(line) {
+        Ok(string) => match string.parse() {
+            Ok(int) => Ok(int),
+            Err(_) => Err(parse_error(format!("Not an integer: {}", string))),
+        },
+        Err(_) => Err(parse_error(format!("Not a valid string: {:?}", line))),
+    }
+}
+
+fn parse_string(line: &[u8]) -> String {
+    String::from_utf8_lossy(line).into_owned()
+}
+
+impl RespCodec {
+    /// Removes the next line from the buffer, returning it without the terminating `\r\n`.  Returns `None`,
+    /// leaving the buffer untouched, if the whole line hasn't been received yet.
+    fn next_line(&mut self, buf: &mut BytesMut) -> Option<BytesMut> {
+        let mut pos = self.scanned;
+        while let Some(offset) = buf[pos..].iter().position(|b| *b == b'\n') {
+            let end = pos + offset;
+            if end > 0 && buf[end - 1] == b'\r' {
+                self.scanned = 0;
+                let mut line = buf.split_to(end + 1);
+                line.truncate(end - 1);
+                return Some(line);
+            }
+            pos = end + 1;
         }
-        match (at_end, buf[pos]) {
-            (true, b'\n') => return Ok(Some((pos + 1, &buf[idx..pos - 1]))),
-            (false, b'\r') => at_end = true,
-            (false, b'0'...b'9') => (),
-            (false, b'-') => (),
-            (_, val) => {
-                return Err(parse_error(format!(
-                    "Unexpected byte in size_string: {}",
-                    val
+        // Everything up to here has been checked, so there's no need to do so again when more data arrives
+        self.scanned = buf.len();
+        None
+    }
+
+    fn check_line_length(&self) -> Result<(), Error> {
+        if self.scanned > self.max_bulk_length {
+            Err(Error::LimitExceeded(format!(
+                "Line longer than maximum of {} bytes",
+                self.max_bulk_length
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn start_aggregate(&mut self, kind: AggregateKind, size: i64) -> Result<Step, Error> {
+        if size > 0 && size as u64 > self.max_aggregate_length as u64 {
+            return Err(Error::LimitExceeded(format!(
+                "Aggregate of {} elements exceeds maximum of {}",
+                size, self.max_aggregate_length
+            )));
+        }
+        if size >= 0 && self.stack.len() >= self.max_depth {
+            return Err(Error::LimitExceeded(format!(
+                "Aggregates nested deeper than maximum of {}",
+                self.max_depth
+            )));
+        }
+        let size = match kind {
+            AggregateKind::Map | AggregateKind::Attribute if size >= 0 => size
+                .checked_mul(2)
+                .ok_or_else(|| Error::LimitExceeded(format!("Map of {} pairs is too long", size)))?,
+            _ => size,
+        };
+        match (kind, size) {
+            (AggregateKind::Array, -1) => Ok(Step::Value(RespValue::Nil)),
+            (AggregateKind::Attribute, 0) => {
+                self.stack.push(Aggregate {
+                    kind: AggregateKind::Attributed(Vec::new()),
+                    remaining: 1,
+                    values: Vec::with_capacity(1),
+                });
+                Ok(Step::Started)
+            }
+            (kind, 0) => Ok(Step::Value(complete_aggregate(kind, Vec::new()))),
+            (kind, size) if size > 0 => {
+                let size = size as usize;
+                self.stack.push(Aggregate {
+                    kind: kind,
+                    remaining: size,
+                    values: Vec::with_capacity(cmp::min(size, MAX_PREALLOCATE)),
+                });
+                Ok(Step::Started)
+            }
+            (_, size) => Err(parse_error(format!("Invalid aggregate size: {}", size))),
+        }
+    }
+
+    fn start_bulk(&mut self, kind: BulkKind, size: i64) -> Result<Step, Error> {
+        match (kind, size) {
+            (BulkKind::String, -1) => Ok(Step::Value(RespValue::Nil)),
+            (_, size) if size > 0 && size as u64 > self.max_bulk_length as u64 => {
+                Err(Error::LimitExceeded(format!(
+                    "String of {} bytes exceeds maximum of {}",
+                    size, self.max_bulk_length
                 )))
             }
+            (kind, size) if size >= 0 => {
+                self.bulk = Some((kind, size as usize));
+                Ok(Step::Started)
+            }
+            (_, size) => Err(parse_error(format!("Invalid string size: {}", size))),
         }
-        pos += 1;
     }
-}
 
-fn scan_string(buf: &mut BytesMut, idx: usize) -> Option<(usize, String)> {
-    let length = buf.len();
-    let mut at_end = false;
-    let mut pos = idx;
-    loop {
-        if length <= pos {
-            return None;
+    /// Reads a line, this is either a complete value in itself, or the header of a longer value.
+    fn decode_line(&mut self, buf: &mut BytesMut) -> Result<Step, Error> {
+        let line = match self.next_line(buf) {
+            Some(line) => line,
+            None => {
+                self.check_line_length()?;
+                return Ok(Step::Incomplete);
+            }
+        };
+        if line.is_empty() {
+            return Err(parse_error("Unexpected empty line".to_string()));
+        }
+        let body = &line[1..];
+        match line[0] {
+            b'$' => self.start_bulk(BulkKind::String, parse_integer(body)?),
+            b'=' => self.start_bulk(BulkKind::VerbatimString, parse_integer(body)?),
+            b'!' => self.start_bulk(BulkKind::Error, parse_integer(body)?),
+            b'*' => self.start_aggregate(AggregateKind::Array, parse_integer(body)?),
+            b'~' => self.start_aggregate(AggregateKind::Set, parse_integer(body)?),
+            b'>' => self.start_aggregate(AggregateKind::Push, parse_integer(body)?),
+            b'%' => self.start_aggregate(AggregateKind::Map, parse_integer(body)?),
+            b'|' => self.start_aggregate(AggregateKind::Attribute, parse_integer(body)?),
+            b':' => Ok(Step::Value(RespValue::Integer(parse_integer(body)?))),
+            b'+' => Ok(Step::Value(RespValue::SimpleString(parse_string(body)))),
+            b'-' => Ok(Step::Value(RespValue::Error(parse_string(body)))),
+            b'(' => Ok(Step::Value(RespValue::BigNumber(parse_string(body)))),
+            b'_' if body.is_empty() => Ok(Step::Value(RespValue::Nil)),
+            b',' => match parse_string(body).parse() {
+                Ok(d) => Ok(Step::Value(RespValue::Double(d))),
+                Err(_) => Err(parse_error(format!("Not a double: {}", parse_string(body)))),
+            },
+            b'#' => match body {
+                b"t" => Ok(Step::Value(RespValue::Boolean(true))),
+                b"f" => Ok(Step::Value(RespValue::Boolean(false))),
+                _ => Err(parse_error(format!("Not a boolean: {}", parse_string(body)))),
+            },
+            first_byte => Err(parse_error(format!("Unexpected byte: {}", first_byte))),
+        }
+    }
+
+    /// Reads the payload of a bulk string, once all of it (and its terminator) has been received.
+    fn decode_bulk(
+        &mut self,
+        buf: &mut BytesMut,
+        kind: BulkKind,
+        size: usize,
+    ) -> Result<Option<RespValue>, Error> {
+        if buf.len() < size + 2 {
+            self.bulk = Some((kind, size));
+            return Ok(None);
         }
-        match (at_end, buf[pos]) {
-            (true, b'\n') => {
-                let value = String::from_utf8_lossy(&buf[idx..pos - 1]).into_owned();
-                return Some((pos + 1, value));
+        let mut payload = buf.split_to(size);
+        if &buf[..2] != b"\r\n" {
+            return Err(parse_error(format!(
+                "Bulk string not terminated correctly: {:?}",
+                &buf[..2]
+            )));
+        }
+        buf.advance(2);
+        match kind {
+            BulkKind::String => Ok(Some(RespValue::BulkString(payload.freeze()))),
+            BulkKind::Error => Ok(Some(RespValue::Error(parse_string(&payload)))),
+            BulkKind::VerbatimString => {
+                if payload.len() < 4 || payload[3] != b':' {
+                    return Err(parse_error(format!(
+                        "Invalid verbatim string: {:?}",
+                        parse_string(&payload)
+                    )));
+                }
+                let data = payload.split_off(4).freeze();
+                let format = parse_string(&payload[..3]);
+                Ok(Some(RespValue::VerbatimString(format, data)))
             }
-            (true, _) => at_end = false,
-            (false, b'\r') => at_end = true,
-            (false, _) => (),
         }
-        pos += 1;
     }
-}
 
-fn decode_raw_integer(buf: &mut BytesMut, idx: usize) -> Result<Option<(usize, i64)>, Error> {
-    match scan_integer(buf, idx) {
-        Ok(None) => Ok(None),
-        Ok(Some((pos, int_str))) => {
-            // Redis integers are transmitted as strings, so we first convert the raw bytes into a string...
-            match str::from_utf8(int_str) {
-                Ok(string) => {
-                    // ...and then parse the string.
-                    match string.parse() {
-                        Ok(int) => Ok(Some((pos, int))),
-                        Err(_) => Err(parse_error(format!("Not an integer: {}", string))),
+    /// Adds a value to the innermost incomplete aggregate, completing as many aggregates as possible.  Returns
+    /// the value if it completes a whole frame.
+    fn push_value(&mut self, value: RespValue) -> Option<RespValue> {
+        let mut value = value;
+        loop {
+            match self.stack.last_mut() {
+                None => return Some(value),
+                Some(aggregate) => {
+                    aggregate.values.push(value);
+                    aggregate.remaining -= 1;
+                    if aggregate.remaining > 0 {
+                        return None;
                     }
                 }
-                Err(_) => Err(parse_error(format!("Not a valid string: {:?}", int_str))),
+            }
+            let aggregate = self.stack.pop().expect("No aggregate");
+            match aggregate.kind {
+                AggregateKind::Attribute => {
+                    self.stack.push(Aggregate {
+                        kind: AggregateKind::Attributed(into_pairs(aggregate.values)),
+                        remaining: 1,
+                        values: Vec::with_capacity(1),
+                    });
+                    return None;
+                }
+                kind => value = complete_aggregate(kind, aggregate.values),
+            }
+        }
+    }
+
+    /// Decodes the next frame, or as much of it as has been received.
+    fn decode_frame(&mut self, buf: &mut BytesMut) -> Result<Option<RespValue>, Error> {
+        loop {
+            let value = match self.bulk.take() {
+                Some((kind, size)) => match self.decode_bulk(buf, kind, size)? {
+                    Some(value) => value,
+                    None => return Ok(None),
+                },
+                None => match self.decode_line(buf)? {
+                    Step::Incomplete => return Ok(None),
+                    Step::Started => continue,
+                    Step::Value(value) => value,
+                },
+            };
+            if let Some(value) = self.push_value(value) {
+                return Ok(Some(value));
             }
         }
-        Err(e) => Err(e),
+    }
+
+    /// Whether the decoder is between frames, i.e. there's no partially decoded value.
+    fn is_idle(&self) -> bool {
+        self.stack.is_empty() && self.bulk.is_none()
     }
 }
 
-type DecodeResult = Result<Option<(usize, RespValue)>, Error>;
+fn complete_aggregate(kind: AggregateKind, mut values: Vec<RespValue>) -> RespValue {
+    match kind {
+        AggregateKind::Array => RespValue::Array(values),
+        AggregateKind::Set => RespValue::Set(values),
+        AggregateKind::Push => RespValue::Push(values),
+        AggregateKind::Map => RespValue::Map(into_pairs(values)),
+        AggregateKind::Attribute => RespValue::Attribute(into_pairs(values), Box::new(RespValue::Nil)),
+        AggregateKind::Attributed(pairs) => {
+            RespValue::Attribute(pairs, Box::new(values.pop().unwrap_or(RespValue::Nil)))
+        }
+    }
+}
 
-fn decode_bulk_string(buf: &mut BytesMut, idx: usize) -> DecodeResult {
-    match decode_raw_integer(buf, idx) {
-        Ok(None) => Ok(None),
-        Ok(Some((pos, -1))) => Ok(Some((pos, RespValue::Nil))),
-        Ok(Some((pos, size))) if size >= 0 => {
-            let size = size as usize;
-            let remaining = buf.len() - pos;
-            let required_bytes = size + 2;
+impl Decoder for RespCodec {
+    type Item = RespValue;
+    type Error = Error;
 
-            if remaining < required_bytes {
-                return Ok(None);
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let result = self.decode_frame(buf);
+        if result.is_err() {
+            // So that a codec that is used again doesn't carry on part way through the frame that failed
+            self.stack.clear();
+            self.bulk = None;
+            self.scanned = 0;
+        }
+        result
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None => {
+                if buf.is_empty() && self.is_idle() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed part way through a frame",
+                    ).into())
+                }
             }
+        }
+    }
+}
 
-            let bulk_string = RespValue::BulkString(buf[pos..(pos + size)].to_vec());
-            Ok(Some((pos + required_bytes, bulk_string)))
+/// A command sent by a client to a server.
+///
+/// Redis commands are arrays of bulk strings, the first being the name of the command and the rest its
+/// arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    name: String,
+    args: Vec<RespValue>,
+}
+
+impl Command {
+    pub fn new<T: Into<String>>(name: T, args: Vec<RespValue>) -> Self {
+        Command {
+            name: name.into(),
+            args: args,
+        }
+    }
+
+    /// The name of the command, exactly as it was sent.  Redis command names are case-insensitive, so `is`
+    /// should be used to check which command this is.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Is this the named command, ignoring case.
+    pub fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    pub fn args(&self) -> &[RespValue] {
+        &self.args
+    }
+
+    /// Converts the argument at `idx` (zero being the first argument after the name) into the required type.
+    pub fn arg<T: FromResp>(&self, idx: usize) -> Result<T, Error> {
+        match self.args.get(idx) {
+            Some(arg) => T::from_resp(arg.clone()),
+            None => Err(Error::RESP(format!("No argument at: {}", idx), None)),
         }
-        Ok(Some((_, size))) => Err(parse_error(format!("Invalid string size: {}", size))),
-        Err(e) => Err(e),
+    }
+
+    pub fn into_args(self) -> Vec<RespValue> {
+        self.args
     }
 }
 
-fn decode_array(buf: &mut BytesMut, idx: usize) -> DecodeResult {
-    match decode_raw_integer(buf, idx) {
-        Ok(None) => Ok(None),
-        Ok(Some((pos, -1))) => Ok(Some((pos, RespValue::Nil))),
-        Ok(Some((pos, size))) if size >= 0 => {
-            let size = size as usize;
-            let mut pos = pos;
-            let mut values = Vec::with_capacity(size);
-            for _ in 0..size {
-                match decode(buf, pos) {
-                    Ok(None) => return Ok(None),
-                    Ok(Some((new_pos, value))) => {
-                        values.push(value);
-                        pos = new_pos;
-                    }
-                    Err(e) => return Err(e),
+impl FromResp for Command {
+    fn from_resp_int(resp: RespValue) -> Result<Command, Error> {
+        match resp {
+            RespValue::Array(ary) => {
+                let mut ary_iter = ary.into_iter();
+                match ary_iter.next() {
+                    Some(name) => Ok(Command::new(String::from_resp(name)?, ary_iter.collect())),
+                    None => Err(Error::RESP("Command cannot be empty".to_string(), None)),
                 }
             }
-            Ok(Some((pos, RespValue::Array(values))))
+            _ => Err(error::resp("Command must be an array", resp)),
         }
-        Ok(Some((_, size))) => Err(parse_error(format!("Invalid array size: {}", size))),
-        Err(e) => Err(e),
     }
 }
 
-fn decode_integer(buf: &mut BytesMut, idx: usize) -> DecodeResult {
-    match decode_raw_integer(buf, idx) {
-        Ok(None) => Ok(None),
-        Ok(Some((pos, int))) => Ok(Some((pos, RespValue::Integer(int)))),
-        Err(e) => Err(e),
+impl From<Command> for RespValue {
+    fn from(command: Command) -> RespValue {
+        let mut ary = Vec::with_capacity(command.args.len() + 1);
+        ary.push(command.name.into());
+        ary.extend(command.args);
+        RespValue::Array(ary)
     }
 }
 
-/// A simple string is any series of bytes that ends with `\r\n`
-fn decode_simple_string(buf: &mut BytesMut, idx: usize) -> DecodeResult {
-    match scan_string(buf, idx) {
-        None => Ok(None),
-        Some((pos, string)) => Ok(Some((pos, RespValue::SimpleString(string)))),
-    }
+/// Codec for the server side of a connection, for building services that speak the Redis protocol.
+///
+/// It decodes the commands sent by clients, either as RESP arrays or as
+/// ["inline commands"](https://redis.io/topics/protocol#inline-commands), and encodes replies.  The same limits
+/// as the wrapped `RespCodec` apply to both.
+#[derive(Debug, Default)]
+pub struct RespServerCodec {
+    codec: RespCodec,
 }
 
-fn decode_error(buf: &mut BytesMut, idx: usize) -> DecodeResult {
-    match scan_string(buf, idx) {
-        None => Ok(None),
-        Some((pos, string)) => Ok(Some((pos, RespValue::Error(string)))),
+impl RespServerCodec {
+    pub fn new(codec: RespCodec) -> Self {
+        RespServerCodec { codec: codec }
     }
-}
 
-fn decode(buf: &mut BytesMut, idx: usize) -> DecodeResult {
-    let length = buf.len();
-    if length <= idx {
-        return Ok(None);
+    /// Reads an inline command, returns `None` if the whole line hasn't been received yet.  Empty lines are
+    /// skipped.
+    fn decode_inline(&mut self, buf: &mut BytesMut) -> Result<Option<Option<Command>>, Error> {
+        let end = match buf.iter().position(|b| *b == b'\n') {
+            Some(end) => end,
+            None => {
+                if buf.len() > self.codec.max_bulk_length {
+                    return Err(Error::LimitExceeded(format!(
+                        "Inline command longer than maximum of {} bytes",
+                        self.codec.max_bulk_length
+                    )));
+                }
+                return Ok(None);
+            }
+        };
+        let line = buf.split_to(end + 1);
+        let mut args = split_inline(&line)?.into_iter();
+        match args.next() {
+            Some(name) => {
+                let name = parse_string(&name);
+                let args = args.map(RespValue::BulkString).collect();
+                Ok(Some(Some(Command::new(name, args))))
+            }
+            None => Ok(Some(None)),
+        }
     }
+}
 
-    let first_byte = buf[idx];
-    match first_byte {
-        b'$' => decode_bulk_string(buf, idx + 1),
-        b'*' => decode_array(buf, idx + 1),
-        b':' => decode_integer(buf, idx + 1),
-        b'+' => decode_simple_string(buf, idx + 1),
-        b'-' => decode_error(buf, idx + 1),
-        _ => Err(parse_error(format!("Unexpected byte: {}", first_byte))),
+/// Splits an inline command into its parts, separated by whitespace.  As with Redis, parts can be quoted with
+/// single or double quotes, in double quotes the escape sequences `\n`, `\r`, `\t`, `\"` and `\\` are
+/// recognised.
+fn split_inline(line: &[u8]) -> Result<Vec<Bytes>, Error> {
+    let mut parts = Vec::new();
+    let mut bytes = line.iter().cloned().peekable();
+    loop {
+        while bytes.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+            bytes.next();
+        }
+        let quote = match bytes.peek() {
+            None => return Ok(parts),
+            Some(&b'"') | Some(&b'\'') => bytes.next(),
+            Some(_) => None,
+        };
+        let mut part = Vec::new();
+        loop {
+            match (quote, bytes.next()) {
+                (None, None) => break,
+                (None, Some(b)) if b.is_ascii_whitespace() => break,
+                (Some(_), None) => {
+                    return Err(parse_error(format!(
+                        "Unbalanced quotes in inline command: {}",
+                        parse_string(line)
+                    )))
+                }
+                (Some(q), Some(b)) if b == q => break,
+                (Some(b'"'), Some(b'\\')) => match bytes.next() {
+                    Some(b'n') => part.push(b'\n'),
+                    Some(b'r') => part.push(b'\r'),
+                    Some(b't') => part.push(b'\t'),
+                    Some(b) => part.push(b),
+                    None => (),
+                },
+                (_, Some(b)) => part.push(b),
+            }
+        }
+        parts.push(part.into());
     }
 }
 
-impl Decoder for RespCodec {
-    type Item = RespValue;
+impl Decoder for RespServerCodec {
+    type Item = Command;
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        match decode(buf, 0) {
-            Ok(None) => Ok(None),
-            Ok(Some((pos, item))) => {
-                buf.split_to(pos);
-                Ok(Some(item))
+        loop {
+            if !self.codec.is_idle() || buf.first() == Some(&b'*') {
+                return match self.codec.decode(buf)? {
+                    Some(value) => Ok(Some(Command::from_resp(value)?)),
+                    None => Ok(None),
+                };
+            }
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            match self.decode_inline(buf)? {
+                Some(Some(command)) => return Ok(Some(command)),
+                Some(None) => continue,
+                None => return Ok(None),
             }
-            Err(e) => Err(e),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(command) => Ok(Some(command)),
+            None => self.codec.decode_eof(buf).map(|_| None),
         }
     }
 }
 
+impl Encoder for RespServerCodec {
+    type Item = RespValue;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: RespValue, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        self.codec.encode(msg, buf)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use bytes::BytesMut;
+    use std::collections::HashMap;
+    use std::f64;
+
+    use bytes::{Bytes, BytesMut};
 
     use tokio_io::codec::{Decoder, Encoder};
 
-    use super::{RespCodec, RespValue};
+    use error::Error;
+
+    use super::{Command, FromResp, RespCodec, RespServerCodec, RespValue};
 
     #[test]
     fn test_bulk_string() {
-        let resp_object = RespValue::BulkString("THISISATEST".as_bytes().to_vec());
+        let resp_object = RespValue::BulkString("THISISATEST".into());
         let mut bytes = BytesMut::new();
-        let mut codec = RespCodec;
+        let mut codec = RespCodec::default();
         codec.encode(resp_object.clone(), &mut bytes).unwrap();
         assert_eq!(b"$11\r\nTHISISATEST\r\n".to_vec(), bytes.to_vec());
 
@@ -577,11 +1204,25 @@ mod tests {
         assert_eq!(deserialized, resp_object);
     }
 
+    #[test]
+    fn test_bulk_string_is_not_copied() {
+        let data = "THISISATEST".repeat(10);
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(format!("$110\r\n{}\r\n", data).as_bytes());
+        let payload_ptr = bytes[6..].as_ptr();
+
+        let mut codec = RespCodec::default();
+        let deserialized = codec.decode(&mut bytes).unwrap().unwrap();
+        let payload = Bytes::from_resp(deserialized).unwrap();
+        assert_eq!(payload, data.as_bytes());
+        assert_eq!(payload.as_ptr(), payload_ptr);
+    }
+
     #[test]
     fn test_array() {
         let resp_object = RespValue::Array(vec!["TEST1".into(), "TEST2".into()]);
         let mut bytes = BytesMut::new();
-        let mut codec = RespCodec;
+        let mut codec = RespCodec::default();
         codec.encode(resp_object.clone(), &mut bytes).unwrap();
         assert_eq!(
             b"*2\r\n$5\r\nTEST1\r\n$5\r\nTEST2\r\n".to_vec(),
@@ -597,8 +1238,230 @@ mod tests {
         let mut bytes = BytesMut::new();
         bytes.extend_from_slice(&b"$-1\r\n"[..]);
 
-        let mut codec = RespCodec;
+        let mut codec = RespCodec::default();
         let deserialized = codec.decode(&mut bytes).unwrap().unwrap();
         assert_eq!(deserialized, RespValue::Nil);
     }
+
+    #[test]
+    fn test_resp3_round_trip() {
+        let resp_object = RespValue::Map(vec![
+            ("set".into(), RespValue::Set(vec![RespValue::Boolean(true)])),
+            ("double".into(), RespValue::Double(1.5)),
+            (
+                "big".into(),
+                RespValue::BigNumber("3492890328409238509324850943850943825024385".into()),
+            ),
+            (
+                "verbatim".into(),
+                RespValue::VerbatimString("txt".into(), "Some string".into()),
+            ),
+            (
+                "attribute".into(),
+                RespValue::Attribute(
+                    vec![("ttl".into(), RespValue::Integer(3600))],
+                    Box::new(RespValue::Push(vec!["message".into()])),
+                ),
+            ),
+        ]);
+        let mut bytes = BytesMut::new();
+        let mut codec = RespCodec::default();
+        codec.encode(resp_object.clone(), &mut bytes).unwrap();
+
+        let deserialized = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(deserialized, resp_object);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_verbatim_format_length() {
+        let mut bytes = BytesMut::new();
+        let mut codec = RespCodec::default();
+        let verbatim = RespValue::VerbatimString("text".into(), "Some string".into());
+        assert!(codec.encode(verbatim, &mut bytes).is_err());
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_resp3_decode() {
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"*5\r\n_\r\n,-inf\r\n#f\r\n"[..]);
+        bytes.extend_from_slice(&b"!9\r\nERR oops!\r\n=7\r\nmkd:abc\r\n"[..]);
+
+        let mut codec = RespCodec::default();
+        let deserialized = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(
+            deserialized,
+            RespValue::Array(vec![
+                RespValue::Nil,
+                RespValue::Double(f64::NEG_INFINITY),
+                RespValue::Boolean(false),
+                RespValue::Error("ERR oops!".into()),
+                RespValue::VerbatimString("mkd".into(), "abc".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_decode_in_parts() {
+        let resp_object = RespValue::Array(vec![
+            RespValue::Array((0..100).map(|i| format!("VALUE: {}", i).into()).collect()),
+            RespValue::Map(vec![("key".into(), RespValue::Nil)]),
+            RespValue::Attribute(vec![], Box::new(RespValue::Integer(-42))),
+            RespValue::SimpleString("OK".into()),
+        ]);
+        let mut encoded = BytesMut::new();
+        let mut codec = RespCodec::default();
+        codec.encode(resp_object.clone(), &mut encoded).unwrap();
+
+        let mut bytes = BytesMut::new();
+        let mut result = None;
+        for (idx, byte) in encoded.iter().enumerate() {
+            assert_eq!(result, None, "Decoded early at: {}", idx);
+            bytes.extend_from_slice(&[*byte]);
+            result = codec.decode(&mut bytes).unwrap();
+        }
+        assert_eq!(result, Some(resp_object));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_eof_part_way_through_frame() {
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"*2\r\n$3\r\nGET\r\n"[..]);
+
+        let mut codec = RespCodec::default();
+        assert_eq!(codec.decode(&mut bytes).unwrap(), None);
+        assert!(codec.decode_eof(&mut bytes).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut codec = RespCodec::default().max_bulk_length(10);
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"$999999999999\r\n"[..]);
+        match codec.decode(&mut bytes) {
+            Err(Error::LimitExceeded(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let mut codec = RespCodec::default().max_aggregate_length(2);
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"*999999999999\r\n"[..]);
+        match codec.decode(&mut bytes) {
+            Err(Error::LimitExceeded(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let mut codec = RespCodec::default().max_depth(2);
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"*1\r\n*1\r\n*1\r\n:1\r\n"[..]);
+        match codec.decode(&mut bytes) {
+            Err(Error::LimitExceeded(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let mut codec = RespCodec::default().max_bulk_length(10);
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"+THIS IS A VERY LONG LINE"[..]);
+        match codec.decode(&mut bytes) {
+            Err(Error::LimitExceeded(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let mut codec = RespCodec::default().max_aggregate_length(usize::MAX);
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"%9223372036854775807\r\n"[..]);
+        match codec.decode(&mut bytes) {
+            Err(Error::LimitExceeded(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_reuse_after_error() {
+        let mut codec = RespCodec::default().max_depth(2);
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"*1\r\n*1\r\n*1\r\n"[..]);
+        assert!(codec.decode(&mut bytes).is_err());
+
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b":1\r\n"[..]);
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(RespValue::Integer(1)));
+
+        let mut codec = RespCodec::default().max_bulk_length(10);
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"+THIS IS A VERY LONG LINE"[..]);
+        assert!(codec.decode(&mut bytes).is_err());
+
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b":1\r\n"[..]);
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(RespValue::Integer(1)));
+    }
+
+    #[test]
+    fn test_resp2_and_resp3_conversions() {
+        let resp2: HashMap<String, i64> = FromResp::from_resp(resp_array!["a", 1usize, "b", 2usize]).unwrap();
+        let map = RespValue::Map(vec![
+            ("a".into(), RespValue::Integer(1)),
+            ("b".into(), RespValue::Integer(2)),
+        ]);
+        let resp3: HashMap<String, i64> = FromResp::from_resp(map).unwrap();
+        assert_eq!(resp2, resp3);
+
+        let resp2: Vec<String> = FromResp::from_resp(resp_array!["a", "b"]).unwrap();
+        let resp3: Vec<String> =
+            FromResp::from_resp(RespValue::Set(vec!["a".into(), "b".into()])).unwrap();
+        assert_eq!(resp2, resp3);
+
+        assert!(bool::from_resp(RespValue::Integer(1)).unwrap());
+        assert!(bool::from_resp(RespValue::Boolean(true)).unwrap());
+        assert_eq!(f64::from_resp("2.5".into()).unwrap(), 2.5);
+        assert_eq!(f64::from_resp(RespValue::Double(2.5)).unwrap(), 2.5);
+
+        let with_attribute = RespValue::Attribute(vec![], Box::new("value".into()));
+        assert_eq!(String::from_resp(with_attribute).unwrap(), "value");
+    }
+
+    #[test]
+    fn test_result_conversion() {
+        let values = resp_array![RespValue::Integer(1), RespValue::Error("ERR oops".into()), "x"];
+        let results: Vec<Result<i64, Error>> = FromResp::from_resp(values.clone()).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &1);
+        match results[1] {
+            Err(Error::Remote(ref msg)) => assert_eq!(msg, "ERR oops"),
+            ref x => panic!("Unexpected result: {:?}", x),
+        }
+        assert!(results[2].is_err());
+        assert!(Vec::<i64>::from_resp(values).is_err());
+    }
+
+    #[test]
+    fn test_server_codec() {
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&b"*3\r\n$3\r\nset\r\n$1\r\nx\r\n$1\r\n1\r\n"[..]);
+        bytes.extend_from_slice(&b"\r\nPING\r\nECHO \"a \\\"b\\\"\\n\" 'c d'\n"[..]);
+
+        let mut codec = RespServerCodec::default();
+        let set = codec.decode(&mut bytes).unwrap().unwrap();
+        assert!(set.is("SET"));
+        assert_eq!(set.name(), "set");
+        assert_eq!(set.arg::<String>(0).unwrap(), "x");
+        assert_eq!(set.arg::<String>(1).unwrap(), "1");
+        assert!(set.arg::<String>(2).is_err());
+
+        let ping = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(ping, Command::new("PING", vec![]));
+
+        let echo = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(
+            echo,
+            Command::new("ECHO", vec!["a \"b\"\n".into(), "c d".into()])
+        );
+        assert!(bytes.is_empty());
+
+        codec.encode(RespValue::SimpleString("OK".into()), &mut bytes).unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), bytes.to_vec());
+    }
 }